@@ -0,0 +1,33 @@
+
+// Per-account copies of user_ranks and user_champion_stats, used by role conditions
+// that only look at accounts on specific regions. These are filled by shockwave the
+// next time it fetches the user, until then it falls back to the merged values where it can.
+exports.up = async knex => {
+    await knex.schema.createTable("league_account_ranks", table => {
+        table.increments("id").primary();
+        table.integer("account_id").unsigned().references("id").inTable("league_accounts").onDelete("cascade");
+        table.integer("user_id").unsigned().references("id").inTable("users").onDelete("cascade");
+        table.string("queue").notNullable();
+        table.string("tier").notNullable();
+
+        table.unique(["account_id", "queue"]);
+        table.index(["user_id"]);
+    });
+
+    await knex.schema.createTable("league_account_champion_stats", table => {
+        table.increments("id").primary();
+        table.integer("account_id").unsigned().references("id").inTable("league_accounts").onDelete("cascade");
+        table.integer("user_id").unsigned().references("id").inTable("users").onDelete("cascade");
+        table.integer("champion_id").notNullable();
+        table.integer("level").notNullable();
+        table.integer("score").notNullable();
+
+        table.unique(["account_id", "champion_id"]);
+        table.index(["user_id"]);
+    });
+};
+
+exports.down = async knex => {
+    await knex.schema.dropTableIfExists("league_account_champion_stats");
+    await knex.schema.dropTableIfExists("league_account_ranks");
+};
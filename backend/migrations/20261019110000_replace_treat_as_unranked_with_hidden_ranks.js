@@ -0,0 +1,42 @@
+
+// Replaces the all-or-nothing `treat_as_unranked` flag with a set of rules per user.
+// A rule hides the ranks of a single account (or all accounts, if account_id is null)
+// in a single queue (or all queues, if queue is null). The old flag maps to a rule
+// that has neither.
+exports.up = async knex => {
+    await knex.schema.createTable("user_hidden_ranks", table => {
+        table.increments("id").primary();
+        table.integer("user_id").unsigned().notNullable().references("id").inTable("users").onDelete("cascade");
+        table.integer("account_id").unsigned().nullable().references("id").inTable("league_accounts").onDelete("cascade");
+        table.string("queue").nullable();
+
+        table.index(["user_id"]);
+    });
+
+    await knex.raw(`INSERT INTO user_hidden_ranks (user_id) SELECT id FROM users WHERE treat_as_unranked = true;`);
+
+    // Rules for specific accounts need the ranks of each account. For users with a single
+    // account those are the same as their merged ranks, so we don't have to wait for a refetch.
+    await knex.raw(`
+        INSERT INTO league_account_ranks (account_id, user_id, queue, tier)
+        SELECT league_accounts.id, user_ranks.user_id, user_ranks.queue, user_ranks.tier
+        FROM user_ranks
+        INNER JOIN league_accounts ON league_accounts.user_id = user_ranks.user_id
+        WHERE (SELECT count(*) FROM league_accounts x WHERE x.user_id = user_ranks.user_id) = 1
+        ON CONFLICT (account_id, queue) DO NOTHING;
+    `);
+
+    await knex.schema.table("users", table => {
+        table.dropColumn("treat_as_unranked");
+    });
+};
+
+exports.down = async knex => {
+    await knex.schema.table("users", table => {
+        table.boolean("treat_as_unranked").defaultTo(false);
+    });
+
+    await knex.raw(`UPDATE users SET treat_as_unranked = true WHERE id IN (SELECT user_id FROM user_hidden_ranks WHERE account_id IS NULL AND queue IS NULL);`);
+
+    await knex.schema.dropTableIfExists("user_hidden_ranks");
+};
@@ -0,0 +1,12 @@
+
+exports.up = knex => knex.schema.createTable("server_exemptions", table => {
+    table.integer("server_id").unsigned().notNullable().references("id").inTable("servers").onDelete("cascade");
+    table.integer("user_id").unsigned().notNullable().references("id").inTable("users").onDelete("cascade");
+    table.boolean("roles").notNullable().defaultTo(false);
+    table.boolean("nickname").notNullable().defaultTo(false);
+
+    table.unique(["server_id", "user_id"]);
+    table.index(["user_id"]);
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("server_exemptions");
@@ -0,0 +1,8 @@
+
+exports.up = knex => knex.schema.table("role_conditions", table => {
+    table.integer("weight").notNullable().defaultTo(1);
+});
+
+exports.down = knex => knex.schema.table("role_conditions", table => {
+    table.dropColumn("weight");
+});
@@ -0,0 +1,25 @@
+
+exports.up = async knex => {
+    await knex.schema.createTable("role_ladders", table => {
+        table.increments("id").primary();
+        table.integer("server_id").unsigned().notNullable().references("id").inTable("servers").onDelete("cascade");
+        table.string("name").notNullable();
+        table.jsonb("metric").notNullable();
+
+        table.index(["server_id"]);
+    });
+
+    await knex.schema.createTable("role_ladder_steps", table => {
+        table.increments("id").primary();
+        table.integer("ladder_id").unsigned().notNullable().references("id").inTable("role_ladders").onDelete("cascade");
+        table.integer("threshold").notNullable();
+        table.string("snowflake").notNullable();
+
+        table.index(["ladder_id"]);
+    });
+};
+
+exports.down = async knex => {
+    await knex.schema.dropTableIfExists("role_ladder_steps");
+    await knex.schema.dropTableIfExists("role_ladders");
+};
@@ -0,0 +1,21 @@
+
+exports.up = knex => knex.schema.createTable("role_audit_log", table => {
+    table.increments();
+    table.integer("server_id").unsigned().notNullable().references("id").inTable("servers").onDelete("cascade");
+    table.integer("user_id").unsigned().notNullable().references("id").inTable("users").onDelete("cascade");
+    table.string("action").notNullable(); // add_role, remove_role or nickname
+    table.integer("role_id").unsigned().nullable().references("id").inTable("roles").onDelete("set null");
+    table.string("role_snowflake").nullable();
+    table.string("nickname_from").nullable();
+    table.string("nickname_to").nullable();
+    table.jsonb("conditions").notNullable();
+    table.jsonb("context").notNullable();
+    table.boolean("success").notNullable();
+    table.text("error").nullable();
+    table.timestamp("created_at").notNullable().defaultTo(knex.fn.now());
+
+    table.index(["user_id", "created_at"]);
+    table.index(["server_id", "created_at"]);
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("role_audit_log");
@@ -0,0 +1,8 @@
+
+exports.up = knex => knex.schema.table("servers", table => {
+    table.jsonb("permission_issues").nullable();
+});
+
+exports.down = knex => knex.schema.table("servers", table => {
+    table.dropColumn("permission_issues");
+});
@@ -0,0 +1,22 @@
+
+exports.up = async knex => {
+    await knex.schema.table("servers", table => {
+        table.string("primary_account_fallback").notNullable().defaultTo("none");
+        table.string("main_region").nullable();
+    });
+
+    await knex.schema.table("league_accounts", table => {
+        table.bigInteger("last_played").nullable();
+    });
+};
+
+exports.down = async knex => {
+    await knex.schema.table("servers", table => {
+        table.dropColumn("primary_account_fallback");
+        table.dropColumn("main_region");
+    });
+
+    await knex.schema.table("league_accounts", table => {
+        table.dropColumn("last_played");
+    });
+};
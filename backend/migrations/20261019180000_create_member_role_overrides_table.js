@@ -0,0 +1,13 @@
+
+exports.up = knex => knex.schema.createTable("member_role_overrides", table => {
+    table.integer("server_id").unsigned().notNullable().references("id").inTable("servers").onDelete("cascade");
+    table.integer("user_id").unsigned().notNullable().references("id").inTable("users").onDelete("cascade");
+    table.string("role_snowflake").notNullable();
+    table.boolean("present").notNullable(); // whether the role was given (true) or taken away (false)
+    table.timestamp("created_at").notNullable().defaultTo(knex.fn.now());
+
+    table.unique(["server_id", "user_id", "role_snowflake"]);
+    table.index(["user_id"]);
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("member_role_overrides");
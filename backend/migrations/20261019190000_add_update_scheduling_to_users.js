@@ -0,0 +1,27 @@
+
+const COLUMNS = ["last_score_update_timestamp", "last_rank_update_timestamp", "last_account_update_timestamp"];
+
+exports.up = async knex => {
+    await knex.schema.table("users", table => {
+        table.bigInteger("last_active_timestamp").nullable();
+        table.bigInteger("update_boost").notNullable().defaultTo(0);
+    });
+
+    // Shockwave walks users by how overdue they are, which is their last update minus their boost.
+    for (const column of COLUMNS) {
+        await knex.raw(`DROP INDEX IF EXISTS ${column}_idx;`);
+        await knex.raw(`CREATE INDEX ${column}_due_idx ON users USING btree ((${column} - update_boost), id) WHERE has_accounts;`);
+    }
+};
+
+exports.down = async knex => {
+    for (const column of COLUMNS) {
+        await knex.raw(`DROP INDEX IF EXISTS ${column}_due_idx;`);
+        await knex.raw(`CREATE INDEX ${column}_idx ON users USING btree (${column} ASC NULLS LAST, has_accounts);`);
+    }
+
+    await knex.schema.table("users", table => {
+        table.dropColumn("last_active_timestamp");
+        table.dropColumn("update_boost");
+    });
+};
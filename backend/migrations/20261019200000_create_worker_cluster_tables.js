@@ -0,0 +1,24 @@
+
+// Users are split into this many partitions by `id % PARTITIONS`. Every Shockwave
+// instance in cluster mode leases a share of them, so all instances need to agree.
+const PARTITIONS = 64;
+
+exports.up = async knex => {
+    await knex.schema.createTable("worker_instances", table => {
+        table.string("id").primary();
+        table.timestamp("heartbeat_at").notNullable().defaultTo(knex.fn.now());
+    });
+
+    await knex.schema.createTable("worker_partitions", table => {
+        table.integer("partition").primary();
+        table.string("owner").nullable();
+        table.timestamp("lease_expires_at").nullable();
+    });
+
+    await knex("worker_partitions").insert([...Array(PARTITIONS).keys()].map(partition => ({ partition })));
+};
+
+exports.down = async knex => {
+    await knex.schema.dropTableIfExists("worker_partitions");
+    await knex.schema.dropTableIfExists("worker_instances");
+};
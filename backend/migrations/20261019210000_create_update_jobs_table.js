@@ -0,0 +1,27 @@
+
+exports.up = async knex => {
+    await knex.schema.createTable("update_jobs", table => {
+        table.bigIncrements("id").primary();
+        table.integer("user_id").notNullable().references("id").inTable("users").onDelete("CASCADE");
+        // Only update the user on this server, if set.
+        table.integer("server_id").nullable().references("id").inTable("servers").onDelete("CASCADE");
+        // What to fetch from Riot before updating: nothing, accounts, ranked_tiers, mastery or everything.
+        table.string("kind").notNullable();
+        table.integer("priority").notNullable().defaultTo(0);
+        // One of pending, running or dead. Finished jobs are removed.
+        table.string("state").notNullable().defaultTo("pending");
+        table.integer("attempts").notNullable().defaultTo(0);
+        table.timestamp("next_run_at").notNullable().defaultTo(knex.fn.now());
+        table.timestamp("locked_until").nullable();
+        table.text("last_error").nullable();
+        table.timestamp("created_at").notNullable().defaultTo(knex.fn.now());
+    });
+
+    // There is at most one pending job for the same work, so that requesting it again joins the queued job.
+    await knex.raw(`CREATE UNIQUE INDEX update_jobs_pending_unique ON update_jobs (user_id, kind, (coalesce(server_id, 0))) WHERE state = 'pending';`);
+    await knex.raw(`CREATE INDEX update_jobs_claim_idx ON update_jobs (priority DESC, next_run_at) WHERE state <> 'dead';`);
+};
+
+exports.down = async knex => {
+    await knex.schema.dropTableIfExists("update_jobs");
+};
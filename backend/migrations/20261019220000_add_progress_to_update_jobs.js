@@ -0,0 +1,27 @@
+
+exports.up = async knex => {
+    await knex.schema.table("update_jobs", table => {
+        // The state of every step of the job, such as fetching accounts or applying roles.
+        table.jsonb("phases").notNullable().defaultTo("{}");
+        // The changes that were applied, for jobs that update a single server.
+        table.jsonb("plan").nullable();
+        table.timestamp("finished_at").nullable();
+    });
+
+    // Jobs that someone asked for are kept for a while after finishing, so their status can be looked up.
+    await knex.raw(`DROP INDEX IF EXISTS update_jobs_claim_idx;`);
+    await knex.raw(`CREATE INDEX update_jobs_claim_idx ON update_jobs (priority DESC, next_run_at) WHERE state IN ('pending', 'running');`);
+};
+
+exports.down = async knex => {
+    await knex("update_jobs").where("state", "done").delete();
+
+    await knex.raw(`DROP INDEX IF EXISTS update_jobs_claim_idx;`);
+    await knex.raw(`CREATE INDEX update_jobs_claim_idx ON update_jobs (priority DESC, next_run_at) WHERE state <> 'dead';`);
+
+    await knex.schema.table("update_jobs", table => {
+        table.dropColumn("phases");
+        table.dropColumn("plan");
+        table.dropColumn("finished_at");
+    });
+};
@@ -0,0 +1,15 @@
+
+// Role changes that shockwave is about to make, written before it asks Discord to make them.
+// Dissonance consumes the matching row when the change comes in through the gateway, so that
+// it is not mistaken for a manual change by a moderator.
+exports.up = knex => knex.schema.createTable("pending_role_changes", table => {
+    table.bigInteger("guild_id").notNullable();
+    table.bigInteger("user_id").notNullable();
+    table.string("role_snowflake").notNullable();
+    table.boolean("present").notNullable(); // whether the role is being given (true) or taken away (false)
+    table.timestamp("expires_at").notNullable();
+
+    table.unique(["guild_id", "user_id", "role_snowflake"]);
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("pending_role_changes");
@@ -0,0 +1,13 @@
+// Users that the shockwave worker loops should not pick up for a kind of data until a later
+// time, because fetching it keeps failing or because none of their servers need it yet. The
+// row is removed once a fetch succeeds.
+exports.up = knex => knex.schema.createTable("user_fetch_deferrals", table => {
+    table.integer("user_id").notNullable().references("id").inTable("users").onDelete("CASCADE");
+    table.string("kind").notNullable(); // "accounts", "ranked_tiers" or "mastery"
+    table.bigInteger("deferred_until").notNullable(); // in milliseconds since the epoch
+    table.integer("failures").notNullable().defaultTo(0);
+
+    table.primary(["user_id", "kind"]);
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("user_fetch_deferrals");
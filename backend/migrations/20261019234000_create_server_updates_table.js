@@ -0,0 +1,16 @@
+// The progress of the current or last re-evaluation of all members of a server by shockwave.
+// It is kept here rather than in memory so that every shockwave instance can report it, and
+// so that an update requested on one instance while another runs it is not run twice at once.
+exports.up = knex => knex.schema.createTable("server_updates", table => {
+    table.integer("server_id").primary().references("id").inTable("servers").onDelete("CASCADE");
+    table.integer("total").notNullable().defaultTo(0);
+    table.integer("done").notNullable().defaultTo(0);
+    table.integer("errors").notNullable().defaultTo(0);
+    table.boolean("finished").notNullable().defaultTo(false);
+    table.boolean("rerun").notNullable().defaultTo(false); // whether to run once more after this update
+    table.timestamp("started_at").notNullable().defaultTo(knex.fn.now());
+    table.timestamp("finished_at").nullable();
+    table.timestamp("updated_at").notNullable().defaultTo(knex.fn.now()); // the last sign of life of the update
+});
+
+exports.down = knex => knex.schema.dropTableIfExists("server_updates");
@@ -13,6 +13,7 @@ Model.knex(knex);
 
 export { default as LeagueAccount } from "./league_account";
 export { default as Role, RoleCondition } from "./role";
-export { default as User, UserChampionStat, UserRank, UserAuthKey, UserMasteryDelta } from "./user";
+export { default as Ladder, LadderStep } from "./ladder";
+export { default as User, UserChampionStat, UserRank, UserAuthKey, UserMasteryDelta, UserHiddenRank } from "./user";
 export { default as Server, BlacklistedChannel } from "./server";
 export { default as GuildMember } from "./member";
\ No newline at end of file
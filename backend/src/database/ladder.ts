@@ -0,0 +1,71 @@
+import { Model, Pojo } from "objection";
+import { LadderMetric } from "../types/conditions";
+import * as decorators from "../util/objection";
+import omit = require("lodash/omit");
+
+@decorators.table("role_ladders")
+export default class Ladder extends Model {
+    /**
+     * Ensure that the metric is JSON (de)serialized.
+     */
+    static jsonAttributes = ["metric"];
+
+    /**
+     * Unique incremented ID for this ladder.
+     */
+    readonly id: number;
+
+    /**
+     * ID of the server this ladder belongs to.
+     */
+    server_id: number;
+
+    /**
+     * The name for this ladder, only used in the web interface.
+     */
+    name: string;
+
+    /**
+     * The metric that users are placed on this ladder by.
+     */
+    metric: LadderMetric;
+
+    /**
+     * Optionally eager-loaded steps.
+     */
+    steps?: LadderStep[];
+
+    /**
+     * Omit server_id from the JSON object.
+     */
+    $formatJson(json: Pojo) {
+        return omit(super.$formatJson(json), ["server_id"]);
+    }
+}
+
+@decorators.table("role_ladder_steps")
+export class LadderStep extends Model {
+    /**
+     * Unique incremented ID for this step.
+     */
+    readonly id: number;
+
+    /**
+     * The minimum value of the ladder metric needed to be in this step.
+     */
+    threshold: number;
+
+    /**
+     * The ID of the Discord role that users in this step receive.
+     */
+    snowflake: string;
+
+    /**
+     * Omit id and ladder_id from the JSON object.
+     */
+    $formatJson(json: Pojo) {
+        return omit(super.$formatJson(json), ["id", "ladder_id"]);
+    }
+}
+
+decorators.hasMany("steps", () => LadderStep, "id", "ladder_id")(Ladder);
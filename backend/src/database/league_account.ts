@@ -53,6 +53,12 @@ export default class LeagueAccount extends Model {
      */
     include_region: boolean;
 
+    /**
+     * The last time (in milliseconds) that any champion was played on this account,
+     * as reported by champion mastery. Null if unknown.
+     */
+    last_played: number | null;
+
     /**
      * Riot ID game name for this user, if available.
      */
@@ -87,6 +87,11 @@ export class RoleCondition extends Model {
      */
     options: any;
 
+    /**
+     * The amount of points this condition contributes to a weighted role.
+     */
+    weight: number;
+
     /**
      * Omit id and role_id from the JSON object.
      */
@@ -2,6 +2,7 @@ import { Model, Pojo } from "objection";
 import omit = require("lodash/omit");
 import * as decorators from "../util/objection";
 import Role from "./role";
+import Ladder from "./ladder";
 
 export type EngagementMode = {
     type: "on_join"
@@ -74,6 +75,19 @@ export default class Server extends Model {
      */
     nickname_pattern: string;
 
+    /**
+     * How to pick the account that nicknames are based on for users that did not
+     * mark any of their accounts as primary. One of `none` (reset their nickname),
+     * `highest_rank`, `most_mastery`, `recently_played` or `main_region`.
+     */
+    primary_account_fallback: "none" | "highest_rank" | "most_mastery" | "recently_played" | "main_region";
+
+    /**
+     * The region that most members of this server play on, used by the `main_region`
+     * primary account fallback. Null if not configured.
+     */
+    main_region: string | null;
+
     /**
      * The snowflake of the role that is being filtered on for server mastery leaderboards.
      * If not null, server leaderboards will only include users that have the specific role.
@@ -81,6 +95,16 @@ export default class Server extends Model {
      */
     server_leaderboard_role_requirement: string | null;
 
+    /**
+     * Problems with the permissions of Orianna on this server that prevent some roles
+     * or nicknames from being assigned, as last seen by Shockwave. Null if there are none.
+     */
+    permission_issues: {
+        missing_manage_roles: boolean;
+        missing_manage_nicknames: boolean;
+        unassignable_roles: string[];
+    } | null;
+
     /**
      * Optionally eager-loaded blacklisted channels.
      */
@@ -91,6 +115,11 @@ export default class Server extends Model {
      */
     roles?: Role[];
 
+    /**
+     * Optionally eager-loaded role ladders for this server.
+     */
+    ladders?: Ladder[];
+
     /**
      * Omit id from the JSON object.
      */
@@ -126,4 +155,5 @@ export class BlacklistedChannel extends Model {
 }
 
 decorators.hasMany("roles", () => Role, "id", "server_id")(Server);
+decorators.hasMany("ladders", () => Ladder, "id", "server_id")(Server);
 decorators.hasMany("blacklisted_channels", () => BlacklistedChannel, "id", "server_id")(Server);
\ No newline at end of file
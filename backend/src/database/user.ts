@@ -65,10 +65,17 @@ export default class User extends Model {
     last_account_update_timestamp: string;
 
     /**
-     * If this user should be treated as if they are unranked in every single
-     * queue.
+     * Epoch timestamp of when this user last used an Orianna command, or null if
+     * they never did. Shockwave updates recently active users more often.
+     * Stored as a string since knex returns bigint values as strings.
+     */
+    last_active_timestamp: string | null;
+
+    /**
+     * How much earlier than usual (in milliseconds) Shockwave should update this
+     * user, based on how likely their roles are to change soon. Maintained by Shockwave.
      */
-    treat_as_unranked: boolean;
+    update_boost: string;
 
     /**
      * If the accounts for this user should not be publicly shown.
@@ -111,6 +118,11 @@ export default class User extends Model {
      */
     deltas?: UserMasteryDelta[];
 
+    /**
+     * Optionally eager-loaded rank visibility rules, null if not specified in the query.
+     */
+    hidden_ranks?: UserHiddenRank[];
+
     /**
      * Returns the fully qualified URL to the avatar for this user.
      */
@@ -124,7 +136,6 @@ export default class User extends Model {
     $formatJson(json: Pojo) {
         return omit({
             ...super.$formatJson(json),
-            treat_as_unranked: Boolean(this.treat_as_unranked),
             hide_accounts: Boolean(this.hide_accounts)
         }, ["id", "token"]);
     }
@@ -233,6 +244,48 @@ export class UserRank extends Model {
     }
 }
 
+@decorators.table("user_hidden_ranks")
+export class UserHiddenRank extends Model {
+    /**
+     * Unique incremented ID for this rule.
+     */
+    readonly id: number;
+
+    /**
+     * The user this rule belongs to.
+     */
+    user_id: number;
+
+    /**
+     * The account whose ranks are hidden, or null if this rule
+     * applies to all accounts of the user.
+     */
+    account_id: number | null;
+
+    /**
+     * The queue that is hidden, or null if this rule applies to
+     * all queues.
+     */
+    queue: string | null;
+
+    /**
+     * Whether this rule hides the rank in the given queue on the given
+     * account. If no account is given, only rules that apply to all
+     * accounts will match.
+     */
+    hides(accountId: number | null, queue: string): boolean {
+        return (this.account_id === null || this.account_id === accountId)
+            && (this.queue === null || this.queue === queue);
+    }
+
+    /**
+     * Omit id and user_id from the JSON object.
+     */
+    $formatJson(json: Pojo) {
+        return omit(super.$formatJson(json), ["id", "user_id"]);
+    }
+}
+
 @decorators.table("user_auth_keys")
 export class UserAuthKey extends Model {
     /**
@@ -296,4 +349,5 @@ decorators.belongsTo("user", () => User, "user_id", "id")(UserAuthKey);
 decorators.hasMany("accounts", () => LeagueAccount, "id", "user_id")(User);
 decorators.hasMany("stats", () => UserChampionStat, "id", "user_id")(User);
 decorators.hasMany("ranks", () => UserRank, "id", "user_id")(User);
-decorators.hasMany("deltas", () => UserMasteryDelta, "id", "user_id")(User);
\ No newline at end of file
+decorators.hasMany("deltas", () => UserMasteryDelta, "id", "user_id")(User);
+decorators.hasMany("hidden_ranks", () => UserHiddenRank, "id", "user_id")(User);
\ No newline at end of file
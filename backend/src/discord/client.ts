@@ -424,8 +424,9 @@ export default class DiscordClient {
         // Else, do nothing.
         if (!oriUser) return;
 
-        // This should assign new roles, if appropriate.
-        shockwave.fetchAndUpdateUser(user.id);
+        // This should assign new roles, if appropriate. Only this server needs to
+        // change, so there's no point in touching the others the user is on.
+        shockwave.updateUserOnServer(oriUser, server);
     };
 
     /**
@@ -497,6 +498,9 @@ export default class DiscordClient {
 
         info("[%s] [%s] %s", author.username, matchedCommand.name, content);
 
+        // Remember that this user is active, so that Shockwave keeps their data fresher.
+        User.query().where("snowflake", author.id).patch({ last_active_timestamp: "" + Date.now() }).catch(() => {});
+
         // Check if this is the first time that this user has used an orianna command.
         // If in a server and yes, check if engagement should apply (only on commands
         // that are not no-mention, such as the nadeko/etc bot compat commands).
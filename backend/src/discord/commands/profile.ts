@@ -1,5 +1,5 @@
 import { differenceInDays } from "date-fns";
-import { UserChampionStat, UserMasteryDelta, UserRank } from "../../database";
+import { knex, UserChampionStat, UserHiddenRank, UserMasteryDelta, UserRank } from "../../database";
 import LeagueAccount from "../../database/league_account";
 import generateProfileGraphic from "../../graphics/profile";
 import formatName from "../../util/format-name";
@@ -38,7 +38,18 @@ const ProfileCommand: SlashCapableCommand = {
         const levelCounts: { level: number, count: number }[] = <any>await target.$relatedQuery("stats").groupBy("level", "user_id").count().select("level");
         const totalMastery: string[] = <any>await target.$relatedQuery("stats").sum("score").groupBy("user_id").pluck("sum");
         const avgMastery: string[] = <any>await target.$relatedQuery("stats").avg("score").groupBy("user_id").pluck("avg");
-        const rankedData = await target.$relatedQuery<UserRank>("ranks");
+        const hiddenRanks = await target.$relatedQuery<UserHiddenRank>("hidden_ranks");
+
+        // If the user hides the ranks of specific accounts, we need to recompute the best rank
+        // per queue from the ranks of the remaining accounts. Otherwise, the merged ranks suffice.
+        // Users that were not fetched since we started keeping ranks per account only have the
+        // merged ranks, in which case a rule for any account hides the whole queue (like Shockwave).
+        const accountRanks: { account_id: number, queue: string, tier: string }[] = hiddenRanks.some(x => x.account_id !== null)
+            ? await knex("league_account_ranks").where("user_id", target.id)
+            : [];
+        const rankedData: { queue: string, tier: string }[] = accountRanks.length
+            ? accountRanks.filter(x => !hiddenRanks.some(r => r.hides(x.account_id, x.queue)))
+            : (await target.$relatedQuery<UserRank>("ranks")).filter(x => !hiddenRanks.some(r => r.queue === null || r.queue === x.queue));
 
         // Formatting helpers.
         const champ = async (entry: UserChampionStat | UserMasteryDelta) => emote(await t.staticData.championById(entry.champion_id)) + " " + (await t.staticData.championById(entry.champion_id)).name;
@@ -60,9 +71,11 @@ const ProfileCommand: SlashCapableCommand = {
             "GRANDMASTER": `${emote("Grandmaster")} ` + t.ranked_tier_grandmaster,
             "CHALLENGER": `${emote("Challenger")} ` + t.ranked_tier_challenger
         })[rank];
-        const queueRank = (queue: string) =>
-            target.treat_as_unranked ? formatRank("UNRANKED") :
-                rankedData.find(x => x.queue === queue) ? formatRank(rankedData.find(x => x.queue === queue)!.tier) : formatRank("UNRANKED");
+        const tierOrder = ["UNRANKED", "IRON", "BRONZE", "SILVER", "GOLD", "PLATINUM", "EMERALD", "DIAMOND", "MASTER", "GRANDMASTER", "CHALLENGER"];
+        const queueRank = (queue: string) => {
+            const best = rankedData.filter(x => x.queue === queue).map(x => x.tier).sort((a, b) => tierOrder.indexOf(b) - tierOrder.indexOf(a))[0];
+            return formatRank(best || "UNRANKED");
+        };
         const daysAgo = (entry: UserMasteryDelta) => {
             const diff = Math.abs(differenceInDays(+entry.timestamp, new Date()));
             if (diff === 0) return t.time_ago_today;
@@ -39,11 +39,22 @@ const RefreshCommand: SlashCapableCommand = {
             await user.$relatedQuery("stats").delete();
         }
 
-        // Attempt to update user or time out after 20 seconds.
-        await Promise.race([
-            shockwave.fetchAndUpdateUser(user).then(x => { if (!x) throw new Error("Refreshing failed."); }),
-            new Promise((_, reject) => setTimeout(reject, 20000))
-        ]);
+        // Queue the update and show its progress until it finishes or times out.
+        const jobId = await shockwave.startUserUpdate(user);
+        if (jobId === null) throw new Error("Refreshing failed.");
+
+        let lastProgress = "";
+        const job = await shockwave.waitForUpdateJob(jobId, job => {
+            const progress = describeProgress(job);
+            if (progress === lastProgress) return;
+
+            lastProgress = progress;
+            msg.info({
+                title: `<a:${loadingEmojiId}> Refreshing${user.snowflake === author.id ? " your" : ""} data...`,
+                description: progress
+            }).catch(() => {});
+        });
+        if (!job || job.state !== "done") throw new Error("Refreshing failed.");
 
         // Update timestamps.
         user.$query().patch({
@@ -58,4 +69,30 @@ const RefreshCommand: SlashCapableCommand = {
         ]);
     }
 };
+
+const PHASE_NAMES: { [K in keyof shockwave.UpdateJob["phases"]]-?: string } = {
+    accounts: "Accounts",
+    mastery: "Champion mastery",
+    ranks: "Ranked tiers",
+    roles: "Roles and nickname"
+};
+
+const PHASE_ICONS: { [K in shockwave.UpdateJobPhase["state"]]: string } = {
+    pending: "⬜",
+    running: "🔄",
+    done: "✅",
+    failed: "❌",
+    skipped: "⏭️"
+};
+
+/**
+ * Render the state of every phase of the given update job, one per line.
+ */
+function describeProgress(job: shockwave.UpdateJob): string {
+    return (Object.keys(PHASE_NAMES) as (keyof typeof PHASE_NAMES)[])
+        .filter(phase => job.phases[phase])
+        .map(phase => `${PHASE_ICONS[job.phases[phase]!.state]} ${PHASE_NAMES[phase]}`)
+        .join("\n");
+}
+
 export default RefreshCommand;
\ No newline at end of file
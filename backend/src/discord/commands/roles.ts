@@ -83,7 +83,11 @@ const RolesCommand: SlashCapableCommand = {
             });
             if (cond.type === "total_mastery_score") return t.command_roles_total_mastery_score({ range: formatRange(cond.options) });
             if (cond.type === "ranked_tier") return t.command_roles_ranked_tier({ ranked: formatRanked(cond) });
-            if (cond.type === "server") return t.command_roles_region({ region: cond.options.region });
+            if (cond.type === "server") return t.command_roles_region({ region: ([] as string[]).concat(cond.options.region).join(", ") });
+            if (cond.type === "group") {
+                const nested = await Promise.all(cond.options.conditions.map(formatCondition));
+                return "(" + formatCombinator(cond.options.combinator) + " " + nested.join("; ") + ")";
+            }
 
             // Error out since we don't have a valid role here. It'll be caught and reported to ELK so we end up seeing it.
             throw new Error("Unknown condition type: " + JSON.stringify(cond));
@@ -93,6 +97,7 @@ const RolesCommand: SlashCapableCommand = {
             if (comb.type === "all") return t.command_roles_all_match;
             if (comb.type === "any") return t.command_roles_any_match;
             if (comb.type === "at_least") return t.command_roles_at_least_n({ amount: comb.amount });
+            if (comb.type === "weighted") return t.command_roles_weighted({ threshold: comb.threshold });
 
             // Should never happen.
             throw new Error("Unknown combinator type: " + JSON.stringify(comb));
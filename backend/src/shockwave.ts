@@ -1,12 +1,13 @@
 import config from "./config";
 import { Role, RoleCondition, Server, User } from "./database";
 import fetch from "node-fetch";
+import { RoleCombinator, TypedRoleCondition } from "./types/conditions";
 
 /**
  * Perform a request to Shockwave to fetch and update the latest statistics
- * for the given user. If a string argument is given, it is assumed to be the
- * snowflake of the user. Will return true if there is no user with that
- * snowflake (since we technically successfully updated the user).
+ * for the given user, and wait for it to finish. If a string argument is given,
+ * it is assumed to be the snowflake of the user. Will return true if there is no
+ * user with that snowflake (since we technically successfully updated the user).
  */
 export async function fetchAndUpdateUser(user: User | string): Promise<boolean> {
     if (typeof user === "string") {
@@ -16,11 +17,245 @@ export async function fetchAndUpdateUser(user: User | string): Promise<boolean>
         user = dbUser;
     }
 
+    const jobId = await startUserUpdate(user);
+    if (jobId === null) return false;
+
+    const job = await waitForUpdateJob(jobId);
+    return !!job && job.state === "done";
+}
+
+/**
+ * The state of a single step of an update job.
+ */
+export interface UpdateJobPhase {
+    state: "pending" | "running" | "done" | "failed" | "skipped";
+    error?: string;
+}
+
+/**
+ * A queued update of a user in Shockwave. Only the phases that the job runs are present.
+ */
+export interface UpdateJob {
+    id: number;
+    user_id: number;
+    server_id: number | null;
+    state: "pending" | "running" | "done" | "dead";
+    attempts: number;
+    last_error: string | null;
+    phases: { accounts?: UpdateJobPhase, mastery?: UpdateJobPhase, ranks?: UpdateJobPhase, roles?: UpdateJobPhase };
+}
+
+/**
+ * Ask Shockwave to fetch the latest statistics for the given user and update them
+ * everywhere. This only queues the update, use `waitForUpdateJob` to follow it.
+ * Resolves to the ID of the update job, or null if it could not be queued.
+ */
+export async function startUserUpdate(user: User): Promise<number | null> {
     return fetch(`${config.shockwave.url}/api/v1/user/${user.id}/update`, {
         method: "POST"
+    }).then(x => x.json()).then(x => x.successful ? x.job_id : null).catch(() => null);
+}
+
+/**
+ * Look up the current state of the update job with the given ID, or null if it is unknown.
+ */
+export async function getUpdateJob(id: number): Promise<UpdateJob | null> {
+    return fetch(`${config.shockwave.url}/api/v1/jobs/${id}`)
+        .then(x => x.ok ? x.json() : null)
+        .catch(() => null);
+}
+
+/**
+ * Follow the update job with the given ID until its first attempt finished, calling
+ * `onProgress` with every state we see. Resolves to the final state of the job, or
+ * null if the job is unknown or did not finish within `timeout` milliseconds. Note
+ * that a failed attempt puts the job back in the queue with its error.
+ */
+export async function waitForUpdateJob(id: number, onProgress?: (job: UpdateJob) => void, timeout = 120000): Promise<UpdateJob | null> {
+    const deadline = Date.now() + timeout;
+
+    while (Date.now() < deadline) {
+        const job = await getUpdateJob(id);
+        if (!job) return null;
+
+        if (onProgress) onProgress(job);
+        if (job.state === "done" || job.state === "dead" || (job.state === "pending" && job.last_error)) return job;
+
+        await new Promise(resolve => setTimeout(resolve, 1000));
+    }
+
+    return null;
+}
+
+/**
+ * Ask Shockwave to recompute the roles of every member of the given server, for
+ * example after the configuration of the server changed. Shockwave does this in
+ * the background, so this resolves before the members are actually updated. If the
+ * server is already being updated, it is updated again once that finishes.
+ */
+export async function updateServer(server: Server): Promise<boolean> {
+    return fetch(`${config.shockwave.url}/api/v1/server/${server.id}/update`, {
+        method: "POST"
     }).then(x => x.json()).then(x => !!x.successful).catch(() => false);
 }
 
+/**
+ * The changes that Shockwave would make to a single member of a server.
+ */
+export interface MemberPlan {
+    user_id: number;
+    server_id: number;
+    add_roles: { snowflake: string, role_id: number | null, announce: boolean }[];
+    remove_roles: { snowflake: string, role_id: number | null, announce: boolean }[];
+    nickname: { from: string | null, to: string | null } | null;
+}
+
+/**
+ * Ask Shockwave to recompute the roles and nickname of the given user on the given
+ * server only, leaving the other servers they are on alone. Unless `fetch` is false,
+ * the latest statistics of the user are fetched first. Resolves to the changes that
+ * were applied, or null if nothing was evaluated (for example because the user is
+ * not on the server) or the update failed.
+ */
+export async function updateUserOnServer(user: User, server: Server, fetchFirst = true): Promise<MemberPlan | null> {
+    return fetch(`${config.shockwave.url}/api/v1/server/${server.id}/user/${user.id}/update?fetch=${fetchFirst}`, {
+        method: "POST"
+    }).then(x => x.json()).then(x => x.successful ? x.plan : null).catch(() => null);
+}
+
+/**
+ * Ask Shockwave which roles and nicknames it would change on the given server,
+ * without actually changing them. If a role override is given, that role is
+ * evaluated using the given combinator and conditions instead of the saved ones.
+ * Only members that would change are returned.
+ */
+export async function dryRunServer(server: Server, override?: {
+    role: number,
+    combinator: RoleCombinator,
+    conditions: { type: TypedRoleCondition["type"], options: any, weight?: number }[]
+}): Promise<MemberPlan[]> {
+    const response = await fetch(`${config.shockwave.url}/api/v1/server/${server.id}/dry-run`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: override ? JSON.stringify(override) : undefined
+    });
+    if (!response.ok) throw new Error("Failed to communicate with Shockwave.");
+
+    return response.json();
+}
+
+/**
+ * Fetch the progress of the current (or last) update of the given server, or null
+ * if the server has not been updated within the last hour.
+ */
+export async function getServerUpdateProgress(server: Server): Promise<{
+    total: number,
+    done: number,
+    errors: number,
+    finished: boolean,
+    rerun: boolean,
+    started_at: number,
+    finished_at: number | null
+} | null> {
+    const response = await fetch(`${config.shockwave.url}/api/v1/server/${server.id}/update`);
+    if (response.status === 404) return null;
+    if (!response.ok) throw new Error("Failed to communicate with Shockwave.");
+
+    return response.json();
+}
+
+/**
+ * Fetch the most recent role and nickname changes that Shockwave made on the given
+ * server, newest first. If `before` is given, only entries older than the entry
+ * with that ID are returned.
+ */
+export async function getServerAuditLog(server: Server, before?: number, limit?: number): Promise<{
+    id: number,
+    server_id: number,
+    user_id: number,
+    action: "add_role" | "remove_role" | "nickname",
+    role_id: number | null,
+    role_snowflake: string | null,
+    nickname_from: string | null,
+    nickname_to: string | null,
+    conditions: any,
+    context: any,
+    success: boolean,
+    error: string | null,
+    timestamp: number
+}[]> {
+    const params = [before ? `before=${before}` : "", limit ? `limit=${limit}` : ""].filter(x => x).join("&");
+
+    const response = await fetch(`${config.shockwave.url}/api/v1/server/${server.id}/audit-log?${params}`);
+    if (!response.ok) throw new Error("Failed to communicate with Shockwave.");
+
+    return response.json();
+}
+
+/**
+ * Compile the given role condition DSL expression using Shockwave. Returns either
+ * the combinator and conditions described by the expression, or an error with the
+ * byte range of the source that the error applies to.
+ */
+export async function compileDsl(source: string): Promise<{
+    ok: true,
+    combinator: RoleCombinator,
+    conditions: { type: TypedRoleCondition["type"], options: any, weight: number }[]
+} | {
+    ok: false,
+    error: string,
+    span: { start: number, end: number },
+    rendered: string
+}> {
+    const response = await fetch(`${config.shockwave.url}/api/v1/dsl/compile`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({ source })
+    });
+
+    if (response.status !== 200 && response.status !== 400) throw new Error("Failed to communicate with Shockwave.");
+
+    return { ok: response.status === 200, ...await response.json() };
+}
+
+/**
+ * Render the given nickname pattern using Shockwave, either for the given user on
+ * the given server or with example values. The nickname is null if the user has no primary account.
+ * Returns an error with the byte range of the pattern if it is invalid.
+ */
+export async function previewNickname(pattern: string, target?: { user: User, server: Server }): Promise<{
+    ok: true,
+    nickname: string | null
+} | {
+    ok: false,
+    error: string,
+    span: { start: number, end: number }
+}> {
+    const response = await fetch(`${config.shockwave.url}/api/v1/nickname/preview`, {
+        method: "POST",
+        headers: { "Content-Type": "application/json" },
+        body: JSON.stringify({
+            pattern,
+            user_id: target ? target.user.id : null,
+            server_id: target ? target.server.id : null
+        })
+    });
+
+    if (response.status !== 200 && response.status !== 400) throw new Error("Failed to communicate with Shockwave.");
+
+    return { ok: response.status === 200, ...await response.json() };
+}
+
+/**
+ * Print all roles on the given server as role condition DSL expressions.
+ */
+export async function printServerDsl(server: Server): Promise<{ role: number, source: string }[]> {
+    const response = await fetch(`${config.shockwave.url}/api/v1/server/${server.id}/dsl`);
+    if (!response.ok) throw new Error("Failed to communicate with Shockwave.");
+
+    return response.json();
+}
+
 /**
  * Perform a request to shockwave to evaluate the conditions for the given
  * user on all roles configured on the given server. Returns whether the user applies
@@ -29,6 +264,7 @@ export async function fetchAndUpdateUser(user: User | string): Promise<boolean>
  */
 export async function evaluateRolesForUser(user: User, server: Server): Promise<Map<Role, {
     applies: boolean;
+    score: number;
     conditions: Map<RoleCondition, boolean>;
 }>> {
     const response = await fetch(`${config.shockwave.url}/api/v1/evaluate/${server.id}/${user.id}`, {
@@ -40,6 +276,7 @@ export async function evaluateRolesForUser(user: User, server: Server): Promise<
     const result: [{
         role: number,
         applies: boolean,
+        score: number,
         conditions: [number, boolean][]
     }] = await response.json();
 
@@ -59,6 +296,7 @@ export async function evaluateRolesForUser(user: User, server: Server): Promise<
 
         ret.set(role, {
             applies: entry.applies,
+            score: entry.score,
             conditions
         });
     }
@@ -21,28 +21,40 @@ export function evaluateRangeCondition(cnd: RangeCondition<{}>, value: number):
     return value >= cnd.min && value <= cnd.max;
 }
 
+/**
+ * Either a single region or a list of regions. Every entry may be a region
+ * ("EUW") or a macro region ("ANY_EUROPE", "ANY_AMERICAS", "ANY_ASIA", "ANY_SEA").
+ */
+export type RegionSet = string | string[];
+
 export interface MasteryLevelCondition {
     type: "mastery_level";
     options: RangeCondition<{
-        champion: number
+        champion: number,
+        region?: RegionSet
     }>;
 }
 
 export interface TotalMasteryLevelCondition {
     type: "total_mastery_level";
-    options: RangeCondition<{}>;
+    options: RangeCondition<{
+        region?: RegionSet
+    }>;
 }
 
 export interface MasteryScoreCondition {
     type: "mastery_score";
     options: RangeCondition<{
-        champion: number
+        champion: number,
+        region?: RegionSet
     }>;
 }
 
 export interface TotalMasteryScoreCondition {
     type: "total_mastery_score";
-    options: RangeCondition<{}>;
+    options: RangeCondition<{
+        region?: RegionSet
+    }>;
 }
 
 export interface RankedTierCondition {
@@ -51,13 +63,26 @@ export interface RankedTierCondition {
         compare_type: "higher" | "lower" | "equal";
         tier: string;
         queue: string;
+        region?: RegionSet;
     };
 }
 
 export interface ServerCondition {
     type: "server";
     options: {
-        region: string;
+        region: RegionSet;
+    };
+}
+
+/**
+ * A nested set of conditions with its own combinator. Created by the role
+ * condition DSL for expressions such as `a && (b || c)`.
+ */
+export interface GroupCondition {
+    type: "group";
+    options: {
+        combinator: RoleCombinator;
+        conditions: TypedRoleCondition[];
     };
 }
 
@@ -67,7 +92,8 @@ export type TypedRoleCondition =
     | MasteryScoreCondition
     | TotalMasteryScoreCondition
     | RankedTierCondition
-    | ServerCondition;
+    | ServerCondition
+    | GroupCondition;
 
 export type RoleCombinator = {
     type: "all"
@@ -76,4 +102,35 @@ export type RoleCombinator = {
 } | {
     type: "at_least",
     amount: number
+} | {
+    type: "weighted",
+    threshold: number
+};
+
+/**
+ * The metric a role ladder is based on. For ranked tiers, the thresholds of the
+ * ladder are tier indices where IRON is 1 and CHALLENGER is 10.
+ */
+export type LadderMetric = {
+    type: "mastery_level";
+    champion: number;
+    region?: RegionSet;
+} | {
+    type: "total_mastery_level";
+    region?: RegionSet;
+} | {
+    type: "mastery_score";
+    champion: number;
+    region?: RegionSet;
+} | {
+    type: "total_mastery_score";
+    region?: RegionSet;
+} | {
+    type: "ranked_tier";
+    queue: string;
+    region?: RegionSet;
+} | {
+    type: "mastery_gain";
+    champion?: number;
+    days: number;
 };
\ No newline at end of file
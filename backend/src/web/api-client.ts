@@ -3,7 +3,7 @@ import * as eris from "eris";
 import { Constants } from "eris";
 import randomstring = require("randomstring");
 import Joi = require("joi");
-import { Server, User, BlacklistedChannel, Role, RoleCondition, LeagueAccount, GuildMember } from "../database";
+import { Server, User, BlacklistedChannel, Role, RoleCondition, LeagueAccount, GuildMember, UserHiddenRank, Ladder, LadderStep } from "../database";
 import { getCachedGuild } from "../redis";
 import { hasPermission } from "../util/permissions";
 import { requireAuth, swallowErrors } from "./decorators";
@@ -14,6 +14,25 @@ import * as crypto from "crypto";
 import * as shockwave from "../shockwave";
 import { default as getTranslator, getLanguages as getI18nLanguages } from "../i18n";
 
+const REGION_SET_SCHEMA = Joi.alternatives(Joi.string(), Joi.array().items(Joi.string())).optional();
+
+/**
+ * Payload for creating or updating a role ladder.
+ */
+const LADDER_SCHEMA = {
+    name: Joi.string().required(),
+    metric: Joi.alternatives(
+        { type: Joi.any().valid("mastery_level", "mastery_score").required(), champion: Joi.number().required(), region: REGION_SET_SCHEMA },
+        { type: Joi.any().valid("total_mastery_level", "total_mastery_score").required(), region: REGION_SET_SCHEMA },
+        { type: Joi.any().valid("ranked_tier").required(), queue: Joi.string().required(), region: REGION_SET_SCHEMA },
+        { type: Joi.any().valid("mastery_gain").required(), champion: Joi.number().optional(), days: Joi.number().integer().min(1).required() }
+    ).required(),
+    steps: Joi.array().items({
+        threshold: Joi.number().integer().required(),
+        snowflake: Joi.string().regex(/^\d+$/).required()
+    }).required()
+};
+
 export default class WebAPIClient {
     private bot: eris.Client;
     private pendingAccounts: Map<string, { region: string, targetSummonerIcon: number, summoner: riot.Summoner, riotId: riot.RiotAccount }> = new Map();
@@ -44,6 +63,17 @@ export default class WebAPIClient {
         app.post("/api/v1/server/:id/role/:role", swallowErrors(this.updateRole));
         app.delete("/api/v1/server/:id/role/:role", swallowErrors(this.deleteRole));
         app.post("/api/v1/server/:id/role/:role/link", swallowErrors(this.linkRoleWithDiscord));
+        app.post("/api/v1/server/:id/role/:role/preview", swallowErrors(this.previewRole));
+        app.post("/api/v1/server/:id/update", swallowErrors(this.updateServer));
+        app.get("/api/v1/server/:id/update", swallowErrors(this.serveServerUpdateProgress));
+        app.get("/api/v1/server/:id/audit-log", swallowErrors(this.serveServerAuditLog));
+        app.post("/api/v1/server/:id/nickname/preview", swallowErrors(this.previewNickname));
+        app.get("/api/v1/server/:id/dsl", swallowErrors(this.serveServerDsl));
+        app.post("/api/v1/server/:id/role/:role/dsl", swallowErrors(this.updateRoleFromDsl));
+
+        app.post("/api/v1/server/:id/ladder", swallowErrors(this.addLadder));
+        app.post("/api/v1/server/:id/ladder/:ladder", swallowErrors(this.updateLadder));
+        app.delete("/api/v1/server/:id/ladder/:ladder", swallowErrors(this.deleteLadder));
     }
 
     /**
@@ -92,11 +122,13 @@ export default class WebAPIClient {
             });
         }
 
-        // Load user accounts.
-        await req.user.$loadRelated("accounts");
+        // Load user accounts and rank visibility.
+        await req.user.$loadRelated("[accounts, hidden_ranks]");
 
         res.json({
             ...req.user.toJSON(),
+            // Kept for older clients, true if the user hides all of their ranks.
+            treat_as_unranked: req.user.hidden_ranks!.some(x => x.account_id === null && x.queue === null),
             avatar: req.user.avatarURL,
             guilds
         });
@@ -108,13 +140,40 @@ export default class WebAPIClient {
     private patchUserProfile = requireAuth(async (req: express.Request, res: express.Response) => {
         if (!this.validate({
             treat_as_unranked: Joi.bool().optional(),
+            hidden_ranks: Joi.array().items({
+                account_id: Joi.number().allow(null),
+                queue: Joi.string().allow(null)
+            }).optional(),
             language: Joi.any().valid("", ...getI18nLanguages().map(x => x.code)).optional()
         }, req, res)) return;
 
+        // Shorthand for hiding or showing all ranks, used by older clients.
         if (typeof req.body.treat_as_unranked !== "undefined") {
-            await req.user.$query().patch({
-                treat_as_unranked: req.body.treat_as_unranked
-            });
+            await req.user.$relatedQuery("hidden_ranks").whereNull("account_id").whereNull("queue").delete();
+
+            if (req.body.treat_as_unranked) {
+                await req.user.$relatedQuery<UserHiddenRank>("hidden_ranks").insert({
+                    account_id: null,
+                    queue: null
+                });
+            }
+        }
+
+        // Replace all rank visibility rules, ensuring that they only reference the user's own accounts.
+        if (typeof req.body.hidden_ranks !== "undefined") {
+            await req.user.$loadRelated("accounts");
+            const rules: { account_id: number | null, queue: string | null }[] = req.body.hidden_ranks;
+            if (rules.some(x => x.account_id !== null && !req.user.accounts!.some(a => a.id === x.account_id))) {
+                return res.status(400).json({ ok: false, error: "Unknown account." });
+            }
+
+            await req.user.$relatedQuery("hidden_ranks").delete();
+            for (const rule of rules) {
+                await req.user.$relatedQuery<UserHiddenRank>("hidden_ranks").insert({
+                    account_id: rule.account_id,
+                    queue: rule.queue
+                });
+            }
         }
 
         if (typeof req.body.language !== "undefined") {
@@ -339,6 +398,7 @@ export default class WebAPIClient {
         if (!server) return;
 
         await server.$loadRelated("roles.*");
+        await server.$loadRelated("ladders.steps");
         await server.$loadRelated("blacklisted_channels");
 
         // Find our highest rank, for web interface logic.
@@ -387,6 +447,12 @@ export default class WebAPIClient {
             // Nickname pattern must be a string
             nickname_pattern: Joi.string().allow("").optional(),
 
+            // Fallback for users without a primary account. Must be a known strategy.
+            primary_account_fallback: Joi.any().valid("none", "highest_rank", "most_mastery", "recently_played", "main_region").optional(),
+
+            // Main region must be null or a valid region.
+            main_region: Joi.any().valid(null, ...REGIONS).optional(),
+
             // Engagement mode. Must match one of the patterns.
             engagement: Joi.alt([
                 { type: Joi.any().valid("on_command") },
@@ -398,6 +464,14 @@ export default class WebAPIClient {
             server_leaderboard_role_requirement: Joi.any().valid(null, ...guild.roles.map(x => x.id)).optional(),
         }, req, res)) return;
 
+        // Reject nickname patterns that would fail to render.
+        if (req.body.nickname_pattern) {
+            const preview = await shockwave.previewNickname(req.body.nickname_pattern);
+            if (!preview.ok) {
+                return res.status(400).json({ ok: false, error: preview.error, span: preview.span });
+            }
+        }
+
         // Convert engagement to the JSON representation.
         if (req.body.engagement) {
             req.body.engagement_json = JSON.stringify(req.body.engagement);
@@ -607,10 +681,12 @@ export default class WebAPIClient {
                 { type: "all" },
                 { type: "any" },
                 { type: "at_least", amount: Joi.number().required() },
+                { type: "weighted", threshold: Joi.number().required() },
             ],
             conditions: Joi.array().items({
                 type: Joi.string(),
-                options: Joi.object()
+                options: Joi.object(),
+                weight: Joi.number().integer().optional()
             })
         }, req, res)) return;
 
@@ -628,7 +704,8 @@ export default class WebAPIClient {
         for (const condition of req.body.conditions) {
             await role.$relatedQuery<RoleCondition>("conditions").insert({
                 type: condition.type,
-                options: condition.options
+                options: condition.options,
+                weight: typeof condition.weight === "number" ? condition.weight : 1
             });
         }
 
@@ -679,6 +756,207 @@ export default class WebAPIClient {
         });
     });
 
+    /**
+     * Re-evaluates all members of the specified server in the background.
+     */
+    private updateServer = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        res.json({ ok: await shockwave.updateServer(server) });
+    });
+
+    /**
+     * Serves the progress of the current or last re-evaluation of the specified server.
+     */
+    private serveServerUpdateProgress = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        res.json(await shockwave.getServerUpdateProgress(server));
+    });
+
+    /**
+     * Serves the most recent role and nickname changes made on the specified server.
+     */
+    private serveServerAuditLog = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        res.json(await shockwave.getServerAuditLog(server, +req.query.before || undefined, +req.query.limit || undefined));
+    });
+
+    /**
+     * Previews the changes that saving the given combinator and conditions for the
+     * specified role would cause to the members of the server, without saving them.
+     */
+    private previewRole = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        // Check that role exists and belongs to server.
+        const role = await server.$relatedQuery<Role>("roles").findById(req.params.role);
+        if (!role) return;
+
+        if (!this.validate({
+            combinator: [
+                { type: "all" },
+                { type: "any" },
+                { type: "at_least", amount: Joi.number().required() },
+                { type: "weighted", threshold: Joi.number().required() },
+            ],
+            conditions: Joi.array().items({
+                type: Joi.string(),
+                options: Joi.object(),
+                weight: Joi.number().integer().optional()
+            }).required()
+        }, req, res)) return;
+
+        res.json(await shockwave.dryRunServer(server, {
+            role: role.id,
+            combinator: req.body.combinator,
+            conditions: req.body.conditions
+        }));
+    });
+
+    /**
+     * Renders the given nickname pattern for the requesting user, so that it can
+     * be checked before saving it.
+     */
+    private previewNickname = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        if (!this.validate({
+            pattern: Joi.string().required()
+        }, req, res)) return;
+
+        // Invalid patterns are not an error here, since the editor shows them inline.
+        res.json(await shockwave.previewNickname(req.body.pattern, { user: req.user, server }));
+    });
+
+    /**
+     * Returns the conditions of every role in the specified server as a DSL expression.
+     */
+    private serveServerDsl = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        res.json(await shockwave.printServerDsl(server));
+    });
+
+    /**
+     * Replaces the combinator and conditions of the specified role with the ones
+     * described by the given DSL expression.
+     */
+    private updateRoleFromDsl = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        // Check that role exists and belongs to server.
+        const role = await server.$relatedQuery<Role>("roles").findById(req.params.role);
+        if (!role) return;
+
+        if (!this.validate({
+            source: Joi.string().required()
+        }, req, res)) return;
+
+        const compiled = await shockwave.compileDsl(req.body.source);
+        if (!compiled.ok) {
+            return res.status(400).json({ ok: false, error: compiled.error, span: compiled.span, rendered: compiled.rendered });
+        }
+
+        await role.$query().update({ combinator: compiled.combinator });
+
+        // Drop all old conditions and insert the compiled ones.
+        await role.$relatedQuery("conditions").delete();
+        for (const condition of compiled.conditions) {
+            await role.$relatedQuery<RoleCondition>("conditions").insert({
+                type: condition.type,
+                options: condition.options,
+                weight: condition.weight
+            });
+        }
+
+        res.json({ ok: true });
+    });
+
+    /**
+     * Creates a new role ladder with the specified metric and steps.
+     */
+    private addLadder = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        if (!this.validate(LADDER_SCHEMA, req, res)) return;
+
+        const ladder = await server.$relatedQuery<Ladder>("ladders").insertAndFetch({
+            name: req.body.name,
+            metric: req.body.metric
+        });
+
+        for (const step of req.body.steps) {
+            await ladder.$relatedQuery<LadderStep>("steps").insert({
+                threshold: step.threshold,
+                snowflake: step.snowflake
+            });
+        }
+
+        // Thresholds changed, so every member may be in a different bracket now.
+        shockwave.updateServer(server);
+
+        await ladder.$loadRelated("steps");
+        res.json(ladder.toJSON());
+    });
+
+    /**
+     * Replaces the name, metric and steps of the specified ladder.
+     */
+    private updateLadder = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        // Check that ladder exists and belongs to server.
+        const ladder = await server.$relatedQuery<Ladder>("ladders").findById(req.params.ladder);
+        if (!ladder) return;
+
+        if (!this.validate(LADDER_SCHEMA, req, res)) return;
+
+        await ladder.$query().update({
+            name: req.body.name,
+            metric: req.body.metric
+        });
+
+        // Drop all old steps and insert the new ones.
+        await ladder.$relatedQuery("steps").delete();
+        for (const step of req.body.steps) {
+            await ladder.$relatedQuery<LadderStep>("steps").insert({
+                threshold: step.threshold,
+                snowflake: step.snowflake
+            });
+        }
+
+        shockwave.updateServer(server);
+
+        res.json({ ok: true });
+    });
+
+    /**
+     * Deletes the specified ladder. Members keep the role of the bracket they were in.
+     */
+    private deleteLadder = requireAuth(async (req: express.Request, res: express.Response) => {
+        const { server } = await this.verifyServerRequest(req, res);
+        if (!server) return;
+
+        // Check that ladder exists and belongs to server.
+        const ladder = await server.$relatedQuery<Ladder>("ladders").findById(req.params.ladder);
+        if (!ladder) return;
+
+        await ladder.$query().delete();
+
+        res.json({ ok: true });
+    });
+
     /**
      * Validates the post contents of the specified request with the specified schema. Returns
      * true if the request is valid, false otherwise. This will close the request if the request
@@ -94,6 +94,20 @@ impl Cache {
         Ok(())
     }
 
+    /// Marks the Orianna configuration of the given guild as changed, so that
+    /// shockwave reloads it instead of using a copy it loaded earlier.
+    pub async fn invalidate_config(self: &Cache, guild_id: Id<GuildMarker>) -> CacheResult<()> {
+        let mut conn = self.0.write().await;
+
+        conn.incr(
+            format!("dissonance:guild:{}:config_version", guild_id.get()),
+            1,
+        )
+        .await?;
+
+        Ok(())
+    }
+
     /// Deletes the specified guild from the redis cache, including all
     /// channels that belonged to it.
     pub async fn delete_guild(self: &Cache, guild_id: Id<GuildMarker>) -> CacheResult<()> {
@@ -1,6 +1,6 @@
 use std::{collections::HashSet, error::Error};
 
-use sqlx::{postgres::PgPoolOptions, types::Json, PgPool};
+use sqlx::{postgres::PgPoolOptions, types::Json, PgPool, Row};
 use twilight_model::{
     guild::Member,
     id::{
@@ -136,4 +136,139 @@ impl Database {
 
         Ok(())
     }
+
+    /// Returns the roles that we currently know the given member to have on the
+    /// given server, or `None` if we don't know about the member.
+    pub async fn get_member_roles(
+        self: &Database,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> DBResult<Option<Vec<Id<RoleMarker>>>> {
+        let row =
+            sqlx::query("SELECT roles FROM guild_members WHERE guild_id = $1 AND user_id = $2")
+                .bind(guild_id.get() as i64)
+                .bind(user_id.get() as i64)
+                .fetch_optional(&self.0)
+                .await?;
+
+        Ok(row
+            .map(|x| x.try_get::<Json<Vec<Id<RoleMarker>>>, _>("roles"))
+            .transpose()?
+            .map(|x| x.0))
+    }
+
+    /// Records the given role changes of a member as manual overrides, so that
+    /// shockwave leaves these roles alone. Changes are given as `(role, added)`.
+    /// Only roles that are managed by Orianna on the server are recorded. Changes
+    /// that shockwave announced in `pending_role_changes` before making them are
+    /// its own, and consume the announcement instead.
+    pub async fn record_manual_role_changes(
+        self: &Database,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+        changes: &[(Id<RoleMarker>, bool)],
+    ) -> DBResult<u64> {
+        let snowflakes = changes.iter().map(|x| x.0.to_string()).collect::<Vec<_>>();
+        let added = changes.iter().map(|x| x.1).collect::<Vec<_>>();
+
+        let result = sqlx::query(
+            r#"
+            WITH own_changes AS (
+                DELETE FROM pending_role_changes pending
+                USING unnest($3::text[], $4::bool[]) AS changes(snowflake, added)
+                WHERE pending.guild_id = $1::bigint
+                    AND pending.user_id = $2::bigint
+                    AND pending.role_snowflake = changes.snowflake
+                    AND pending.present = changes.added
+                    AND pending.expires_at > now()
+                RETURNING pending.role_snowflake
+            )
+            INSERT INTO member_role_overrides (server_id, user_id, role_snowflake, present)
+            SELECT servers.id, users.id, changes.snowflake, changes.added
+            FROM unnest($3::text[], $4::bool[]) AS changes(snowflake, added)
+            JOIN servers ON servers.snowflake = $1
+            JOIN users ON users.snowflake = $2
+            WHERE (
+                EXISTS (SELECT 1 FROM roles WHERE roles.server_id = servers.id AND roles.snowflake = changes.snowflake)
+                OR EXISTS (
+                    SELECT 1 FROM role_ladder_steps steps
+                    JOIN role_ladders ladders ON ladders.id = steps.ladder_id
+                    WHERE ladders.server_id = servers.id AND steps.snowflake = changes.snowflake
+                )
+            )
+            AND changes.snowflake NOT IN (SELECT role_snowflake FROM own_changes)
+            ON CONFLICT (server_id, user_id, role_snowflake) DO UPDATE SET
+                present = EXCLUDED.present, created_at = now()
+            "#,
+        )
+        .bind(guild_id.to_string())
+        .bind(user_id.to_string())
+        .bind(snowflakes.as_slice())
+        .bind(added.as_slice())
+        .execute(&self.0)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Clears the snowflake of every Orianna role and ladder step on the given
+    /// server that refers to the given (deleted) Discord role. Returns the amount
+    /// of updated rows.
+    pub async fn clear_role_snowflake(
+        self: &Database,
+        guild_id: Id<GuildMarker>,
+        role_id: Id<RoleMarker>,
+    ) -> DBResult<u64> {
+        let roles = sqlx::query(
+            r#"
+            UPDATE roles SET snowflake = ''
+            WHERE snowflake = $2 AND server_id = (SELECT id FROM servers WHERE snowflake = $1)
+            "#,
+        )
+        .bind(guild_id.to_string())
+        .bind(role_id.to_string())
+        .execute(&self.0)
+        .await?;
+
+        let steps = sqlx::query(
+            r#"
+            UPDATE role_ladder_steps SET snowflake = ''
+            WHERE snowflake = $2 AND ladder_id IN (
+                SELECT role_ladders.id FROM role_ladders
+                JOIN servers ON servers.id = role_ladders.server_id
+                WHERE servers.snowflake = $1
+            )
+            "#,
+        )
+        .bind(guild_id.to_string())
+        .bind(role_id.to_string())
+        .execute(&self.0)
+        .await?;
+
+        Ok(roles.rows_affected() + steps.rows_affected())
+    }
+
+    /// Updates the name of every Orianna role on the given server that is linked
+    /// to the given Discord role. Returns the amount of updated roles.
+    pub async fn sync_role_name(
+        self: &Database,
+        guild_id: Id<GuildMarker>,
+        role_id: Id<RoleMarker>,
+        name: &str,
+    ) -> DBResult<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE roles SET name = $3
+            WHERE snowflake = $2 AND name != $3
+                AND server_id = (SELECT id FROM servers WHERE snowflake = $1)
+            "#,
+        )
+        .bind(guild_id.to_string())
+        .bind(role_id.to_string())
+        .bind(name)
+        .execute(&self.0)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
 }
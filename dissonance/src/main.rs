@@ -10,6 +10,7 @@ use crate::worker::Worker;
 mod cache;
 mod database;
 mod forwarder;
+mod shockwave;
 mod worker;
 
 #[tokio::main]
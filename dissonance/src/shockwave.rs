@@ -0,0 +1,53 @@
+use std::error::Error;
+
+use serde_json::json;
+use tracing::warn;
+use twilight_model::id::{
+    Id,
+    marker::{GuildMarker, UserMarker},
+};
+
+/// Simple Result alias that returns any error.
+type ShockwaveResult<T> = Result<T, Box<dyn Error>>;
+
+/// Client for the HTTP API of shockwave, used to tell it about events that
+/// should be handled right away instead of by one of its update loops.
+pub(crate) struct Shockwave(Option<(reqwest::Client, String)>);
+
+impl Shockwave {
+    /// Creates a client for the shockwave instance at the URL specified in the
+    /// SHOCKWAVE_URL environment variable. If it is not set, notifications are
+    /// silently dropped.
+    pub fn new() -> Shockwave {
+        let Ok(url) = std::env::var("SHOCKWAVE_URL") else {
+            warn!("No SHOCKWAVE_URL set, shockwave will not be notified of new members.");
+            return Shockwave(None);
+        };
+
+        Shockwave(Some((reqwest::Client::new(), url)))
+    }
+
+    /// Notifies shockwave that the given user joined the given guild, so that
+    /// their roles can be assigned immediately.
+    pub async fn member_joined(
+        self: &Shockwave,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> ShockwaveResult<()> {
+        let Some((client, url)) = &self.0 else {
+            return Ok(());
+        };
+
+        client
+            .post(format!("{}/api/v1/member/joined", url))
+            .json(&json!({
+                "guild_id": guild_id.to_string(),
+                "user_id": user_id.to_string(),
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
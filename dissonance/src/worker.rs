@@ -19,7 +19,7 @@ use twilight_http::Client;
 use twilight_model::{
     gateway::{
         payload::{
-            incoming::{GuildCreate, MemberChunk},
+            incoming::{GuildCreate, MemberAdd, MemberChunk, MemberUpdate, RoleDelete, RoleUpdate},
             outgoing::{
                 RequestGuildMembers, UpdatePresence, update_presence::UpdatePresencePayload,
             },
@@ -30,7 +30,7 @@ use twilight_model::{
     id::{Id, marker::GuildMarker},
 };
 
-use crate::{cache::Cache, database::Database, forwarder::Forwarder};
+use crate::{cache::Cache, database::Database, forwarder::Forwarder, shockwave::Shockwave};
 
 type VoidResult = Result<(), Box<dyn Error>>;
 
@@ -59,6 +59,7 @@ const STATUSES: &[(ActivityType, &'static str)] = &[
 
 pub(crate) struct Worker {
     db: Database,
+    shockwave: Shockwave,
     shard_senders: Vec<MessageSender>,
     cache: Cache,
     outstanding_member_requests: DashMap<Id<GuildMarker>, Instant>,
@@ -119,6 +120,7 @@ impl Worker {
             shards,
             Worker {
                 db,
+                shockwave: Shockwave::new(),
                 shard_senders,
                 cache,
                 outstanding_member_requests: DashMap::new(),
@@ -280,30 +282,11 @@ impl Worker {
             }
 
             Event::RoleUpdate(role) => {
-                handle_event!(
-                    "RoleUpdate",
-                    self.cache
-                        .update_guild(role.guild_id, |g| {
-                            g.roles
-                                .iter_mut()
-                                .filter(|g| g.id == role.role.id)
-                                .for_each(|r| {
-                                    *r = role.role.clone();
-                                });
-                        })
-                        .await
-                );
+                handle_event!("RoleUpdate", self.handle_role_updated(&role).await);
             }
 
             Event::RoleDelete(role) => {
-                handle_event!(
-                    "RoleDelete",
-                    self.cache
-                        .update_guild(role.guild_id, |g| {
-                            g.roles.retain(|x| x.id != role.role_id);
-                        })
-                        .await
-                );
+                handle_event!("RoleDelete", self.handle_role_deleted(&role).await);
             }
 
             Event::ChannelCreate(channel_create) if channel_create.guild_id.is_some() => {
@@ -345,21 +328,11 @@ impl Worker {
             }
 
             Event::MemberAdd(member) => {
-                handle_event!(
-                    "MemberAdd",
-                    self.db
-                        .upsert_member(member.guild_id, member.user.id, &member.nick, &member.roles)
-                        .await
-                );
+                handle_event!("MemberAdd", self.handle_member_added(&member).await);
             }
 
             Event::MemberUpdate(update) => {
-                handle_event!(
-                    "MemberUpdate",
-                    self.db
-                        .upsert_member(update.guild_id, update.user.id, &update.nick, &update.roles)
-                        .await
-                );
+                handle_event!("MemberUpdate", self.handle_member_update(&update).await);
             }
 
             Event::MemberRemove(removal) => {
@@ -471,6 +444,123 @@ impl Worker {
         Ok(())
     }
 
+    /// Invoked on a new task whenever a role is updated. Besides updating the cache,
+    /// the name of the Orianna roles linked to it is kept in sync.
+    async fn handle_role_updated(self: Arc<Worker>, role: &RoleUpdate) -> VoidResult {
+        self.cache
+            .update_guild(role.guild_id, |g| {
+                g.roles
+                    .iter_mut()
+                    .filter(|g| g.id == role.role.id)
+                    .for_each(|r| {
+                        *r = role.role.clone();
+                    });
+            })
+            .await?;
+
+        let updated = self
+            .db
+            .sync_role_name(role.guild_id, role.role.id, &role.role.name)
+            .await?;
+
+        if updated > 0 {
+            self.cache.invalidate_config(role.guild_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Invoked on a new task whenever a role is deleted. Orianna roles and ladder
+    /// steps that gave out the role are unlinked from it, so that shockwave stops
+    /// trying to assign it.
+    async fn handle_role_deleted(self: Arc<Worker>, role: &RoleDelete) -> VoidResult {
+        self.cache
+            .update_guild(role.guild_id, |g| {
+                g.roles.retain(|x| x.id != role.role_id);
+            })
+            .await?;
+
+        let cleared = self
+            .db
+            .clear_role_snowflake(role.guild_id, role.role_id)
+            .await?;
+
+        if cleared > 0 {
+            debug!(
+                "Unlinked {} role(s) from deleted role {} on {}",
+                cleared,
+                role.role_id.get(),
+                role.guild_id.get()
+            );
+
+            self.cache.invalidate_config(role.guild_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Invoked on a new task whenever a member joins a guild. Once they are stored,
+    /// shockwave is asked to assign their roles right away.
+    async fn handle_member_added(self: Arc<Worker>, member: &MemberAdd) -> VoidResult {
+        self.db
+            .upsert_member(member.guild_id, member.user.id, &member.nick, &member.roles)
+            .await?;
+
+        if !member.user.bot {
+            self.shockwave
+                .member_joined(member.guild_id, member.user.id)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Invoked on a new task whenever a member is updated. Besides storing the new
+    /// roles and nickname, role changes that were not made by shockwave are recorded
+    /// as manual overrides so that they are not immediately undone.
+    async fn handle_member_update(self: Arc<Worker>, update: &MemberUpdate) -> VoidResult {
+        let old_roles = self
+            .db
+            .get_member_roles(update.guild_id, update.user.id)
+            .await?;
+
+        self.db
+            .upsert_member(update.guild_id, update.user.id, &update.nick, &update.roles)
+            .await?;
+
+        // Without a previous state, we can't tell what changed.
+        let Some(old_roles) = old_roles else {
+            return Ok(());
+        };
+
+        let added = update.roles.iter().filter(|x| !old_roles.contains(x));
+        let removed = old_roles.iter().filter(|x| !update.roles.contains(x));
+        let changes = added
+            .map(|x| (*x, true))
+            .chain(removed.map(|x| (*x, false)))
+            .collect::<Vec<_>>();
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let recorded = self
+            .db
+            .record_manual_role_changes(update.guild_id, update.user.id, &changes)
+            .await?;
+
+        if recorded > 0 {
+            debug!(
+                "Recorded {} manual role change(s) for {} on {}",
+                recorded,
+                update.user.id.get(),
+                update.guild_id.get()
+            );
+        }
+
+        Ok(())
+    }
+
     /// Invoked on a new task whenever a guild is deleted. We clean up both the cache
     /// and the membership for the guild.
     async fn handle_guild_deleted(self: Arc<Worker>, guild_id: Id<GuildMarker>) -> VoidResult {
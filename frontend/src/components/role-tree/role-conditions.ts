@@ -19,7 +19,7 @@ export default class RoleConditions extends Vue {
     $root: App;
 
     role: Role;
-    conditions: { valid: boolean, opts: any }[] = [];
+    conditions: { valid: boolean, opts: any, weight: number }[] = [];
 
     discordRoles: DiscordRole[];
     highest: number;
@@ -29,7 +29,8 @@ export default class RoleConditions extends Vue {
     mounted() {
         this.conditions = this.role.conditions.map(x => ({
             valid: true,
-            opts: { type: x.type, ...x.options }
+            opts: { type: x.type, ...x.options },
+            weight: typeof x.weight === "number" ? x.weight : 1
         }));
 
         // If the combinator changes, mark as dirty (may not 100% be correct).
@@ -39,7 +40,7 @@ export default class RoleConditions extends Vue {
         });
     }
 
-    private handleChange(state: { valid: boolean, options: any }, condition: { valid: boolean, opts: any }) {
+    private handleChange(state: { valid: boolean, options: any }, condition: { valid: boolean, opts: any, weight: number }) {
         this.dirty = true;
         this.$emit("dirty");
         condition.valid = state.valid;
@@ -59,7 +60,7 @@ export default class RoleConditions extends Vue {
      * Adds a new empty condition.
      */
     private addCondition() {
-        this.conditions.push({ valid: false, opts: {} });
+        this.conditions.push({ valid: false, opts: {}, weight: 1 });
     }
 
     /**
@@ -109,10 +110,14 @@ export default class RoleConditions extends Vue {
             combinator: this.role.combinator.type === "at_least" ? {
                 type: "at_least",
                 amount: +this.role.combinator.amount
+            } : this.role.combinator.type === "weighted" ? {
+                type: "weighted",
+                threshold: +this.role.combinator.threshold
             } : { type: this.role.combinator.type },
             conditions: this.conditions.map(x => ({
                 type: x.opts.type,
-                options: { ...x.opts, type: undefined }
+                options: { ...x.opts, type: undefined },
+                weight: +x.weight
             }))
         });
         this.dirty = false;
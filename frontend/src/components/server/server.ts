@@ -13,6 +13,9 @@ export type RoleCombinator = {
 } | {
     type: "at_least",
     amount: number
+} | {
+    type: "weighted",
+    threshold: number
 };
 
 export interface Role {
@@ -23,6 +26,7 @@ export interface Role {
     conditions: {
         type: string;
         options: any;
+        weight: number;
     }[];
     combinator: RoleCombinator;
 }
@@ -44,6 +48,8 @@ export interface ServerDetails {
     roles: Role[];
     blacklisted_channels: string[];
     nickname_pattern: string;
+    primary_account_fallback: "none" | "highest_rank" | "most_mastery" | "recently_played" | "main_region";
+    main_region: string | null;
     server_leaderboard_role_requirement: string | null;
     discord: {
         channels: { id: string, name: string }[];
@@ -73,6 +79,8 @@ export default class ServerProfile extends Vue {
     message = "";
     rolesDirty = false;
     nickEnabled = false;
+    nickExample: string | null = null;
+    nickError = "";
     timeoutID: number = 0;
     languages: { code: string, name: string }[] = [{ code: "en-US", name: "English" }];
     $refs: { roleElements: RoleConditionsTy[] };
@@ -84,6 +92,7 @@ export default class ServerProfile extends Vue {
         // Load user details. Will error if the user is not logged in.
         this.server = (await this.$root.get<ServerDetails>("/api/v1/server/" + this.$route.params.id))!;
         this.nickEnabled = this.server.nickname_pattern !== "";
+        if (this.nickEnabled) this.previewNicknamePattern();
 
         // Redirect to intro if it's not yet complete.
         if (!this.server.completed_intro) {
@@ -191,6 +200,8 @@ export default class ServerProfile extends Vue {
      * Updates the nickname pattern with the server.
      */
     private async updateNicknamePattern() {
+        if (this.nickEnabled && !await this.previewNicknamePattern()) return;
+
         await this.$root.submit("/api/v1/server/" + this.$route.params.id, "PATCH", {
             nickname_pattern: this.nickEnabled ? this.server.nickname_pattern : ""
         });
@@ -198,6 +209,48 @@ export default class ServerProfile extends Vue {
         this.showMessage(this.nickEnabled ? "Updated nickname pattern!" : "Disabled automatic nickname enforcement.");
     }
 
+    /**
+     * Updates how the account used for nicknames is picked for users without a primary account.
+     */
+    private async updatePrimaryAccountFallback(evt: Event) {
+        const val = <ServerDetails["primary_account_fallback"]>(<HTMLSelectElement>evt.target).value;
+
+        this.server.primary_account_fallback = val;
+        await this.$root.submit("/api/v1/server/" + this.$route.params.id, "PATCH", {
+            primary_account_fallback: val
+        });
+
+        this.showMessage("Updated primary account fallback!");
+        await this.previewNicknamePattern();
+    }
+
+    /**
+     * Updates the main region of the server, used by the main region primary account fallback.
+     */
+    private async updateMainRegion() {
+        await this.$root.submit("/api/v1/server/" + this.$route.params.id, "PATCH", {
+            main_region: this.server.main_region
+        });
+
+        this.showMessage("Updated main region!");
+        await this.previewNicknamePattern();
+    }
+
+    /**
+     * Renders the current nickname pattern for the logged in user, showing any
+     * errors in it. Returns whether the pattern is valid.
+     */
+    private async previewNicknamePattern() {
+        const res = await this.$root.submit<{ ok: boolean, nickname?: string | null, error?: string }>("/api/v1/server/" + this.$route.params.id + "/nickname/preview", "POST", {
+            pattern: this.server.nickname_pattern
+        });
+        if (!res) return false;
+
+        this.nickExample = res.ok ? res.nickname! : null;
+        this.nickError = res.ok ? "" : res.error!;
+        return res.ok;
+    }
+
     /**
      * Marks the currently selected blacklist channel as being blacklisted.
      */
@@ -316,19 +369,6 @@ export default class ServerProfile extends Vue {
         return this.server.discord.roles.map(x => x.name);
     }
 
-    /**
-     * @returns an example of a nickname generated by the nickname pattern for this server
-     */
-    get nickExample() {
-        return this.server.nickname_pattern
-            .replace(/\{region\}/g, "KR")
-            .replace(/\{username\}/g, "Hide on bush")
-            .replace(/\{gamename\}/g, "Hide on bush")
-            .replace(/\{tagline\}g/, "KR1")
-            .replace(/\{discord_username\}/g, "T1 Faker")
-            .slice(0, 32);
-    }
-
     /**
      * @returns the URL to the discord CDN for the current engagement emote
      */
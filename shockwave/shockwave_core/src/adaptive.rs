@@ -0,0 +1,217 @@
+use std::{
+    sync::{
+        atomic::{AtomicI64, AtomicU32, AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tracing::{info, warn};
+
+use crate::fetch_plan::FetchKind;
+
+/// The fewest users that are queried from the database at once.
+const MIN_BATCH_SIZE: u32 = 20;
+/// The most users that are queried from the database at once.
+const MAX_BATCH_SIZE: u32 = 500;
+
+/// If raising the concurrency improved throughput by less than this factor, we
+/// assume that the rate limiter is the bottleneck rather than the concurrency.
+const SATURATION_FACTOR: f64 = 1.05;
+
+/// The static bounds of a worker loop, and the default target that operators can
+/// override through an environment variable.
+pub struct LoopSettings {
+    pub name: &'static str,
+    pub kind: FetchKind,
+    pub initial_concurrency: u32,
+    pub max_concurrency: u32,
+    /// The environment variable with the target staleness of this loop, in minutes.
+    pub target_env: &'static str,
+    pub default_target: Duration,
+}
+
+/// Adapts the concurrency of a single worker loop so that the data it fetches is
+/// never older than the target staleness, while staying within the Riot rate limits.
+///
+/// The loop reports how stale the data was of every user it successfully fetched,
+/// and every update it completes. Every time `adjust` is called, the concurrency is
+/// halved if the requests of the loop were rate limited, raised if we fall behind,
+/// and slowly lowered if we are well ahead, so that other loops can use the rate
+/// budget instead.
+pub struct LoopController {
+    pub settings: &'static LoopSettings,
+    target: Duration,
+    concurrency: AtomicU32,
+    /// The amount of updates completed so far.
+    completed: AtomicU64,
+    /// The highest staleness (in milliseconds) of users fetched since the last adjustment.
+    max_staleness: AtomicI64,
+    window: Mutex<Window>,
+}
+
+/// What happened in the previous adjustment window.
+struct Window {
+    started: Instant,
+    completed: u64,
+    rate_limited: u64,
+    /// The throughput before we raised the concurrency, if we did.
+    raised_from: Option<(u32, f64)>,
+    status: LoopStatus,
+}
+
+/// The state of a worker loop as of the last adjustment.
+#[derive(Serialize, Clone, Debug)]
+pub struct LoopStatus {
+    pub name: &'static str,
+    pub concurrency: u32,
+    pub batch_size: u32,
+    /// Updated users per second.
+    pub throughput: f64,
+    pub target_staleness_secs: u64,
+    /// The oldest data that was fetched in the last window, in seconds.
+    pub observed_staleness_secs: u64,
+    pub rate_limited: u64,
+    /// Whether the observed staleness stays within the target.
+    pub meeting_target: bool,
+}
+
+impl LoopController {
+    pub fn new(settings: &'static LoopSettings) -> LoopController {
+        let target = std::env::var(settings.target_env)
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .map_or(settings.default_target, |minutes: u64| Duration::from_secs(minutes * 60));
+
+        LoopController {
+            settings,
+            target,
+            concurrency: AtomicU32::new(settings.initial_concurrency),
+            completed: AtomicU64::new(0),
+            max_staleness: AtomicI64::new(0),
+            window: Mutex::new(Window {
+                started: Instant::now(),
+                completed: 0,
+                rate_limited: 0,
+                raised_from: None,
+                status: LoopStatus {
+                    name: settings.name,
+                    concurrency: settings.initial_concurrency,
+                    batch_size: batch_size(settings.initial_concurrency),
+                    throughput: 0.0,
+                    target_staleness_secs: target.as_secs(),
+                    observed_staleness_secs: 0,
+                    rate_limited: 0,
+                    meeting_target: true,
+                },
+            }),
+        }
+    }
+
+    /// How long users may wait between updates at most.
+    pub fn target(&self) -> Duration {
+        self.target
+    }
+
+    /// The amount of users that may be updated at the same time.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.load(Ordering::Relaxed) as usize
+    }
+
+    /// The amount of users to query from the database at once.
+    pub fn batch_size(&self) -> u32 {
+        batch_size(self.concurrency.load(Ordering::Relaxed))
+    }
+
+    /// The amount of updates completed so far.
+    pub fn completed(&self) -> u64 {
+        self.completed.load(Ordering::Relaxed)
+    }
+
+    /// Record that the data of a user was fetched when it was `staleness` milliseconds old.
+    pub fn record_fetched(&self, staleness: i64) {
+        self.max_staleness.fetch_max(staleness, Ordering::Relaxed);
+    }
+
+    /// Record that an update finished.
+    pub fn record_completed(&self) {
+        self.completed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The state of the loop as of the last adjustment.
+    pub fn status(&self) -> LoopStatus {
+        self.window.lock().unwrap().status.clone()
+    }
+
+    /// Adjust the concurrency based on what happened since the last call, given the
+    /// total amount of rate limited requests for the kind of data of this loop so far.
+    pub fn adjust(&self, rate_limited: u64) {
+        let mut window = self.window.lock().unwrap();
+
+        let completed = self.completed();
+        let elapsed = window.started.elapsed().as_secs_f64();
+        let throughput = (completed - window.completed) as f64 / elapsed.max(1.0);
+        let rate_limited_now = rate_limited - window.rate_limited;
+        let staleness = Duration::from_millis(self.max_staleness.swap(0, Ordering::Relaxed).max(0) as u64);
+
+        let current = self.concurrency.load(Ordering::Relaxed);
+        let max = self.settings.max_concurrency;
+        let behind = staleness > self.target;
+        let mut raised_from = None;
+
+        let next = if rate_limited_now > 0 {
+            (current / 2).max(1)
+        } else if behind {
+            match window.raised_from {
+                // Raising the concurrency did not help, so we are limited by the rate limits instead.
+                Some((previous, previous_throughput)) if throughput < previous_throughput * SATURATION_FACTOR => {
+                    previous
+                },
+                _ if current < max => {
+                    raised_from = Some((current, throughput));
+                    (current + (current / 4).max(1)).min(max)
+                },
+                _ => current,
+            }
+        } else if staleness < self.target / 2 {
+            (current - current / 10).max(1)
+        } else {
+            current
+        };
+
+        if behind && raised_from.is_none() {
+            warn!(
+                "Cannot keep {} within {:?} (oldest was {:?}, {} rate limited requests, concurrency {})",
+                self.settings.name, self.target, staleness, rate_limited_now, next
+            );
+        }
+
+        if next != current {
+            info!("Changing concurrency of {} from {} to {}", self.settings.name, current, next);
+            self.concurrency.store(next, Ordering::Relaxed);
+        }
+
+        *window = Window {
+            started: Instant::now(),
+            completed,
+            rate_limited,
+            raised_from,
+            status: LoopStatus {
+                name: self.settings.name,
+                concurrency: next,
+                batch_size: batch_size(next),
+                throughput,
+                target_staleness_secs: self.target.as_secs(),
+                observed_staleness_secs: staleness.as_secs(),
+                rate_limited: rate_limited_now,
+                meeting_target: !behind,
+            },
+        };
+    }
+}
+
+/// Query enough users at once to keep the given amount of updates busy.
+fn batch_size(concurrency: u32) -> u32 {
+    concurrency.clamp(MIN_BATCH_SIZE, MAX_BATCH_SIZE)
+}
@@ -0,0 +1,132 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use rand::Rng;
+use tracing::{info, warn};
+
+use crate::{database::Database, util::DynError};
+
+/// How often an instance renews its leases and rebalances the partitions.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a partition stays with an instance that stopped sending heartbeats.
+/// Instances that did not send a heartbeat within this time no longer count as
+/// active either, so their share of the partitions is divided among the others.
+const LEASE_DURATION: Duration = Duration::from_secs(30);
+
+/// Splits the users between several Shockwave processes that share the same
+/// database, so that they can all run the worker loops without updating the
+/// same users twice.
+///
+/// Users are divided into a fixed amount of partitions by their ID. Every instance
+/// leases an equal share of the partitions from the `worker_partitions` table and
+/// renews those leases with every heartbeat. If an instance dies, its leases expire
+/// and the remaining instances claim its partitions. If a new instance joins, the
+/// others release partitions until everyone owns about the same amount.
+///
+/// Cluster mode is enabled by setting `CLUSTER_MODE=true`. Without it, this instance
+/// considers itself the owner of all users.
+pub struct Cluster {
+    database: Arc<Database>,
+    instance_id: String,
+    enabled: bool,
+    state: Mutex<ClusterState>,
+}
+
+struct ClusterState {
+    partition_count: i32,
+    owned: Vec<i32>,
+    renewed: Instant,
+}
+
+impl Cluster {
+    pub fn new(database: Arc<Database>) -> Cluster {
+        let instance_id = std::env::var("SHOCKWAVE_INSTANCE_ID").unwrap_or_else(|_| {
+            format!(
+                "{}-{}-{:08x}",
+                std::env::var("HOSTNAME").unwrap_or("shockwave".to_string()),
+                std::process::id(),
+                rand::thread_rng().gen::<u32>()
+            )
+        });
+
+        Cluster {
+            database,
+            instance_id,
+            enabled: std::env::var("CLUSTER_MODE").is_ok_and(|x| x == "true"),
+            state: Mutex::new(ClusterState { partition_count: 1, owned: vec![], renewed: Instant::now() }),
+        }
+    }
+
+    /// The amount of partitions and the partitions that this instance currently owns.
+    /// A user belongs to partition `id % count`.
+    pub fn partitions(&self) -> (i32, Vec<i32>) {
+        if !self.enabled {
+            return (1, vec![0]);
+        }
+
+        let state = self.state.lock().unwrap();
+        (state.partition_count, state.owned.clone())
+    }
+
+    /// Keep sending heartbeats and balancing partitions with the other instances.
+    /// Returns immediately if cluster mode is disabled.
+    pub async fn run(&self) {
+        if !self.enabled {
+            return;
+        }
+
+        info!("Running in cluster mode as {}", self.instance_id);
+
+        loop {
+            if let Err(e) = self.heartbeat().await {
+                warn!("Failed to send cluster heartbeat: {:?}", e);
+
+                // Our leases have expired by now, so others may already be working on them.
+                let mut state = self.state.lock().unwrap();
+                if state.renewed.elapsed() > LEASE_DURATION && !state.owned.is_empty() {
+                    warn!("Lost all {} partitions after failing to renew their leases", state.owned.len());
+                    state.owned.clear();
+                }
+            }
+
+            tokio::time::sleep(HEARTBEAT_INTERVAL).await;
+        }
+    }
+
+    /// Renew our leases, then claim or release partitions until we own our share of them.
+    async fn heartbeat(&self) -> Result<(), DynError> {
+        let lease = LEASE_DURATION.as_secs_f64();
+
+        self.database.heartbeat_instance(&self.instance_id).await?;
+        let partition_count = self.database.count_partitions().await?;
+        let mut owned = self.database.renew_partition_leases(&self.instance_id, lease).await?;
+
+        // Our own heartbeat was just sent, so there is at least one active instance.
+        let active = self.database.count_active_instances(lease).await?.max(1);
+        let share = ((partition_count + active - 1) / active) as usize;
+
+        if owned.len() < share {
+            let claimed =
+                self.database.claim_partitions(&self.instance_id, lease, (share - owned.len()) as i64).await?;
+            owned.extend(claimed);
+        } else if owned.len() > share {
+            owned.sort_unstable();
+            let excess = owned.split_off(share);
+            self.database.release_partitions(&self.instance_id, &excess).await?;
+        }
+
+        owned.sort_unstable();
+
+        let mut state = self.state.lock().unwrap();
+        if state.owned != owned {
+            info!("Now owning {} of {} partitions ({} active instances)", owned.len(), partition_count, active);
+        }
+
+        *state = ClusterState { partition_count: partition_count as i32, owned, renewed: Instant::now() };
+
+        Ok(())
+    }
+}
@@ -1,16 +1,25 @@
-use std::ops::DerefMut;
+use std::{collections::HashMap, ops::DerefMut};
 
 use itertools::Itertools;
 use sqlx::{
     pool::PoolConnection,
     postgres::{PgPoolOptions, PgRow},
-    Executor, PgPool, Postgres, Row,
+    types::Json,
+    Executor, FromRow, PgPool, Postgres, Row,
 };
+use tracing::warn;
 
 use crate::{
-    db_model::{LeagueAccount, Role, ServerAndUserPresence, User, UserChampionStat, UserRank},
+    db_model::{
+        AccountChampionStat, AccountRank, AuditLogEntry, HiddenRank, JobStatus, Ladder, LadderStep, LeagueAccount,
+        MemberRoleOverride, NewAuditLogEntry, PermissionIssues, Role, Server, ServerAndUserPresence, ServerExemption,
+        UpdateJob, User, UserChampionStat, UserRank,
+    },
     evaluate::EvaluationContext,
+    jobs::JobPhases,
+    region::Region,
     role_model::RoleConditionWithId,
+    updater::{Fetch, ServerUpdateProgress},
     util::DynError,
 };
 
@@ -42,27 +51,117 @@ impl Database {
         Ok(self.0.acquire().await?)
     }
 
-    /// Find the `amount` updates whose `column_name` was least recently updated and
-    /// return a list of their IDs.
-    pub async fn find_lru_users(&self, column_name: &str, amount: i32) -> DBResult<Vec<i32>> {
-        Ok(sqlx::query(&format!(
-            "SELECT id FROM users WHERE has_accounts=true ORDER BY {} ASC NULLS LAST LIMIT $1",
+    /// Find up to `amount` users with at least one account whose `column_name` is due
+    /// for an update, most overdue first. A user is due once `column_name` minus their
+    /// boost is at most `due_before`. Results are ordered by `(due, id)` and start after
+    /// the given pair, so that the last returned pair can be used to request the next page.
+    /// Only users in one of the given partitions (out of `partition_count`) are returned,
+    /// and none whose fetches of the given kind are deferred until later.
+    pub async fn find_due_users(
+        &self,
+        column_name: &str,
+        kind: &str,
+        due_before: i64,
+        after: (i64, i32),
+        amount: i64,
+        (partition_count, partitions): (i32, &[i32]),
+    ) -> DBResult<Vec<(i64, i32)>> {
+        Ok(sqlx::query_as::<_, (i64, i32)>(&format!(
+            r#"
+            SELECT {0} - update_boost AS due, id FROM users
+            WHERE has_accounts = true AND {0} - update_boost <= $1 AND ({0} - update_boost, id) > ($2, $3)
+            AND id % $5 = ANY($6)
+            AND NOT EXISTS (
+                SELECT 1 FROM user_fetch_deferrals
+                WHERE user_id = users.id AND kind = $7
+                AND deferred_until > (extract(EPOCH FROM now()) * 1000)::bigint
+            )
+            ORDER BY {0} - update_boost ASC, id ASC
+            LIMIT $4
+            "#,
             column_name
         ))
+        .bind(due_before)
+        .bind(after.0)
+        .bind(after.1)
         .bind(amount)
-        .map(|x: PgRow| x.get::<i32, _>("id"))
+        .bind(partition_count)
+        .bind(partitions)
+        .bind(kind)
         .fetch_all(&self.0)
         .await?)
     }
 
-    /// Find amount users starting at offset, with at least one account.
-    pub async fn find_users(&self, amount: u32, offset: u32) -> DBResult<Vec<i32>> {
-        Ok(sqlx::query("SELECT id FROM users WHERE has_accounts=true ORDER BY id ASC NULLS LAST LIMIT $1 OFFSET $2")
-            .bind(amount as i64)
-            .bind(offset as i64)
-            .map(|x: PgRow| x.get::<i32, _>("id"))
-            .fetch_all(&self.0)
-            .await?)
+    /// Record that fetching the given kind of data for the user with the given ID failed,
+    /// and defer their next fetch by `backoff_ms`, doubled for every earlier failure in a
+    /// row but no more than `max_backoff_ms`.
+    pub async fn record_fetch_failure(
+        &self,
+        user_id: i32,
+        kind: &str,
+        backoff_ms: i64,
+        max_backoff_ms: i64,
+    ) -> DBResult {
+        sqlx::query(
+            r#"
+            INSERT INTO user_fetch_deferrals (user_id, kind, deferred_until, failures)
+            VALUES ($1, $2, (extract(EPOCH FROM now()) * 1000)::bigint + $3, 1)
+            ON CONFLICT (user_id, kind) DO UPDATE SET
+                failures = user_fetch_deferrals.failures + 1,
+                deferred_until = (extract(EPOCH FROM now()) * 1000)::bigint
+                    + LEAST($3 * power(2, user_fetch_deferrals.failures), $4)::bigint
+            "#,
+        )
+        .bind(user_id)
+        .bind(kind)
+        .bind(backoff_ms)
+        .bind(max_backoff_ms)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Defer fetching the given kind of data for the users with the given IDs until the
+    /// corresponding time in `until`, keeping any failures they had so far.
+    pub async fn defer_fetches(&self, kind: &str, user_ids: &[i32], until: &[i64]) -> DBResult {
+        sqlx::query(
+            r#"
+            INSERT INTO user_fetch_deferrals (user_id, kind, deferred_until)
+            SELECT user_id, $1, deferred_until FROM UNNEST($2::integer[], $3::bigint[]) AS x(user_id, deferred_until)
+            ON CONFLICT (user_id, kind) DO UPDATE SET deferred_until = EXCLUDED.deferred_until
+            "#,
+        )
+        .bind(kind)
+        .bind(user_ids)
+        .bind(until)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Forget the failures and deferral of the given kind of data for the user with the
+    /// given ID, after it was fetched successfully.
+    pub async fn clear_fetch_deferral(&self, user_id: i32, kind: &str) -> DBResult {
+        sqlx::query("DELETE FROM user_fetch_deferrals WHERE user_id = $1 AND kind = $2")
+            .bind(user_id)
+            .bind(kind)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Set how much earlier than usual the user with the given ID is updated.
+    pub async fn set_update_boost(&self, user_id: i32, boost: i64) -> DBResult {
+        sqlx::query("UPDATE users SET update_boost = $1 WHERE id = $2")
+            .bind(boost)
+            .bind(user_id)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
     }
 
     /// Batch retrieve matching evaluation contexts for the list of ids.
@@ -72,9 +171,8 @@ impl Database {
         let users =
             sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ANY($1)").bind(&user_ids).fetch_all(&self.0);
 
-        let accounts = sqlx::query_as::<_, LeagueAccount>("SELECT * FROM league_accounts WHERE user_id = ANY($1)")
-            .bind(&user_ids)
-            .fetch_all(&self.0);
+        let accounts =
+            sqlx::query("SELECT * FROM league_accounts WHERE user_id = ANY($1)").bind(&user_ids).fetch_all(&self.0);
 
         let ranks = sqlx::query_as::<_, UserRank>("SELECT * FROM user_ranks WHERE user_id = ANY($1)")
             .bind(&user_ids)
@@ -84,7 +182,24 @@ impl Database {
             .bind(&user_ids)
             .fetch_all(&self.0);
 
-        let (users, mut accounts, mut ranks, mut stats) = futures::try_join!(users, accounts, ranks, stats)?;
+        let account_stats = sqlx::query_as::<_, AccountChampionStat>(
+            "SELECT * FROM league_account_champion_stats WHERE user_id = ANY($1)",
+        )
+        .bind(&user_ids)
+        .fetch_all(&self.0);
+
+        let account_ranks =
+            sqlx::query_as::<_, AccountRank>("SELECT * FROM league_account_ranks WHERE user_id = ANY($1)")
+                .bind(&user_ids)
+                .fetch_all(&self.0);
+
+        let hidden_ranks = sqlx::query_as::<_, HiddenRank>("SELECT * FROM user_hidden_ranks WHERE user_id = ANY($1)")
+            .bind(&user_ids)
+            .fetch_all(&self.0);
+
+        let (users, accounts, mut ranks, mut stats, mut account_stats, mut account_ranks, mut hidden_ranks) =
+            futures::try_join!(users, accounts, ranks, stats, account_stats, account_ranks, hidden_ranks)?;
+        let mut accounts = parse_accounts(accounts)?;
 
         let mut ret = vec![];
 
@@ -93,6 +208,9 @@ impl Database {
                 accounts: accounts.extract_if(.., |x| x.user_id == user.id).collect(),
                 ranks: ranks.extract_if(.., |x| x.user_id == user.id).collect(),
                 stats: stats.extract_if(.., |x| x.user_id == user.id).collect(),
+                account_stats: account_stats.extract_if(.., |x| x.user_id == user.id).collect(),
+                account_ranks: account_ranks.extract_if(.., |x| x.user_id == user.id).collect(),
+                hidden_ranks: hidden_ranks.extract_if(.., |x| x.user_id == user.id).collect(),
                 user,
             });
         }
@@ -208,6 +326,85 @@ impl Database {
         Ok(())
     }
 
+    /// Replace the per-account mastery statistics for the given account with the given
+    /// set of `(champion id, level, points)` tuples. Champions not in the set are removed.
+    #[tracing::instrument(skip(self, conn, stats))]
+    #[inline]
+    pub async fn replace_account_stats(
+        &self,
+        conn: &mut Connection,
+        user_id: i32,
+        account_id: i32,
+        stats: &[(i32, i32, i32)],
+    ) -> DBResult {
+        let champs: Vec<_> = stats.iter().map(|x| x.0).collect();
+        let levels: Vec<_> = stats.iter().map(|x| x.1).collect();
+        let points: Vec<_> = stats.iter().map(|x| x.2).collect();
+
+        sqlx::query("DELETE FROM league_account_champion_stats WHERE account_id = $1 AND NOT champion_id = ANY($2)")
+            .bind(account_id)
+            .bind(champs.as_slice())
+            .execute(conn.deref_mut())
+            .await?;
+
+        if stats.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO league_account_champion_stats (account_id, user_id, champion_id, level, score)
+            SELECT $1, $2, * FROM unnest($3, $4, $5)
+            ON CONFLICT (account_id, champion_id) DO UPDATE SET
+                level = EXCLUDED.level, score = EXCLUDED.score
+            "#,
+        )
+        .bind(account_id)
+        .bind(user_id)
+        .bind(champs.as_slice())
+        .bind(levels.as_slice())
+        .bind(points.as_slice())
+        .execute(conn.deref_mut())
+        .await?;
+
+        Ok(())
+    }
+
+    /// Replace the per-account ranked tiers for the given account with the given set
+    /// of `(queue, tier)` tuples. Queues not in the set are removed.
+    #[tracing::instrument(skip(self, ranks))]
+    #[inline]
+    pub async fn replace_account_ranks(&self, user_id: i32, account_id: i32, ranks: &[(&str, &str)]) -> DBResult {
+        let queues: Vec<_> = ranks.iter().map(|x| x.0).collect();
+        let tiers: Vec<_> = ranks.iter().map(|x| x.1).collect();
+
+        sqlx::query("DELETE FROM league_account_ranks WHERE account_id = $1 AND NOT queue = ANY($2)")
+            .bind(account_id)
+            .bind(queues.as_slice())
+            .execute(&self.0)
+            .await?;
+
+        if ranks.is_empty() {
+            return Ok(());
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO league_account_ranks (account_id, user_id, queue, tier)
+            SELECT $1, $2, * FROM unnest($3::text[], $4::text[])
+            ON CONFLICT (account_id, queue) DO UPDATE SET tier = EXCLUDED.tier
+            "#,
+        )
+        .bind(account_id)
+        .bind(user_id)
+        .bind(queues.as_slice())
+        .bind(tiers.as_slice())
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
     /// Removes all stats for the given user for all champion ids given.
     #[tracing::instrument(skip(self, user_id, ids))]
     #[inline]
@@ -249,10 +446,9 @@ impl Database {
     #[tracing::instrument(skip(self, user_id))]
     #[inline]
     pub async fn get_user_accounts(&self, user_id: i32) -> DBResult<Vec<LeagueAccount>> {
-        Ok(sqlx::query_as::<_, LeagueAccount>("SELECT * FROM league_accounts WHERE user_id = $1")
-            .bind(user_id)
-            .fetch_all(&self.0)
-            .await?)
+        parse_accounts(
+            sqlx::query("SELECT * FROM league_accounts WHERE user_id = $1").bind(user_id).fetch_all(&self.0).await?,
+        )
     }
 
     /// Update the rank for the given user to the given tier in the given queue.
@@ -285,6 +481,95 @@ impl Database {
         Ok(())
     }
 
+    /// Announce that we are about to give (`true`) or take away (`false`) the given roles of
+    /// the given discord member, so that dissonance does not take the changes for manual
+    /// ones. Announcements that were not seen by dissonance expire after `ttl` seconds.
+    #[tracing::instrument(skip(self, changes))]
+    pub async fn record_pending_role_changes(
+        &self,
+        user_id: u64,
+        guild_id: u64,
+        changes: &[(&str, bool)],
+        ttl: f64,
+    ) -> DBResult {
+        let snowflakes = changes.iter().map(|x| x.0).collect::<Vec<_>>();
+        let present = changes.iter().map(|x| x.1).collect::<Vec<_>>();
+
+        sqlx::query(
+            r#"
+            INSERT INTO pending_role_changes (guild_id, user_id, role_snowflake, present, expires_at)
+            SELECT $1, $2, changes.snowflake, changes.present, now() + make_interval(secs => $5)
+            FROM unnest($3::text[], $4::bool[]) AS changes(snowflake, present)
+            ON CONFLICT (guild_id, user_id, role_snowflake) DO UPDATE SET
+                present = EXCLUDED.present, expires_at = EXCLUDED.expires_at
+            "#,
+        )
+        .bind(guild_id as i64)
+        .bind(user_id as i64)
+        .bind(snowflakes.as_slice())
+        .bind(present.as_slice())
+        .bind(ttl)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find the cached roles of the given discord user on the given guild, or an
+    /// empty list if they are not a member.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_discord_member_roles(&self, user_id: u64, guild_id: u64) -> DBResult<Vec<String>> {
+        Ok(sqlx::query("SELECT roles FROM guild_members WHERE user_id = $1 AND guild_id = $2")
+            .bind(user_id as i64)
+            .bind(guild_id as i64)
+            .map(|x: PgRow| x.get::<Option<Json<Vec<String>>>, _>("roles"))
+            .fetch_optional(&self.0)
+            .await?
+            .flatten()
+            .map_or(vec![], |x| x.0))
+    }
+
+    /// Remove a role from the cached roles of the given discord user, after it
+    /// was removed from them on Discord.
+    #[tracing::instrument(skip(self))]
+    pub async fn remove_discord_member_role(&self, user_id: u64, guild_id: u64, role_id: u64) -> DBResult {
+        sqlx::query("UPDATE guild_members SET roles = roles - $1 WHERE user_id = $2 AND guild_id = $3")
+            .bind(role_id.to_string())
+            .bind(user_id as i64)
+            .bind(guild_id as i64)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace the cached roles of the given discord user, after all of their roles
+    /// were updated on Discord at once.
+    #[tracing::instrument(skip(self, roles))]
+    pub async fn set_discord_member_roles(&self, user_id: u64, guild_id: u64, roles: &[String]) -> DBResult {
+        sqlx::query("UPDATE guild_members SET roles = $1 WHERE user_id = $2 AND guild_id = $3")
+            .bind(Json(roles))
+            .bind(user_id as i64)
+            .bind(guild_id as i64)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replace the cached nickname of the given discord user.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_discord_member_nickname(&self, user_id: u64, guild_id: u64, nickname: Option<&str>) -> DBResult {
+        sqlx::query("UPDATE guild_members SET nickname = $1 WHERE user_id = $2 AND guild_id = $3")
+            .bind(nickname)
+            .bind(user_id as i64)
+            .bind(guild_id as i64)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
     /// Add the rank for the given user with the given tier in the given queue.
     #[tracing::instrument(skip(self, user_id, queue, tier))]
     #[inline]
@@ -347,6 +632,19 @@ impl Database {
         Ok(())
     }
 
+    /// Update the last time that any champion was played on the given account.
+    #[tracing::instrument(skip(self))]
+    #[inline]
+    pub async fn update_account_last_played(&self, account_id: i32, last_played: Option<i64>) -> DBResult {
+        sqlx::query("UPDATE league_accounts SET last_played = $1 WHERE id = $2")
+            .bind(last_played)
+            .bind(account_id)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
     /// Query all role conditions for the server with the given ID.
     #[tracing::instrument(skip(self, server_id))]
     #[inline]
@@ -359,7 +657,7 @@ impl Database {
         let conditions = sqlx::query!(
             r#"
                 SELECT
-                    json_build_object('id', role_conditions.id, 'role_id', role_id, 'type', type, 'options', options)::text as json
+                    json_build_object('id', role_conditions.id, 'role_id', role_id, 'type', type, 'options', options, 'weight', weight)::text as json
                 FROM role_conditions
                 WHERE role_id IN (SELECT * FROM unnest($1::int[]))
             "#,
@@ -400,21 +698,70 @@ impl Database {
                 .bind(user_id)
                 .fetch_one(conn.deref_mut())
                 .await?,
-            accounts: sqlx::query_as::<_, LeagueAccount>("SELECT * FROM league_accounts WHERE user_id = $1")
+            accounts: parse_accounts(
+                sqlx::query("SELECT * FROM league_accounts WHERE user_id = $1")
+                    .bind(user_id)
+                    .fetch_all(conn.deref_mut())
+                    .await?,
+            )?,
+            ranks: sqlx::query_as::<_, UserRank>("SELECT * FROM user_ranks WHERE user_id = $1")
                 .bind(user_id)
                 .fetch_all(conn.deref_mut())
                 .await?,
-            ranks: sqlx::query_as::<_, UserRank>("SELECT * FROM user_ranks WHERE user_id = $1")
+            stats: sqlx::query_as::<_, UserChampionStat>("SELECT * FROM user_champion_stats WHERE user_id = $1")
                 .bind(user_id)
                 .fetch_all(conn.deref_mut())
                 .await?,
-            stats: sqlx::query_as::<_, UserChampionStat>("SELECT * FROM user_champion_stats WHERE user_id = $1")
+            account_stats: sqlx::query_as::<_, AccountChampionStat>(
+                "SELECT * FROM league_account_champion_stats WHERE user_id = $1",
+            )
+            .bind(user_id)
+            .fetch_all(conn.deref_mut())
+            .await?,
+            account_ranks: sqlx::query_as::<_, AccountRank>("SELECT * FROM league_account_ranks WHERE user_id = $1")
+                .bind(user_id)
+                .fetch_all(conn.deref_mut())
+                .await?,
+            hidden_ranks: sqlx::query_as::<_, HiddenRank>("SELECT * FROM user_hidden_ranks WHERE user_id = $1")
                 .bind(user_id)
                 .fetch_all(conn.deref_mut())
                 .await?,
         })
     }
 
+    /// Find the server with the given ID.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_server(&self, id: i32) -> DBResult<Server> {
+        Ok(sqlx::query_as::<_, Server>("SELECT * FROM servers WHERE id = $1").bind(id).fetch_one(&self.0).await?)
+    }
+
+    /// Find the problems with the permissions of the bot on the given server, as
+    /// last recorded while updating one of its members.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_server_permission_issues(&self, id: i32) -> DBResult<Option<PermissionIssues>> {
+        Ok(sqlx::query("SELECT permission_issues FROM servers WHERE id = $1")
+            .bind(id)
+            .map(|x: PgRow| x.get::<Option<Json<PermissionIssues>>, _>("permission_issues"))
+            .fetch_one(&self.0)
+            .await?
+            .map(|x| x.0))
+    }
+
+    /// Store the problems with the permissions of the bot on the given server, or
+    /// clear them if there are none. Only writes if something changed.
+    #[tracing::instrument(skip(self))]
+    pub async fn set_server_permission_issues(&self, id: i32, issues: Option<PermissionIssues>) -> DBResult {
+        sqlx::query(
+            "UPDATE servers SET permission_issues = $1 WHERE id = $2 AND permission_issues IS DISTINCT FROM $1",
+        )
+        .bind(issues.map(Json))
+        .bind(id)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
     /// Find all the roles in the server with the given ID.
     #[tracing::instrument(skip(self, id))]
     #[inline]
@@ -429,22 +776,743 @@ impl Database {
         .await?)
     }
 
+    /// Query all role ladders for the server with the given ID, together with their
+    /// steps. Ladders whose metric cannot be parsed are skipped.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_ladders_for_server(&self, server_id: i32) -> DBResult<Vec<(Ladder, Vec<LadderStep>)>> {
+        let ladders = sqlx::query("SELECT * FROM role_ladders WHERE server_id = $1")
+            .bind(server_id)
+            .fetch_all(&self.0)
+            .await?
+            .iter()
+            .filter_map(|row| Ladder::from_row(row).ok())
+            .collect::<Vec<_>>();
+
+        let mut steps = sqlx::query_as::<_, LadderStep>("SELECT * FROM role_ladder_steps WHERE ladder_id = ANY($1)")
+            .bind(ladders.iter().map(|x| x.id).collect::<Vec<i32>>())
+            .fetch_all(&self.0)
+            .await?;
+
+        Ok(ladders
+            .into_iter()
+            .map(|ladder| {
+                let ladder_steps = steps.extract_if(.., |x| x.ladder_id == ladder.id).collect();
+                (ladder, ladder_steps)
+            })
+            .collect())
+    }
+
+    /// Sum the mastery points that the given user gained in the last `days` days,
+    /// either on the given champion or on all champions if none is given.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_mastery_gain(&self, user_id: i32, champion_id: Option<i32>, days: i32) -> DBResult<i32> {
+        Ok(sqlx::query(
+            r#"
+            SELECT COALESCE(SUM(delta), 0)::int AS gain FROM user_mastery_deltas_ts
+            WHERE user_id = $1
+                AND ($2::int IS NULL OR champion_id = $2)
+                AND timestamp > NOW() - make_interval(days => $3)
+            "#,
+        )
+        .bind(user_id)
+        .bind(champion_id)
+        .bind(days)
+        .map(|x: PgRow| x.get::<i32, _>("gain"))
+        .fetch_one(&self.0)
+        .await?)
+    }
+
+    /// Find the IDs of all users that are a member of the server with the given ID.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_users_in_server(&self, server_id: i32) -> DBResult<Vec<i32>> {
+        Ok(sqlx::query(
+            r#"
+            SELECT users.id FROM guild_members
+            JOIN servers ON servers.snowflake::bigint = guild_members.guild_id
+            JOIN users ON users.snowflake::bigint = guild_members.user_id
+            WHERE servers.id = $1
+            ORDER BY users.id ASC
+            "#,
+        )
+        .bind(server_id)
+        .map(|x: PgRow| x.get::<i32, _>("id"))
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Find the IDs of the servers that each of the given users is a member of, as
+    /// pairs of user ID and server ID. Users that are on no server are left out.
+    #[tracing::instrument(skip(self, user_ids))]
+    pub async fn get_servers_of_users(&self, user_ids: &[i32]) -> DBResult<Vec<(i32, i32)>> {
+        Ok(sqlx::query_as::<_, (i32, i32)>(
+            r#"
+            SELECT users.id, servers.id FROM guild_members
+            JOIN servers ON servers.snowflake::bigint = guild_members.guild_id
+            JOIN users ON users.snowflake::bigint = guild_members.user_id
+            WHERE users.id = ANY($1)
+            "#,
+        )
+        .bind(user_ids)
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Record that the worker instance with the given ID is still alive.
+    pub async fn heartbeat_instance(&self, instance_id: &str) -> DBResult {
+        sqlx::query(
+            r#"
+            INSERT INTO worker_instances (id, heartbeat_at) VALUES ($1, now())
+            ON CONFLICT (id) DO UPDATE SET heartbeat_at = now()
+            "#,
+        )
+        .bind(instance_id)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Count the worker instances that sent a heartbeat within the last `within_secs` seconds.
+    pub async fn count_active_instances(&self, within_secs: f64) -> DBResult<i64> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            "SELECT COUNT(*) FROM worker_instances WHERE heartbeat_at > now() - make_interval(secs => $1)",
+        )
+        .bind(within_secs)
+        .fetch_one(&self.0)
+        .await?)
+    }
+
+    /// Count the partitions that users are split into.
+    pub async fn count_partitions(&self) -> DBResult<i64> {
+        Ok(sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM worker_partitions").fetch_one(&self.0).await?)
+    }
+
+    /// Extend the leases of all partitions owned by the given instance, returning
+    /// the partitions that it still owns.
+    pub async fn renew_partition_leases(&self, owner: &str, lease_secs: f64) -> DBResult<Vec<i32>> {
+        Ok(sqlx::query_scalar::<_, i32>(
+            r#"
+            UPDATE worker_partitions SET lease_expires_at = now() + make_interval(secs => $2)
+            WHERE owner = $1
+            RETURNING partition
+            "#,
+        )
+        .bind(owner)
+        .bind(lease_secs)
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Claim up to `amount` partitions that are unowned or whose lease expired for the
+    /// given instance, returning the partitions that were claimed. Rows that another
+    /// instance is claiming at the same time are skipped instead of waited for.
+    pub async fn claim_partitions(&self, owner: &str, lease_secs: f64, amount: i64) -> DBResult<Vec<i32>> {
+        Ok(sqlx::query_scalar::<_, i32>(
+            r#"
+            UPDATE worker_partitions SET owner = $1, lease_expires_at = now() + make_interval(secs => $2)
+            WHERE partition IN (
+                SELECT partition FROM worker_partitions
+                WHERE owner IS NULL OR lease_expires_at < now()
+                ORDER BY partition
+                LIMIT $3
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING partition
+            "#,
+        )
+        .bind(owner)
+        .bind(lease_secs)
+        .bind(amount)
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Give up the given partitions if they are owned by the given instance, so that
+    /// other instances can claim them.
+    pub async fn release_partitions(&self, owner: &str, partitions: &[i32]) -> DBResult {
+        sqlx::query(
+            "UPDATE worker_partitions SET owner = NULL, lease_expires_at = NULL WHERE owner = $1 AND partition = ANY($2)",
+        )
+        .bind(owner)
+        .bind(partitions)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Add a job to the update queue and return its ID. If the same job is already
+    /// pending, it is run at the higher of both priorities and no later than now, and
+    /// the ID of the pending job is returned instead. If that raises its priority, the
+    /// failed attempts of the pending job are forgotten, since the new request should
+    /// not wait out a backoff or die of failures that it did not cause.
+    pub async fn enqueue_job(
+        &self,
+        user_id: i32,
+        server_id: Option<i32>,
+        kind: Fetch,
+        priority: i32,
+        phases: &JobPhases,
+    ) -> DBResult<i64> {
+        Ok(sqlx::query_scalar::<_, i64>(
+            r#"
+            INSERT INTO update_jobs (user_id, server_id, kind, priority, phases) VALUES ($1, $2, $3, $4, $5)
+            ON CONFLICT (user_id, kind, (coalesce(server_id, 0))) WHERE state = 'pending'
+            DO UPDATE SET
+                priority = GREATEST(update_jobs.priority, EXCLUDED.priority),
+                next_run_at = CASE WHEN EXCLUDED.priority > update_jobs.priority THEN now()
+                    ELSE LEAST(update_jobs.next_run_at, EXCLUDED.next_run_at) END,
+                attempts = CASE WHEN EXCLUDED.priority > update_jobs.priority THEN 0 ELSE update_jobs.attempts END,
+                last_error = CASE WHEN EXCLUDED.priority > update_jobs.priority THEN NULL ELSE update_jobs.last_error END
+            RETURNING id
+            "#,
+        )
+        .bind(user_id)
+        .bind(server_id)
+        .bind(kind.as_str())
+        .bind(priority)
+        .bind(Json(phases))
+        .fetch_one(&self.0)
+        .await?)
+    }
+
+    /// Claim up to `amount` jobs that are due, highest priority first, and mark them as
+    /// running for `lease_secs` seconds. Running jobs whose lease expired are claimed again,
+    /// since whoever ran them presumably died. Jobs that are being claimed by someone else
+    /// at the same time are skipped instead of waited for.
+    ///
+    /// Only jobs of users in the given partitions (see `find_due_users`) are claimed, and
+    /// none of users that already have a job running elsewhere, such as on the instance
+    /// that owned their partition before.
+    pub async fn claim_jobs(
+        &self,
+        amount: i64,
+        lease_secs: f64,
+        (partition_count, partitions): (i32, &[i32]),
+    ) -> DBResult<Vec<UpdateJob>> {
+        Ok(sqlx::query_as::<_, UpdateJob>(
+            r#"
+            UPDATE update_jobs
+            SET state = 'running', attempts = attempts + 1, locked_until = now() + make_interval(secs => $2)
+            WHERE id IN (
+                SELECT id FROM update_jobs jobs
+                WHERE ((state = 'pending' AND next_run_at <= now()) OR (state = 'running' AND locked_until < now()))
+                AND user_id % $3 = ANY($4)
+                AND NOT EXISTS (
+                    SELECT 1 FROM update_jobs other
+                    WHERE other.user_id = jobs.user_id
+                    AND other.id <> jobs.id
+                    AND other.state = 'running'
+                    AND other.locked_until >= now()
+                )
+                ORDER BY priority DESC, next_run_at ASC
+                LIMIT $1
+                FOR UPDATE SKIP LOCKED
+            )
+            RETURNING id, user_id, server_id, kind, priority, attempts
+            "#,
+        )
+        .bind(amount)
+        .bind(lease_secs)
+        .bind(partition_count)
+        .bind(partitions)
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Update the phases of the running job with the given ID.
+    pub async fn set_job_phases(&self, job_id: i64, phases: &JobPhases) -> DBResult {
+        sqlx::query("UPDATE update_jobs SET phases = $2 WHERE id = $1 AND state = 'running'")
+            .bind(job_id)
+            .bind(Json(phases))
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Mark the job with the given ID as done, keeping it around so that its status can be looked up.
+    pub async fn finish_job(&self, job_id: i64, phases: &JobPhases, plan: Option<&serde_json::Value>) -> DBResult {
+        sqlx::query(
+            r#"
+            UPDATE update_jobs SET
+                state = 'done',
+                finished_at = now(),
+                locked_until = NULL,
+                last_error = NULL,
+                phases = $2,
+                plan = $3
+            WHERE id = $1
+            "#,
+        )
+        .bind(job_id)
+        .bind(Json(phases))
+        .bind(plan.map(Json))
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove the job with the given ID from the queue.
+    pub async fn delete_job(&self, job_id: i64) -> DBResult {
+        sqlx::query("DELETE FROM update_jobs WHERE id = $1").bind(job_id).execute(&self.0).await?;
+
+        Ok(())
+    }
+
+    /// Put the running job with the given ID back in the queue to be retried after
+    /// `delay_secs` seconds. If the same job was queued again in the meantime, this
+    /// one is removed in favor of that one.
+    pub async fn retry_job(&self, job_id: i64, delay_secs: f64, error: &str, phases: &JobPhases) -> DBResult {
+        let result = sqlx::query(
+            r#"
+            UPDATE update_jobs SET
+                state = 'pending',
+                next_run_at = now() + make_interval(secs => $2),
+                locked_until = NULL,
+                last_error = $3,
+                phases = $4
+            WHERE id = $1 AND NOT EXISTS (
+                SELECT 1 FROM update_jobs AS queued
+                WHERE queued.state = 'pending'
+                AND queued.user_id = update_jobs.user_id
+                AND queued.kind = update_jobs.kind
+                AND queued.server_id IS NOT DISTINCT FROM update_jobs.server_id
+            )
+            "#,
+        )
+        .bind(job_id)
+        .bind(delay_secs)
+        .bind(error)
+        .bind(Json(phases))
+        .execute(&self.0)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            self.delete_job(job_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Give up on the job with the given ID, keeping it around as dead for inspection.
+    pub async fn bury_job(&self, job_id: i64, error: &str, phases: &JobPhases) -> DBResult {
+        sqlx::query(
+            r#"
+            UPDATE update_jobs SET state = 'dead', finished_at = now(), locked_until = NULL, last_error = $2, phases = $3
+            WHERE id = $1
+            "#,
+        )
+        .bind(job_id)
+        .bind(error)
+        .bind(Json(phases))
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Get the status of the job with the given ID, if it still exists.
+    pub async fn get_job_status(&self, job_id: i64) -> DBResult<Option<JobStatus>> {
+        Ok(sqlx::query_as::<_, JobStatus>(
+            r#"
+            SELECT
+                id, user_id, server_id, kind, priority, state, attempts, last_error, phases, plan,
+                (extract(epoch from created_at) * 1000)::bigint AS created_at,
+                (extract(epoch from finished_at) * 1000)::bigint AS finished_at
+            FROM update_jobs WHERE id = $1
+            "#,
+        )
+        .bind(job_id)
+        .fetch_optional(&self.0)
+        .await?)
+    }
+
+    /// Remove finished jobs older than `done_age_secs` seconds and dead jobs older than
+    /// `dead_age_secs` seconds. Returns the amount of removed jobs.
+    pub async fn prune_jobs(&self, done_age_secs: f64, dead_age_secs: f64) -> DBResult<u64> {
+        Ok(sqlx::query(
+            r#"
+            DELETE FROM update_jobs
+            WHERE (state = 'done' AND finished_at < now() - make_interval(secs => $1))
+            OR (state = 'dead' AND coalesce(finished_at, created_at) < now() - make_interval(secs => $2))
+            "#,
+        )
+        .bind(done_age_secs)
+        .bind(dead_age_secs)
+        .execute(&self.0)
+        .await?
+        .rows_affected())
+    }
+
+    /// Start a new update of the server with the given ID, unless one is already running.
+    /// An update that showed no sign of life for `timeout_secs` seconds is assumed to have
+    /// died with its instance and is replaced. Returns whether the update was started.
+    pub async fn begin_server_update(&self, server_id: i32, timeout_secs: f64) -> DBResult<bool> {
+        Ok(sqlx::query_scalar::<_, bool>(
+            r#"
+            INSERT INTO server_updates (server_id) VALUES ($1)
+            ON CONFLICT (server_id) DO UPDATE SET
+                total = 0, done = 0, errors = 0, finished = false, rerun = false,
+                started_at = now(), finished_at = NULL, updated_at = now()
+            WHERE server_updates.finished OR server_updates.updated_at < now() - make_interval(secs => $2)
+            RETURNING true
+            "#,
+        )
+        .bind(server_id)
+        .bind(timeout_secs)
+        .fetch_optional(&self.0)
+        .await?
+        .is_some())
+    }
+
+    /// Ask the running update of the server with the given ID to run once more after it
+    /// finishes. Returns false if no update is running (anymore).
+    pub async fn request_server_update_rerun(&self, server_id: i32) -> DBResult<bool> {
+        Ok(sqlx::query("UPDATE server_updates SET rerun = true WHERE server_id = $1 AND NOT finished")
+            .bind(server_id)
+            .execute(&self.0)
+            .await?
+            .rows_affected()
+            > 0)
+    }
+
+    /// Set the amount of members that the running update of the given server updates.
+    pub async fn set_server_update_total(&self, server_id: i32, total: i32) -> DBResult {
+        sqlx::query("UPDATE server_updates SET total = $2, updated_at = now() WHERE server_id = $1")
+            .bind(server_id)
+            .bind(total)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Count a member as updated in the running update of the given server.
+    pub async fn add_server_update_progress(&self, server_id: i32, failed: bool) -> DBResult {
+        sqlx::query(
+            "UPDATE server_updates SET done = done + 1, errors = errors + $2, updated_at = now() WHERE server_id = $1",
+        )
+        .bind(server_id)
+        .bind(failed as i32)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Mark the running update of the given server as finished, unless it was asked to
+    /// run once more, in which case it starts over. Returns whether it should run again.
+    pub async fn end_server_update(&self, server_id: i32) -> DBResult<bool> {
+        Ok(sqlx::query_scalar::<_, bool>(
+            r#"
+            UPDATE server_updates SET
+                finished = NOT rerun,
+                finished_at = CASE WHEN rerun THEN NULL ELSE now() END,
+                total = CASE WHEN rerun THEN 0 ELSE total END,
+                done = CASE WHEN rerun THEN 0 ELSE done END,
+                errors = CASE WHEN rerun THEN 0 ELSE errors END,
+                started_at = CASE WHEN rerun THEN now() ELSE started_at END,
+                rerun = false,
+                updated_at = now()
+            WHERE server_id = $1
+            RETURNING NOT finished
+            "#,
+        )
+        .bind(server_id)
+        .fetch_optional(&self.0)
+        .await?
+        .unwrap_or(false))
+    }
+
+    /// Look up the progress of the current or last update of the given server.
+    pub async fn get_server_update_progress(&self, server_id: i32) -> DBResult<Option<ServerUpdateProgress>> {
+        Ok(sqlx::query_as::<_, ServerUpdateProgress>(
+            r#"
+            SELECT
+                total, done, errors, finished, rerun,
+                (extract(epoch from started_at) * 1000)::bigint AS started_at,
+                (extract(epoch from finished_at) * 1000)::bigint AS finished_at
+            FROM server_updates WHERE server_id = $1
+            "#,
+        )
+        .bind(server_id)
+        .fetch_optional(&self.0)
+        .await?)
+    }
+
+    /// Remove the progress of server updates that finished more than `age_secs` seconds ago.
+    pub async fn prune_server_updates(&self, age_secs: f64) -> DBResult {
+        sqlx::query("DELETE FROM server_updates WHERE finished AND finished_at < now() - make_interval(secs => $1)")
+            .bind(age_secs)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Find the IDs of the user and server with the given snowflakes, if both are
+    /// known to Orianna.
+    #[tracing::instrument(skip(self))]
+    pub async fn find_member_ids(&self, guild_snowflake: &str, user_snowflake: &str) -> DBResult<Option<(i32, i32)>> {
+        Ok(sqlx::query_as::<_, (i32, i32)>(
+            "SELECT users.id, servers.id FROM users, servers WHERE users.snowflake = $1 AND servers.snowflake = $2",
+        )
+        .bind(user_snowflake)
+        .bind(guild_snowflake)
+        .fetch_optional(&self.0)
+        .await?)
+    }
+
     /// Find all the servers that the user with the given snowflake is on.
     #[tracing::instrument(skip(self, user_snowflake))]
     #[inline]
     pub async fn get_servers_with_user(&self, user_snowflake: String) -> DBResult<Vec<ServerAndUserPresence>> {
         Ok(sqlx::query_as::<_, ServerAndUserPresence>(
             r#"
-            SELECT servers.*, guild_members.roles, guild_members.nickname
+            SELECT
+                servers.*, guild_members.roles, guild_members.nickname,
+                COALESCE(exemptions.roles, false) AS exempt_roles,
+                COALESCE(exemptions.nickname, false) AS exempt_nickname,
+                COALESCE((
+                    SELECT json_agg(overrides.role_snowflake)
+                    FROM member_role_overrides overrides
+                    WHERE overrides.server_id = servers.id
+                    AND overrides.user_id = (SELECT id FROM users WHERE snowflake = $1)
+                ), '[]') AS overridden_roles
             FROM guild_members
             JOIN servers ON servers.snowflake::bigint = guild_members.guild_id
-            WHERE user_id=$1::bigint
+            LEFT JOIN server_exemptions exemptions
+                ON exemptions.server_id = servers.id
+                AND exemptions.user_id = (SELECT id FROM users WHERE snowflake = $1)
+            WHERE guild_members.user_id=$1::bigint
         "#,
         )
         .bind(user_snowflake)
         .fetch_all(&self.0)
         .await?)
     }
+
+    /// Find the presence of every member of the server with the given ID, by user ID.
+    /// Same as `get_servers_with_user`, but for all users of a single server at once.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_server_presences(&self, server_id: i32) -> DBResult<HashMap<i32, ServerAndUserPresence>> {
+        Ok(sqlx::query(
+            r#"
+            SELECT
+                users.id AS member_user_id, servers.*, guild_members.roles, guild_members.nickname,
+                COALESCE(exemptions.roles, false) AS exempt_roles,
+                COALESCE(exemptions.nickname, false) AS exempt_nickname,
+                COALESCE((
+                    SELECT json_agg(overrides.role_snowflake)
+                    FROM member_role_overrides overrides
+                    WHERE overrides.server_id = servers.id
+                    AND overrides.user_id = users.id
+                ), '[]') AS overridden_roles
+            FROM guild_members
+            JOIN servers ON servers.snowflake::bigint = guild_members.guild_id
+            JOIN users ON users.snowflake::bigint = guild_members.user_id
+            LEFT JOIN server_exemptions exemptions
+                ON exemptions.server_id = servers.id
+                AND exemptions.user_id = users.id
+            WHERE servers.id = $1
+        "#,
+        )
+        .bind(server_id)
+        .try_map(|row: PgRow| Ok((row.try_get("member_user_id")?, ServerAndUserPresence::from_row(&row)?)))
+        .fetch_all(&self.0)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    /// Find all exemptions configured on the server with the given ID.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_exemptions_for_server(&self, server_id: i32) -> DBResult<Vec<ServerExemption>> {
+        Ok(sqlx::query_as::<_, ServerExemption>("SELECT * FROM server_exemptions WHERE server_id = $1")
+            .bind(server_id)
+            .fetch_all(&self.0)
+            .await?)
+    }
+
+    /// Find all exemptions that the user with the given ID has, across all servers.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_exemptions_for_user(&self, user_id: i32) -> DBResult<Vec<ServerExemption>> {
+        Ok(sqlx::query_as::<_, ServerExemption>("SELECT * FROM server_exemptions WHERE user_id = $1")
+            .bind(user_id)
+            .fetch_all(&self.0)
+            .await?)
+    }
+
+    /// Insert or update the exemption for the given user on the given server. If
+    /// neither roles nor the nickname are exempted, the exemption is removed instead.
+    #[tracing::instrument(skip(self))]
+    pub async fn upsert_exemption(&self, server_id: i32, user_id: i32, roles: bool, nickname: bool) -> DBResult {
+        if !roles && !nickname {
+            return self.remove_exemption(server_id, user_id).await;
+        }
+
+        sqlx::query(
+            r#"
+            INSERT INTO server_exemptions (server_id, user_id, roles, nickname)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (server_id, user_id) DO UPDATE SET
+                roles = EXCLUDED.roles, nickname = EXCLUDED.nickname
+            "#,
+        )
+        .bind(server_id)
+        .bind(user_id)
+        .bind(roles)
+        .bind(nickname)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Remove the exemption for the given user on the given server, if any.
+    #[tracing::instrument(skip(self))]
+    pub async fn remove_exemption(&self, server_id: i32, user_id: i32) -> DBResult {
+        sqlx::query("DELETE FROM server_exemptions WHERE server_id = $1 AND user_id = $2")
+            .bind(server_id)
+            .bind(user_id)
+            .execute(&self.0)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Find all manual role overrides on the server with the given ID.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_role_overrides_for_server(&self, server_id: i32) -> DBResult<Vec<MemberRoleOverride>> {
+        Ok(sqlx::query_as::<_, MemberRoleOverride>(
+            r#"
+            SELECT *, (EXTRACT(EPOCH FROM created_at) * 1000)::bigint AS timestamp
+            FROM member_role_overrides
+            WHERE server_id = $1
+            ORDER BY created_at DESC
+            "#,
+        )
+        .bind(server_id)
+        .fetch_all(&self.0)
+        .await?)
+    }
+
+    /// Remove the manual role overrides of the given user on the given server, so
+    /// that Orianna manages their roles again. If a role is given, only the override
+    /// for that role is removed. Returns the amount of removed overrides.
+    #[tracing::instrument(skip(self))]
+    pub async fn remove_role_overrides(&self, server_id: i32, user_id: i32, role: Option<String>) -> DBResult<u64> {
+        let result = sqlx::query(
+            r#"
+            DELETE FROM member_role_overrides
+            WHERE server_id = $1 AND user_id = $2 AND ($3::text IS NULL OR role_snowflake = $3)
+            "#,
+        )
+        .bind(server_id)
+        .bind(user_id)
+        .bind(role)
+        .execute(&self.0)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Store a change that was made to a member in the audit log.
+    #[tracing::instrument(skip(self))]
+    pub async fn insert_audit_log_entry(&self, entry: NewAuditLogEntry) -> DBResult {
+        sqlx::query(
+            r#"
+            INSERT INTO role_audit_log (
+                server_id, user_id, action, role_id, role_snowflake, nickname_from,
+                nickname_to, conditions, context, success, error
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            "#,
+        )
+        .bind(entry.server_id)
+        .bind(entry.user_id)
+        .bind(entry.action)
+        .bind(entry.role_id)
+        .bind(entry.role_snowflake)
+        .bind(entry.nickname_from)
+        .bind(entry.nickname_to)
+        .bind(Json(entry.conditions))
+        .bind(Json(entry.context))
+        .bind(entry.success)
+        .bind(entry.error)
+        .execute(&self.0)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Find the most recent audit log entries of the user with the given ID, across
+    /// all servers. Only entries older than `before` are returned, if given.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_audit_log_for_user(
+        &self,
+        user_id: i32,
+        before: Option<i32>,
+        limit: i64,
+    ) -> DBResult<Vec<AuditLogEntry>> {
+        self.get_audit_log("user_id", user_id, before, limit).await
+    }
+
+    /// Find the most recent audit log entries on the server with the given ID.
+    /// Only entries older than `before` are returned, if given.
+    #[tracing::instrument(skip(self))]
+    pub async fn get_audit_log_for_server(
+        &self,
+        server_id: i32,
+        before: Option<i32>,
+        limit: i64,
+    ) -> DBResult<Vec<AuditLogEntry>> {
+        self.get_audit_log("server_id", server_id, before, limit).await
+    }
+
+    async fn get_audit_log(
+        &self,
+        column_name: &str,
+        id: i32,
+        before: Option<i32>,
+        limit: i64,
+    ) -> DBResult<Vec<AuditLogEntry>> {
+        Ok(sqlx::query_as::<_, AuditLogEntry>(&format!(
+            r#"
+            SELECT *, (EXTRACT(EPOCH FROM created_at) * 1000)::bigint AS timestamp
+            FROM role_audit_log
+            WHERE {} = $1 AND ($2::int IS NULL OR id < $2)
+            ORDER BY id DESC
+            LIMIT $3
+            "#,
+            column_name
+        ))
+        .bind(id)
+        .bind(before)
+        .bind(limit)
+        .fetch_all(&self.0)
+        .await?)
+    }
+}
+
+/// Parse the given rows of `league_accounts`. Accounts in a region that we don't know
+/// (anymore) are left out, instead of failing everything else that was loaded with them.
+fn parse_accounts(rows: Vec<PgRow>) -> DBResult<Vec<LeagueAccount>> {
+    let mut accounts = Vec::with_capacity(rows.len());
+
+    for row in rows {
+        let region: String = row.try_get("region")?;
+        if region.parse::<Region>().is_err() {
+            warn!("Skipping account {} in unknown region {:?}", row.try_get::<i32, _>("id")?, region);
+            continue;
+        }
+
+        accounts.push(LeagueAccount::from_row(&row)?);
+    }
+
+    Ok(accounts)
 }
 
 /// Query builder that allows multiple queries to be built and then executed at
@@ -1,7 +1,13 @@
 use riven::consts::PlatformRoute;
+use serde::{Deserialize, Serialize};
 use sqlx::{postgres::PgRow, types::Json, Row};
 
-use crate::role_model::RoleCombinator;
+use crate::{
+    jobs::JobPhases,
+    region::Region,
+    role_model::{LadderMetric, RoleCombinator},
+    updater::Fetch,
+};
 
 #[derive(sqlx::FromRow, Debug)]
 pub struct User {
@@ -11,9 +17,12 @@ pub struct User {
     pub last_score_update_timestamp: i64,
     pub last_rank_update_timestamp: i64,
     pub last_account_update_timestamp: i64,
-    pub treat_as_unranked: bool,
     pub ignore: bool,
     pub has_accounts: bool,
+    /// When the user last used an Orianna command, in milliseconds since the epoch.
+    pub last_active_timestamp: Option<i64>,
+    /// How much earlier than usual (in milliseconds) the worker loops pick up the user.
+    pub update_boost: i64,
 }
 
 #[derive(sqlx::FromRow, Debug)]
@@ -33,6 +42,58 @@ pub struct UserRank {
     pub tier: String,
 }
 
+/// A rule that hides some of the ranks of a user, so that they are treated as
+/// unranked there. A rule without an account applies to every account of the
+/// user, and a rule without a queue applies to every queue.
+#[derive(sqlx::FromRow, Debug)]
+pub struct HiddenRank {
+    pub id: i32,
+    pub user_id: i32,
+    pub account_id: Option<i32>,
+    pub queue: Option<String>,
+}
+
+impl HiddenRank {
+    /// Whether this rule hides the rank in the given queue. If no account is
+    /// given, this only matches rules that apply to every account.
+    pub fn hides(&self, account_id: Option<i32>, queue: &str) -> bool {
+        let account_matches = match self.account_id {
+            None => true,
+            Some(x) => account_id == Some(x),
+        };
+
+        account_matches && self.queue.as_ref().is_none_or(|x| x == queue)
+    }
+
+    /// Whether this rule hides every rank of the user.
+    pub fn hides_everything(&self) -> bool {
+        self.account_id.is_none() && self.queue.is_none()
+    }
+}
+
+/// The ranked tier of a single account in a single queue. Unlike `UserRank`,
+/// which only stores the best tier across all accounts of the user, this is
+/// used for conditions that are restricted to a set of regions.
+#[derive(sqlx::FromRow, Debug)]
+pub struct AccountRank {
+    pub id: i32,
+    pub account_id: i32,
+    pub user_id: i32,
+    pub queue: String,
+    pub tier: String,
+}
+
+/// The mastery of a single account on a single champion. See `AccountRank`.
+#[derive(sqlx::FromRow, Debug)]
+pub struct AccountChampionStat {
+    pub id: i32,
+    pub account_id: i32,
+    pub user_id: i32,
+    pub champion_id: i32,
+    pub level: i32,
+    pub score: i32,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 pub struct UserMasteryDelta {
     pub id: i32,
@@ -50,6 +111,44 @@ pub struct Server {
     pub name: String,
     pub announcement_channel: Option<String>,
     pub nickname_pattern: String,
+    #[sqlx(try_from = "String")]
+    pub primary_account_fallback: PrimaryAccountFallback,
+    pub main_region: Option<String>,
+}
+
+impl Server {
+    /// The main region of this server, if one is configured.
+    pub fn main_region(&self) -> Option<Region> {
+        self.main_region.as_deref().and_then(|x| x.parse().ok())
+    }
+}
+
+/// How to pick the account of a user that nicknames are based on, if they did not
+/// mark any of their accounts as primary.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PrimaryAccountFallback {
+    /// Treat the user as having no primary account.
+    None,
+    /// The account with the highest solo queue rank.
+    HighestRank,
+    /// The account with the most total mastery points.
+    MostMastery,
+    /// The account that most recently played any champion.
+    RecentlyPlayed,
+    /// Any account in the main region of the server.
+    MainRegion,
+}
+
+impl From<String> for PrimaryAccountFallback {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "highest_rank" => PrimaryAccountFallback::HighestRank,
+            "most_mastery" => PrimaryAccountFallback::MostMastery,
+            "recently_played" => PrimaryAccountFallback::RecentlyPlayed,
+            "main_region" => PrimaryAccountFallback::MainRegion,
+            _ => PrimaryAccountFallback::None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -57,6 +156,10 @@ pub struct ServerAndUserPresence {
     pub server: Server,
     pub roles: Json<Vec<String>>,
     pub nickname: Option<String>,
+    pub exempt_roles: bool,
+    pub exempt_nickname: bool,
+    /// Roles that a moderator manually changed on this member, which we should leave alone.
+    pub overridden_roles: Json<Vec<String>>,
 }
 
 impl<'r> sqlx::FromRow<'r, PgRow> for ServerAndUserPresence {
@@ -68,10 +171,86 @@ impl<'r> sqlx::FromRow<'r, PgRow> for ServerAndUserPresence {
             server: <Server as sqlx::FromRow<'r, PgRow>>::from_row(row)?,
             roles: row.try_get("roles")?,
             nickname: row.try_get("nickname")?,
+            exempt_roles: row.try_get("exempt_roles")?,
+            exempt_nickname: row.try_get("exempt_nickname")?,
+            overridden_roles: row.try_get("overridden_roles")?,
         })
     }
 }
 
+/// Exempts a single user from Orianna's changes on a single server. Roles and
+/// nicknames can be exempted separately, e.g. for a staff account that should
+/// keep its nickname but still receive roles.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct ServerExemption {
+    pub server_id: i32,
+    pub user_id: i32,
+    pub roles: bool,
+    pub nickname: bool,
+}
+
+/// A role that a moderator manually gave to or took from a member, outside of
+/// Orianna. Recorded by dissonance, and respected until it is cleared.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct MemberRoleOverride {
+    pub server_id: i32,
+    pub user_id: i32,
+    pub role_snowflake: String,
+    /// Whether the role was given (`true`) or taken away (`false`).
+    pub present: bool,
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// Problems with the configuration of a server that prevent us from making some
+/// or all of the changes. Stored on the server, so that admins can be told.
+#[derive(Serialize, Deserialize, PartialEq, Default, Debug)]
+pub struct PermissionIssues {
+    pub missing_manage_roles: bool,
+    /// Only set if the server has a nickname pattern.
+    pub missing_manage_nicknames: bool,
+    /// Configured roles that are managed by an integration or that are not below
+    /// the highest role of the bot.
+    pub unassignable_roles: Vec<String>,
+}
+
+/// A single role or nickname change that Orianna made (or attempted to make) to
+/// a member of a server, together with the evaluation results that caused it.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct AuditLogEntry {
+    pub id: i32,
+    pub server_id: i32,
+    pub user_id: i32,
+    pub action: String,
+    pub role_id: Option<i32>,
+    pub role_snowflake: Option<String>,
+    pub nickname_from: Option<String>,
+    pub nickname_to: Option<String>,
+    pub conditions: Json<serde_json::Value>,
+    pub context: Json<serde_json::Value>,
+    pub success: bool,
+    /// The error returned by Discord, if the change failed.
+    pub error: Option<String>,
+    /// Milliseconds since the epoch.
+    pub timestamp: i64,
+}
+
+/// An `AuditLogEntry` that has not been stored yet.
+#[derive(Debug)]
+pub struct NewAuditLogEntry {
+    pub server_id: i32,
+    pub user_id: i32,
+    pub action: &'static str,
+    pub role_id: Option<i32>,
+    pub role_snowflake: Option<String>,
+    pub nickname_from: Option<String>,
+    pub nickname_to: Option<String>,
+    pub conditions: serde_json::Value,
+    pub context: serde_json::Value,
+    pub success: bool,
+    pub error: Option<String>,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 pub struct Role {
     pub id: i32,
@@ -81,11 +260,31 @@ pub struct Role {
     pub combinator: Json<RoleCombinator>,
 }
 
+/// A ladder of Discord roles based on a single metric. A user receives exactly
+/// the role of the highest step whose threshold they reach, and none of the others.
+#[derive(sqlx::FromRow, Debug)]
+pub struct Ladder {
+    pub id: i32,
+    pub server_id: i32,
+    pub name: String,
+    pub metric: Json<LadderMetric>,
+}
+
+#[derive(sqlx::FromRow, Debug)]
+pub struct LadderStep {
+    pub id: i32,
+    pub ladder_id: i32,
+    pub threshold: i32,
+    pub snowflake: String,
+}
+
 #[derive(sqlx::FromRow, Debug)]
 pub struct LeagueAccount {
     pub id: i32,
     pub user_id: i32,
-    pub region: String,
+    /// Accounts whose region can't be parsed are skipped when they are loaded.
+    #[sqlx(try_from = "String")]
+    pub region: Region,
     pub summoner_id: String,
     pub account_id: String,
     pub puuid: String,
@@ -93,23 +292,46 @@ pub struct LeagueAccount {
     pub riot_id_tagline: Option<String>,
     pub primary: bool,
     pub include_region: bool,
+    /// The last time any champion was played on this account, in milliseconds.
+    pub last_played: Option<i64>,
 }
 
 impl LeagueAccount {
-    /// Convert the string representation of this account's region to a PlatformRoute.
-    pub fn route(&self) -> Option<PlatformRoute> {
-        let region = self.region.to_uppercase();
-        let region = match region.as_str() {
-            "SEA" => "SG2", // new name for SEA
-            "PH" => "SG2",  // merged into SG which got renamed to SEA
-            "SG" => "SG2",  // merged into SG which got renamed to SEA
-            "TH" => "SG2",  // merged into SG which got renamed to SEA
-            "TW" => "TW2",
-            "VN" => "VN2",
-            "ME" => "ME1",
-            x => x,
-        };
-
-        region.parse().ok()
+    /// The Riot API platform that this account lives on.
+    pub fn route(&self) -> PlatformRoute {
+        self.region.route()
     }
 }
+
+/// A job in the update queue that was claimed by this instance.
+#[derive(sqlx::FromRow, Debug)]
+pub struct UpdateJob {
+    pub id: i64,
+    pub user_id: i32,
+    pub server_id: Option<i32>,
+    #[sqlx(try_from = "String")]
+    pub kind: Fetch,
+    pub priority: i32,
+    /// Including the current attempt.
+    pub attempts: i32,
+}
+
+/// The status of a job in the update queue, as reported through the API.
+/// Timestamps are in milliseconds since the epoch.
+#[derive(sqlx::FromRow, Serialize, Debug)]
+pub struct JobStatus {
+    pub id: i64,
+    pub user_id: i32,
+    pub server_id: Option<i32>,
+    pub kind: String,
+    pub priority: i32,
+    /// One of `pending`, `running`, `done` or `dead`.
+    pub state: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub phases: Json<JobPhases>,
+    /// The changes that were applied, for finished jobs that update a single server.
+    pub plan: Option<Json<serde_json::Value>>,
+    pub created_at: i64,
+    pub finished_at: Option<i64>,
+}
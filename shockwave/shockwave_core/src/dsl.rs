@@ -0,0 +1,838 @@
+//! A small expression language for role conditions, so that roles can be
+//! configured as text instead of as nested JSON options. For example:
+//!
+//! ```text
+//! mastery(orianna).level >= 7 && rank(RANKED_SOLO_5x5) > GOLD || region in [EUW, EUNE]
+//! ```
+//!
+//! Expressions compile into the regular role model: the outermost operator
+//! becomes the combinator of the role and every operand becomes one of its
+//! conditions, where nested expressions turn into group conditions. `print`
+//! performs the opposite conversion for roles that already exist.
+
+use std::{fmt, ops::Range};
+
+use riven::consts::Champion;
+use serde::Serialize;
+
+use crate::{
+    evaluate::TIERS,
+    region::{RegionFilter, RegionSet},
+    role_model::{
+        GroupCondition, MasteryLevelCondition, MasteryScoreCondition, RangeCondition, RankedTierCompare,
+        RankedTierCondition, RankedTierQueue, RoleCombinator, RoleCondition, RoleConditionWithId, ServerCondition,
+        TotalMasteryLevelCondition, TotalMasteryScoreCondition,
+    },
+};
+
+/// The ranked queues that can be named in a `rank(...)` condition.
+const QUEUES: [&str; 5] =
+    ["RANKED_SOLO_5x5", "RANKED_FLEX_SR", "RANKED_TFT", "RANKED_TFT_TURBO", "RANKED_TFT_DOUBLE_UP"];
+
+/// An error in a DSL expression. The span is a byte range into the source.
+#[derive(Debug, Serialize)]
+pub struct DslError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl DslError {
+    fn new(message: impl Into<String>, span: Range<usize>) -> DslError {
+        DslError { message: message.into(), span }
+    }
+
+    /// Render this error together with the offending line of the source,
+    /// underlining the part of the line that the error applies to.
+    pub fn render(&self, source: &str) -> String {
+        let line_start = source[..self.span.start].rfind('\n').map_or(0, |x| x + 1);
+        let line_end = source[self.span.start..].find('\n').map_or(source.len(), |x| self.span.start + x);
+        let line_number = source[..line_start].matches('\n').count() + 1;
+
+        let column = source[line_start..self.span.start].chars().count();
+        let width = source[self.span.start..self.span.end.min(line_end)].chars().count().max(1);
+
+        format!(
+            "error: {}\n{} | {}\n{} | {}{}",
+            self.message,
+            line_number,
+            &source[line_start..line_end],
+            " ".repeat(line_number.to_string().len()),
+            " ".repeat(column),
+            "^".repeat(width)
+        )
+    }
+}
+
+impl fmt::Display for DslError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}..{}", self.message, self.span.start, self.span.end)
+    }
+}
+
+impl std::error::Error for DslError {}
+
+type DslResult<T> = Result<T, DslError>;
+
+/// A role as described by a DSL expression, in the same shape that roles and
+/// their conditions are stored in.
+#[derive(Serialize, Debug)]
+pub struct CompiledRole {
+    pub combinator: RoleCombinator,
+    pub conditions: Vec<CompiledCondition>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CompiledCondition {
+    pub weight: i32,
+    #[serde(flatten)]
+    pub condition: RoleCondition,
+}
+
+/// Parse and compile the given expression into a combinator and a set of conditions.
+pub fn compile(source: &str) -> DslResult<CompiledRole> {
+    match Parser::new(source)?.parse()? {
+        Expr::Condition(condition) => Ok(CompiledRole {
+            combinator: RoleCombinator::All,
+            conditions: vec![CompiledCondition { weight: 1, condition }],
+        }),
+        Expr::Group { combinator, children, .. } => Ok(CompiledRole {
+            combinator,
+            conditions: children
+                .into_iter()
+                .map(|(weight, expr)| Ok(CompiledCondition { weight, condition: expr.into_condition()? }))
+                .collect::<DslResult<_>>()?,
+        }),
+    }
+}
+
+/// Turn the given combinator and conditions of a role back into an expression.
+/// Compiling the result yields the same role again. Returns an empty string for
+/// roles without any conditions.
+pub fn print(combinator: &RoleCombinator, conditions: &[RoleConditionWithId]) -> String {
+    if conditions.is_empty() {
+        return String::new();
+    }
+
+    print_group(combinator, conditions.iter().map(|x| (x.weight, &x.condition)).collect()).0
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i32),
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Dot,
+    DotDot,
+    Star,
+    And,
+    Or,
+    Ge,
+    Le,
+    Gt,
+    Lt,
+    Eq,
+    Eof,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::Ident(x) => write!(f, "`{}`", x),
+            Token::Number(x) => write!(f, "`{}`", x),
+            Token::LParen => f.write_str("`(`"),
+            Token::RParen => f.write_str("`)`"),
+            Token::LBracket => f.write_str("`[`"),
+            Token::RBracket => f.write_str("`]`"),
+            Token::Comma => f.write_str("`,`"),
+            Token::Dot => f.write_str("`.`"),
+            Token::DotDot => f.write_str("`..`"),
+            Token::Star => f.write_str("`*`"),
+            Token::And => f.write_str("`&&`"),
+            Token::Or => f.write_str("`||`"),
+            Token::Ge => f.write_str("`>=`"),
+            Token::Le => f.write_str("`<=`"),
+            Token::Gt => f.write_str("`>`"),
+            Token::Lt => f.write_str("`<`"),
+            Token::Eq => f.write_str("`==`"),
+            Token::Eof => f.write_str("end of input"),
+        }
+    }
+}
+
+/// Split the source into tokens, together with their byte ranges.
+fn tokenize(source: &str) -> DslResult<Vec<(Token, Range<usize>)>> {
+    let mut tokens = vec![];
+    let mut chars = source.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue;
+        }
+
+        // Identifiers may contain apostrophes so that champions such as Kai'Sa can be written as-is.
+        if c.is_alphabetic() || c == '_' {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_alphanumeric() || c == '_' || c == '\'') {
+                    break;
+                }
+                end = i + c.len_utf8();
+                chars.next();
+            }
+
+            tokens.push((Token::Ident(source[start..end].to_string()), start..end));
+            continue;
+        }
+
+        // Numbers may use underscores as separators and a `k` or `m` suffix, such as `1_500_000` or `1500k`.
+        if c.is_ascii_digit() {
+            let mut end = start;
+            while let Some(&(i, c)) = chars.peek() {
+                if !(c.is_ascii_digit() || c == '_') {
+                    break;
+                }
+                end = i + 1;
+                chars.next();
+            }
+
+            let mut multiplier = 1;
+            if let Some(&(i, c @ ('k' | 'K' | 'm' | 'M'))) = chars.peek() {
+                let followed_by_ident = source[i + 1..].starts_with(|x: char| x.is_alphanumeric() || x == '_');
+                if !followed_by_ident {
+                    multiplier = if c.eq_ignore_ascii_case(&'k') { 1_000 } else { 1_000_000 };
+                    end = i + 1;
+                    chars.next();
+                }
+            }
+
+            let value = source[start..end]
+                .trim_end_matches(['k', 'K', 'm', 'M'])
+                .replace('_', "")
+                .parse::<i32>()
+                .ok()
+                .and_then(|x| x.checked_mul(multiplier))
+                .ok_or_else(|| DslError::new("number is too large", start..end))?;
+
+            tokens.push((Token::Number(value), start..end));
+            continue;
+        }
+
+        chars.next();
+        let next = chars.peek().map(|x| x.1);
+        let (token, len) = match (c, next) {
+            ('&', Some('&')) => (Token::And, 2),
+            ('|', Some('|')) => (Token::Or, 2),
+            ('>', Some('=')) => (Token::Ge, 2),
+            ('<', Some('=')) => (Token::Le, 2),
+            ('=', Some('=')) => (Token::Eq, 2),
+            ('.', Some('.')) => (Token::DotDot, 2),
+            ('>', _) => (Token::Gt, 1),
+            ('<', _) => (Token::Lt, 1),
+            ('(', _) => (Token::LParen, 1),
+            (')', _) => (Token::RParen, 1),
+            ('[', _) => (Token::LBracket, 1),
+            (']', _) => (Token::RBracket, 1),
+            (',', _) => (Token::Comma, 1),
+            ('.', _) => (Token::Dot, 1),
+            ('*', _) => (Token::Star, 1),
+            ('&', _) => return Err(DslError::new("unexpected `&`, did you mean `&&`?", start..start + 1)),
+            ('|', _) => return Err(DslError::new("unexpected `|`, did you mean `||`?", start..start + 1)),
+            ('=', _) => return Err(DslError::new("unexpected `=`, did you mean `==`?", start..start + 1)),
+            _ => return Err(DslError::new(format!("unexpected character `{}`", c), start..start + c.len_utf8())),
+        };
+
+        if len == 2 {
+            chars.next();
+        }
+
+        tokens.push((token, start..start + len));
+    }
+
+    tokens.push((Token::Eof, source.len()..source.len()));
+    Ok(tokens)
+}
+
+/// A parsed expression. Groups keep the weight of every operand, which is
+/// always one unless the group was written using `weighted(...)`.
+enum Expr {
+    Condition(RoleCondition),
+    Group { combinator: RoleCombinator, children: Vec<(i32, Expr)>, span: Range<usize> },
+}
+
+impl Expr {
+    /// Convert this expression into a single (possibly nested) condition.
+    fn into_condition(self) -> DslResult<RoleCondition> {
+        match self {
+            Expr::Condition(x) => Ok(x),
+            Expr::Group { combinator, children, span } => {
+                if children.iter().any(|x| x.0 != 1) {
+                    return Err(DslError::new("weights are only supported in the outermost expression", span));
+                }
+
+                Ok(RoleCondition::Group(GroupCondition {
+                    combinator,
+                    conditions: children.into_iter().map(|x| x.1.into_condition()).collect::<DslResult<_>>()?,
+                }))
+            },
+        }
+    }
+}
+
+struct Parser {
+    tokens: Vec<(Token, Range<usize>)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn new(source: &str) -> DslResult<Parser> {
+        Ok(Parser { tokens: tokenize(source)?, pos: 0 })
+    }
+
+    /// The current token. Reading past the end keeps returning `Token::Eof`.
+    fn current(&self) -> &(Token, Range<usize>) {
+        &self.tokens[self.pos.min(self.tokens.len() - 1)]
+    }
+
+    fn peek(&self) -> &Token {
+        &self.current().0
+    }
+
+    fn span(&self) -> Range<usize> {
+        self.current().1.clone()
+    }
+
+    /// The end of the last consumed token.
+    fn last_end(&self) -> usize {
+        self.pos.checked_sub(1).map_or(0, |x| self.tokens[x.min(self.tokens.len() - 1)].1.end)
+    }
+
+    fn next(&mut self) -> (Token, Range<usize>) {
+        let token = self.current().clone();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.peek() == token {
+            self.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, token: Token) -> DslResult<()> {
+        if self.eat(&token) {
+            Ok(())
+        } else {
+            self.unexpected(&token.to_string())
+        }
+    }
+
+    fn unexpected<T>(&self, expected: &str) -> DslResult<T> {
+        Err(DslError::new(format!("expected {}, found {}", expected, self.peek()), self.span()))
+    }
+
+    fn ident(&mut self, expected: &str) -> DslResult<(String, Range<usize>)> {
+        match self.next() {
+            (Token::Ident(x), span) => Ok((x, span)),
+            _ => {
+                self.pos -= 1;
+                self.unexpected(expected)
+            },
+        }
+    }
+
+    fn number(&mut self) -> DslResult<i32> {
+        match self.peek() {
+            &Token::Number(x) => {
+                self.next();
+                Ok(x)
+            },
+            _ => self.unexpected("a number"),
+        }
+    }
+
+    fn parse(mut self) -> DslResult<Expr> {
+        if self.peek() == &Token::Eof {
+            return self.unexpected("a condition");
+        }
+
+        let expr = self.parse_or()?;
+        if self.peek() != &Token::Eof {
+            return self.unexpected("`&&`, `||` or end of input");
+        }
+
+        Ok(expr)
+    }
+
+    fn parse_or(&mut self) -> DslResult<Expr> {
+        self.parse_operator(Token::Or, RoleCombinator::Any, Parser::parse_and)
+    }
+
+    fn parse_and(&mut self) -> DslResult<Expr> {
+        self.parse_operator(Token::And, RoleCombinator::All, Parser::parse_atom)
+    }
+
+    /// Parse one or more operands separated by the given operator. Operands that
+    /// are themselves groups of the same operator are flattened into this one.
+    fn parse_operator(
+        &mut self,
+        operator: Token,
+        combinator: RoleCombinator,
+        operand: fn(&mut Parser) -> DslResult<Expr>,
+    ) -> DslResult<Expr> {
+        let start = self.span().start;
+        let first = operand(self)?;
+        if self.peek() != &operator {
+            return Ok(first);
+        }
+
+        let mut children = vec![];
+        let mut push = |expr: Expr| match expr {
+            Expr::Group { combinator: c, children: nested, .. }
+                if std::mem::discriminant(&c) == std::mem::discriminant(&combinator)
+                    && nested.iter().all(|x| x.0 == 1) =>
+            {
+                children.extend(nested)
+            },
+            expr => children.push((1, expr)),
+        };
+
+        push(first);
+        while self.eat(&operator) {
+            push(operand(self)?);
+        }
+
+        Ok(Expr::Group { combinator, children, span: start..self.last_end() })
+    }
+
+    fn parse_atom(&mut self) -> DslResult<Expr> {
+        if self.eat(&Token::LParen) {
+            let expr = self.parse_or()?;
+            self.expect(Token::RParen)?;
+            return Ok(expr);
+        }
+
+        let (name, span) = self.ident("a condition")?;
+        match name.to_lowercase().as_str() {
+            "all" | "any" => {
+                let combinator =
+                    if name.eq_ignore_ascii_case("all") { RoleCombinator::All } else { RoleCombinator::Any };
+                self.expect(Token::LParen)?;
+                let children = self.parse_list(|p| Ok((1, p.parse_or()?)))?;
+                Ok(Expr::Group { combinator, children, span: span.start..self.last_end() })
+            },
+            "at_least" => {
+                self.expect(Token::LParen)?;
+                let amount = self.number()?;
+                self.expect(Token::Comma)?;
+                let children = self.parse_list(|p| Ok((1, p.parse_or()?)))?;
+                Ok(Expr::Group {
+                    combinator: RoleCombinator::AtLeast { amount },
+                    children,
+                    span: span.start..self.last_end(),
+                })
+            },
+            "weighted" => {
+                self.expect(Token::LParen)?;
+                let threshold = self.number()?;
+                self.expect(Token::Comma)?;
+                let children = self.parse_list(|p| {
+                    let weight = p.number()?;
+                    p.expect(Token::Star)?;
+                    Ok((weight, p.parse_or()?))
+                })?;
+                Ok(Expr::Group {
+                    combinator: RoleCombinator::Weighted { threshold },
+                    children,
+                    span: span.start..self.last_end(),
+                })
+            },
+            "mastery" => {
+                self.expect(Token::LParen)?;
+                let champion = self.parse_champion()?;
+                let region = self.parse_optional_regions()?;
+                self.expect(Token::RParen)?;
+                self.expect(Token::Dot)?;
+
+                let (stat, stat_span) = self.ident("`level` or `score`")?;
+                let range = self.parse_range()?;
+                Ok(Expr::Condition(match stat.to_lowercase().as_str() {
+                    "level" => RoleCondition::MasteryLevel(MasteryLevelCondition { range, region, champion }),
+                    "score" => RoleCondition::MasteryScore(MasteryScoreCondition { range, region, champion }),
+                    _ => {
+                        return Err(DslError::new(format!("expected `level` or `score`, found `{}`", stat), stat_span))
+                    },
+                }))
+            },
+            "total_mastery" => {
+                let region = if self.eat(&Token::LParen) {
+                    let region = self.parse_regions()?;
+                    self.expect(Token::RParen)?;
+                    Some(region)
+                } else {
+                    None
+                };
+                self.expect(Token::Dot)?;
+
+                let (stat, stat_span) = self.ident("`level` or `score`")?;
+                let range = self.parse_range()?;
+                Ok(Expr::Condition(match stat.to_lowercase().as_str() {
+                    "level" => RoleCondition::TotalMasteryLevel(TotalMasteryLevelCondition { range, region }),
+                    "score" => RoleCondition::TotalMasteryScore(TotalMasteryScoreCondition { range, region }),
+                    _ => {
+                        return Err(DslError::new(format!("expected `level` or `score`, found `{}`", stat), stat_span))
+                    },
+                }))
+            },
+            "rank" => {
+                self.expect(Token::LParen)?;
+                let queue = self.parse_queue()?;
+                let region = self.parse_optional_regions()?;
+                self.expect(Token::RParen)?;
+                let compare = self.parse_tier_compare()?;
+
+                Ok(Expr::Condition(RoleCondition::RankedTier(RankedTierCondition { compare, queue, region })))
+            },
+            "region" => {
+                let region = match self.next() {
+                    (Token::Ident(x), _) if x.eq_ignore_ascii_case("in") => self.parse_regions()?,
+                    (Token::Eq, _) => RegionSet(vec![self.parse_region()?]),
+                    _ => {
+                        self.pos -= 1;
+                        return self.unexpected("`in` or `==`");
+                    },
+                };
+
+                Ok(Expr::Condition(RoleCondition::Server(ServerCondition { region })))
+            },
+            _ => Err(DslError::new(
+                format!(
+                    "unknown condition `{}`, expected one of `mastery`, `total_mastery`, `rank`, `region`, `all`, \
+                     `any`, `at_least` or `weighted`",
+                    name
+                ),
+                span,
+            )),
+        }
+    }
+
+    /// Parse a comma-separated list of items up to and including the closing parenthesis.
+    fn parse_list<T>(&mut self, mut item: impl FnMut(&mut Parser) -> DslResult<T>) -> DslResult<Vec<T>> {
+        let mut items = vec![];
+        while !self.eat(&Token::RParen) {
+            if !items.is_empty() {
+                self.expect(Token::Comma)?;
+            }
+            items.push(item(self)?);
+        }
+        Ok(items)
+    }
+
+    fn parse_champion(&mut self) -> DslResult<i32> {
+        if let &Token::Number(x) = self.peek() {
+            self.next();
+            return Ok(x);
+        }
+
+        let (name, span) = self.ident("a champion")?;
+        let normalized = normalize_champion_name(&name);
+
+        Champion::ALL_KNOWN
+            .iter()
+            .find(|x| {
+                x.name().is_some_and(|x| normalize_champion_name(x) == normalized)
+                    || x.identifier().is_some_and(|x| normalize_champion_name(x) == normalized)
+            })
+            .map(|x| x.0 as i32)
+            .ok_or_else(|| DslError::new(format!("unknown champion `{}`", name), span))
+    }
+
+    fn parse_queue(&mut self) -> DslResult<RankedTierQueue> {
+        let (name, span) = self.ident("a queue")?;
+
+        Ok(match name.to_uppercase().as_str() {
+            "ANY" => RankedTierQueue::Any,
+            "HIGHEST" => RankedTierQueue::HighestExcludingTFT,
+            "HIGHEST_TFT" => RankedTierQueue::HighestIncludingTFT,
+            _ => match QUEUES.iter().find(|x| x.eq_ignore_ascii_case(&name)) {
+                Some(x) => RankedTierQueue::NamedQueue(x.to_string()),
+                None => {
+                    return Err(DslError::new(
+                        format!(
+                            "unknown queue `{}`, expected `ANY`, `HIGHEST`, `HIGHEST_TFT` or one of {}",
+                            name,
+                            QUEUES.join(", ")
+                        ),
+                        span,
+                    ))
+                },
+            },
+        })
+    }
+
+    fn parse_region(&mut self) -> DslResult<RegionFilter> {
+        let (name, span) = self.ident("a region")?;
+
+        name.parse().map_err(|_| DslError::new(format!("unknown region `{}`", name), span))
+    }
+
+    /// Parse either a single region or a bracketed list of regions.
+    fn parse_regions(&mut self) -> DslResult<RegionSet> {
+        if !self.eat(&Token::LBracket) {
+            return Ok(RegionSet(vec![self.parse_region()?]));
+        }
+
+        let mut regions = vec![self.parse_region()?];
+        while self.eat(&Token::Comma) {
+            regions.push(self.parse_region()?);
+        }
+        self.expect(Token::RBracket)?;
+
+        Ok(RegionSet(regions))
+    }
+
+    /// Parse the optional `, regions` argument of `mastery(...)` and `rank(...)`.
+    fn parse_optional_regions(&mut self) -> DslResult<Option<RegionSet>> {
+        if self.eat(&Token::Comma) {
+            Ok(Some(self.parse_regions()?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn parse_range(&mut self) -> DslResult<RangeCondition> {
+        let (token, span) = self.next();
+
+        Ok(match token {
+            Token::Ge => RangeCondition::AtLeast { value: self.number()? },
+            Token::Le => RangeCondition::AtMost { value: self.number()? },
+            Token::Eq => RangeCondition::Exactly { value: self.number()? },
+            Token::Gt => RangeCondition::AtLeast { value: self.number()?.saturating_add(1) },
+            Token::Lt => RangeCondition::AtMost { value: self.number()?.saturating_sub(1) },
+            Token::Ident(x) if x.eq_ignore_ascii_case("in") => {
+                let min = self.number()?;
+                self.expect(Token::DotDot)?;
+                let max = self.number()?;
+                RangeCondition::Between { min, max }
+            },
+            _ => {
+                self.pos -= 1;
+                return Err(DslError::new(
+                    format!("expected a comparison such as `>= 7` or `in 5..7`, found {}", token),
+                    span,
+                ));
+            },
+        })
+    }
+
+    fn parse_tier_compare(&mut self) -> DslResult<RankedTierCompare> {
+        let (token, span) = self.next();
+        if !matches!(token, Token::Gt | Token::Lt | Token::Eq | Token::Ge | Token::Le) {
+            self.pos -= 1;
+            return Err(DslError::new(format!("expected a comparison such as `> GOLD`, found {}", token), span));
+        }
+
+        let (name, tier_span) = self.ident("a tier")?;
+        let tier = name.to_uppercase();
+        let Some(idx) = TIERS.iter().position(|x| *x == tier) else {
+            return Err(DslError::new(format!("unknown tier `{}`", name), tier_span));
+        };
+
+        // There are no inclusive comparisons in the role model, so `>= GOLD` is
+        // stored as `> SILVER` and `<= GOLD` as `< PLATINUM`.
+        Ok(match token {
+            Token::Gt => RankedTierCompare::Higher(tier),
+            Token::Lt => RankedTierCompare::Lower(tier),
+            Token::Eq => RankedTierCompare::Equal(tier),
+            Token::Ge if idx == 0 => {
+                return Err(DslError::new("unranked users only match `== UNRANKED`", span.start..tier_span.end))
+            },
+            Token::Ge => RankedTierCompare::Higher(TIERS[idx - 1].to_string()),
+            Token::Le if idx == TIERS.len() - 1 => RankedTierCompare::Higher(TIERS[0].to_string()),
+            _ => RankedTierCompare::Lower(TIERS[idx + 1].to_string()),
+        })
+    }
+}
+
+/// Lowercase the given champion name and strip everything but letters and
+/// digits, so that `Cho'Gath`, `chogath` and `ChoGath` are all the same.
+fn normalize_champion_name(name: &str) -> String {
+    name.chars().filter(|x| x.is_alphanumeric()).flat_map(char::to_lowercase).collect()
+}
+
+/// The precedence of a printed expression, used to decide where parentheses are needed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Precedence {
+    Or,
+    And,
+    Atom,
+}
+
+fn print_group(combinator: &RoleCombinator, conditions: Vec<(i32, &RoleCondition)>) -> (String, Precedence) {
+    let print_all = |min: Precedence| {
+        conditions
+            .iter()
+            .map(|(_, x)| match print_condition(x) {
+                (text, precedence) if precedence < min => format!("({})", text),
+                (text, _) => text,
+            })
+            .collect::<Vec<_>>()
+    };
+
+    match *combinator {
+        RoleCombinator::All | RoleCombinator::Any if conditions.len() == 1 => print_condition(conditions[0].1),
+        RoleCombinator::All if conditions.is_empty() => ("all()".to_string(), Precedence::Atom),
+        RoleCombinator::Any if conditions.is_empty() => ("any()".to_string(), Precedence::Atom),
+        RoleCombinator::All => (print_all(Precedence::And).join(" && "), Precedence::And),
+        RoleCombinator::Any => (print_all(Precedence::Or).join(" || "), Precedence::Or),
+        RoleCombinator::AtLeast { amount } => {
+            let mut args = vec![amount.to_string()];
+            args.extend(print_all(Precedence::Or));
+            (format!("at_least({})", args.join(", ")), Precedence::Atom)
+        },
+        RoleCombinator::Weighted { threshold } => {
+            let mut args = vec![threshold.to_string()];
+            args.extend(conditions.iter().map(|(weight, x)| format!("{} * {}", weight, print_condition(x).0)));
+            (format!("weighted({})", args.join(", ")), Precedence::Atom)
+        },
+    }
+}
+
+fn print_condition(condition: &RoleCondition) -> (String, Precedence) {
+    let text = match condition {
+        RoleCondition::MasteryLevel(x) => format!(
+            "mastery({}{}).level {}",
+            print_champion(x.champion),
+            print_optional_regions(&x.region),
+            print_range(&x.range)
+        ),
+        RoleCondition::MasteryScore(x) => format!(
+            "mastery({}{}).score {}",
+            print_champion(x.champion),
+            print_optional_regions(&x.region),
+            print_range(&x.range)
+        ),
+        RoleCondition::TotalMasteryLevel(x) => {
+            format!("total_mastery{}.level {}", print_total_regions(&x.region), print_range(&x.range))
+        },
+        RoleCondition::TotalMasteryScore(x) => {
+            format!("total_mastery{}.score {}", print_total_regions(&x.region), print_range(&x.range))
+        },
+        RoleCondition::RankedTier(x) => {
+            let queue = match &x.queue {
+                RankedTierQueue::Any => "ANY",
+                RankedTierQueue::HighestExcludingTFT => "HIGHEST",
+                RankedTierQueue::HighestIncludingTFT => "HIGHEST_TFT",
+                RankedTierQueue::NamedQueue(x) => x,
+            };
+
+            let compare = match &x.compare {
+                RankedTierCompare::Higher(x) => format!("> {}", x),
+                RankedTierCompare::Lower(x) => format!("< {}", x),
+                RankedTierCompare::Equal(x) => format!("== {}", x),
+            };
+
+            format!("rank({}{}) {}", queue, print_optional_regions(&x.region), compare)
+        },
+        RoleCondition::Server(x) => format!("region in {}", print_regions(&x.region)),
+        RoleCondition::Group(x) => return print_group(&x.combinator, x.conditions.iter().map(|x| (1, x)).collect()),
+    };
+
+    (text, Precedence::Atom)
+}
+
+fn print_champion(id: i32) -> String {
+    i16::try_from(id)
+        .ok()
+        .map(Champion)
+        .filter(|x| x.is_known())
+        .and_then(|x| x.name())
+        .map_or_else(|| id.to_string(), normalize_champion_name)
+}
+
+fn print_range(range: &RangeCondition) -> String {
+    match *range {
+        RangeCondition::AtLeast { value } => format!(">= {}", value),
+        RangeCondition::AtMost { value } => format!("<= {}", value),
+        RangeCondition::Exactly { value } => format!("== {}", value),
+        RangeCondition::Between { min, max } => format!("in {}..{}", min, max),
+    }
+}
+
+fn print_regions(regions: &RegionSet) -> String {
+    format!("[{}]", regions.0.iter().map(|x| x.to_string()).collect::<Vec<_>>().join(", "))
+}
+
+fn print_optional_regions(regions: &Option<RegionSet>) -> String {
+    regions.as_ref().map_or_else(String::new, |x| format!(", {}", print_regions(x)))
+}
+
+fn print_total_regions(regions: &Option<RegionSet>) -> String {
+    regions.as_ref().map_or_else(String::new, |x| format!("({})", print_regions(x)))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        dsl::{compile, print},
+        role_model::RoleConditionWithId,
+    };
+
+    /// Compile the given source and print it again.
+    fn roundtrip(source: &str) -> String {
+        let compiled = compile(source).unwrap();
+        let conditions = compiled
+            .conditions
+            .into_iter()
+            .enumerate()
+            .map(|(id, x)| RoleConditionWithId { id: id as i32, role_id: 0, weight: x.weight, condition: x.condition })
+            .collect::<Vec<_>>();
+
+        print(&compiled.combinator, &conditions)
+    }
+
+    #[test]
+    fn compiles_and_prints() {
+        assert_eq!(
+            roundtrip("mastery(Orianna).level >= 7 && rank(ranked_solo_5x5) > gold || region in [EUW, EUNE]"),
+            "mastery(orianna).level >= 7 && rank(RANKED_SOLO_5x5) > GOLD || region in [EUW, EUNE]"
+        );
+        assert_eq!(
+            roundtrip("(region == EUW || region == NA) && total_mastery([ANY_EUROPE]).score >= 1500k"),
+            "(region in [EUW] || region in [NA]) && total_mastery([ANY_EUROPE]).score >= 1500000"
+        );
+        assert_eq!(
+            roundtrip("weighted(5, 3 * mastery(chogath, EUW).score in 1_000..5k, 2 * rank(HIGHEST) >= GOLD)"),
+            "weighted(5, 3 * mastery(chogath, [EUW]).score in 1000..5000, 2 * rank(HIGHEST) > SILVER)"
+        );
+        assert_eq!(
+            roundtrip("at_least(2, region == EUW, region == NA && rank(any) == unranked, region == KR)"),
+            "at_least(2, region in [EUW], region in [NA] && rank(ANY) == UNRANKED, region in [KR])"
+        );
+    }
+
+    #[test]
+    fn reports_spans() {
+        let err = compile("mastery(orianna).level >= 7 && rank(SOLO) > GOLD").unwrap_err();
+        assert_eq!(err.span, 36..40);
+
+        let err = compile("mastery(orianna).level >= 7 &&").unwrap_err();
+        assert_eq!(err.message, "expected a condition, found end of input");
+
+        let err = compile("region in [EUW] && weighted(1, 2 * region == NA)").unwrap_err();
+        assert_eq!(err.span, 19..48);
+        assert_eq!(
+            err.render("region in [EUW] && weighted(1, 2 * region == NA)"),
+            "error: weights are only supported in the outermost expression\n\
+             1 | region in [EUW] && weighted(1, 2 * region == NA)\n  \
+             |                    ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^"
+        );
+    }
+}
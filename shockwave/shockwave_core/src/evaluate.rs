@@ -1,15 +1,21 @@
-use std::ops::Deref;
+use std::collections::HashMap;
+
+use serde_json::json;
 
 use crate::{
-    db_model::{LeagueAccount, Role, User, UserChampionStat, UserRank},
+    db_model::{
+        AccountChampionStat, AccountRank, HiddenRank, Ladder, LadderStep, LeagueAccount, PrimaryAccountFallback, Role,
+        Server, User, UserChampionStat, UserRank,
+    },
+    region::RegionSet,
     role_model::{
-        MasteryLevelCondition, MasteryScoreCondition, RangeCondition, RankedTierCompare, RankedTierCondition,
-        RankedTierQueue, RoleCombinator, RoleCondition, ServerCondition, TotalMasteryLevelCondition,
-        TotalMasteryScoreCondition,
+        GroupCondition, LadderMetric, MasteryLevelCondition, MasteryScoreCondition, RangeCondition, RankedTierCompare,
+        RankedTierCondition, RankedTierQueue, RoleCombinator, RoleCondition, RoleConditionWithId, ServerCondition,
+        TotalMasteryLevelCondition, TotalMasteryScoreCondition,
     },
 };
 
-const TIERS: [&'static str; 11] = [
+pub(crate) const TIERS: [&'static str; 11] = [
     "UNRANKED",
     "IRON",
     "BRONZE",
@@ -40,22 +46,270 @@ pub struct EvaluationContext {
     pub accounts: Vec<LeagueAccount>,
     pub stats: Vec<UserChampionStat>,
     pub ranks: Vec<UserRank>,
+    pub account_stats: Vec<AccountChampionStat>,
+    pub account_ranks: Vec<AccountRank>,
+    pub hidden_ranks: Vec<HiddenRank>,
+}
+
+impl EvaluationContext {
+    /// Returns whether the account with the given ID lives on one of the
+    /// given regions and has not opted out of region-based conditions.
+    fn account_in_regions(&self, account_id: i32, regions: &RegionSet) -> bool {
+        self.accounts.iter().any(|x| x.id == account_id && x.include_region && regions.contains(x.region))
+    }
+
+    /// Returns the `(level, score)` per champion, merged the same way as the
+    /// user-wide statistics (highest level, summed score). If a region filter
+    /// is given, only accounts on those regions are considered.
+    fn champion_stats(&self, regions: Option<&RegionSet>) -> HashMap<i32, (i32, i32)> {
+        let Some(regions) = regions else {
+            return self.stats.iter().map(|x| (x.champion_id, (x.level, x.score))).collect();
+        };
+
+        let mut merged = HashMap::<i32, (i32, i32)>::new();
+        for stat in self.account_stats.iter().filter(|x| self.account_in_regions(x.account_id, regions)) {
+            merged
+                .entry(stat.champion_id)
+                .and_modify(|(level, score)| {
+                    *level = (*level).max(stat.level);
+                    *score += stat.score;
+                })
+                .or_insert((stat.level, stat.score));
+        }
+
+        merged
+    }
+
+    /// Returns the `(queue, tier)` entries of the user that are not hidden by
+    /// any of their rank visibility rules, keeping only the best tier per queue,
+    /// together with whether anything was hidden. If a region filter is given,
+    /// only accounts on those regions are considered.
+    fn visible_ranks(&self, regions: Option<&RegionSet>) -> (Vec<(&str, &str)>, bool) {
+        let mut any_hidden = self.hidden_ranks.iter().any(HiddenRank::hides_everything);
+        let is_hidden = |account_id, queue: &str| self.hidden_ranks.iter().any(|x| x.hides(account_id, queue));
+
+        // The merged ranks suffice if every account counts and there are no rules for specific
+        // accounts. They are also all we have for users that were not fetched since we started
+        // keeping ranks per account, in which case a rule for any account hides the whole queue.
+        let all_accounts =
+            regions.is_none_or(|regions| self.accounts.iter().all(|x| self.account_in_regions(x.id, regions)));
+        let account_rules = self.hidden_ranks.iter().any(|x| x.account_id.is_some());
+
+        if all_accounts && (!account_rules || self.account_ranks.is_empty()) {
+            let hides_queue =
+                |queue: &str| self.hidden_ranks.iter().any(|x| x.queue.as_ref().is_none_or(|x| x == queue));
+            let (hidden, visible): (Vec<_>, Vec<_>) = self.ranks.iter().partition(|x| hides_queue(&x.queue));
+
+            return (
+                visible.into_iter().map(|x| (x.queue.as_str(), x.tier.as_str())).collect(),
+                any_hidden || !hidden.is_empty(),
+            );
+        }
+
+        let mut best = HashMap::<&str, &str>::new();
+        for rank in &self.account_ranks {
+            if regions.is_some_and(|regions| !self.account_in_regions(rank.account_id, regions)) {
+                continue;
+            }
+
+            if is_hidden(Some(rank.account_id), &rank.queue) {
+                any_hidden = true;
+                continue;
+            }
+
+            best.entry(&rank.queue)
+                .and_modify(|tier| {
+                    if tier_to_numeric(&rank.tier) > tier_to_numeric(tier) {
+                        *tier = &rank.tier;
+                    }
+                })
+                .or_insert(&rank.tier);
+        }
+
+        (best.into_iter().collect(), any_hidden)
+    }
+
+    /// Returns the highest visible tier of the user outside of TFT, if they are
+    /// ranked in any queue.
+    pub fn highest_tier(&self) -> Option<&str> {
+        let (ranks, _) = self.visible_ranks(None);
+
+        ranks
+            .into_iter()
+            .filter(|x| x.0 != "RANKED_TFT" && tier_to_numeric(x.1) > 0)
+            .max_by_key(|x| tier_to_numeric(x.1))
+            .map(|x| x.1)
+    }
+
+    /// Returns the primary account of the user. If they did not pick one, the
+    /// fallback configured on the given server is used to choose one instead.
+    pub fn primary_account(&self, server: &Server) -> Option<&LeagueAccount> {
+        if let Some(primary) = self.accounts.iter().find(|x| x.primary) {
+            return Some(primary);
+        }
+
+        let solo_tier = |account: &LeagueAccount| {
+            self.account_ranks
+                .iter()
+                .filter(|x| x.account_id == account.id && x.queue == "RANKED_SOLO_5x5")
+                .filter(|x| !self.hidden_ranks.iter().any(|hidden| hidden.hides(Some(account.id), &x.queue)))
+                .map(|x| tier_to_numeric(&x.tier))
+                .max()
+        };
+        let mastery = |account: &LeagueAccount| {
+            self.account_stats.iter().filter(|x| x.account_id == account.id).map(|x| x.score as i64).sum::<i64>()
+        };
+
+        // Ties are broken in favor of the oldest account.
+        let best_by = |key: &dyn Fn(&LeagueAccount) -> Option<i64>| {
+            self.accounts.iter().filter_map(|x| Some((key(x)?, -x.id, x))).max_by_key(|x| (x.0, x.1)).map(|x| x.2)
+        };
+
+        match server.primary_account_fallback {
+            PrimaryAccountFallback::None => None,
+            PrimaryAccountFallback::HighestRank => best_by(&|x| solo_tier(x).filter(|&tier| tier > 0).map(i64::from)),
+            PrimaryAccountFallback::MostMastery => best_by(&|x| Some(mastery(x)).filter(|&score| score > 0)),
+            PrimaryAccountFallback::RecentlyPlayed => best_by(&|x| x.last_played),
+            PrimaryAccountFallback::MainRegion => {
+                let region = server.main_region()?;
+                best_by(&|x| (x.region == region).then(|| mastery(x)))
+            },
+        }
+    }
+}
+
+impl EvaluationContext {
+    /// Capture the values of this context that conditions are evaluated on, so
+    /// that they can be stored alongside a change. To keep this small, mastery
+    /// is only included for the given champions.
+    pub fn snapshot(&self, champions: &[i32]) -> serde_json::Value {
+        json!({
+            "accounts": self.accounts.iter().map(|x| json!({
+                "id": x.id,
+                "region": x.region.as_str(),
+                "primary": x.primary,
+                "include_region": x.include_region,
+            })).collect::<Vec<_>>(),
+            "ranks": self.ranks.iter().map(|x| (&x.queue, &x.tier)).collect::<HashMap<_, _>>(),
+            "hidden_ranks": self.hidden_ranks.iter().map(|x| (x.account_id, &x.queue)).collect::<Vec<_>>(),
+            "total_mastery_level": self.stats.iter().map(|x| x.level).sum::<i32>(),
+            "total_mastery_score": self.stats.iter().map(|x| x.score).sum::<i32>(),
+            "mastery": self
+                .stats
+                .iter()
+                .filter(|x| champions.contains(&x.champion_id))
+                .map(|x| (x.champion_id, (x.level, x.score)))
+                .collect::<HashMap<_, _>>(),
+        })
+    }
 }
 
 impl Role {
     /// For the given set of role conditions and the given evaluation
     /// context, check if this role applies to the given user, using
     /// the combinator configured for this role.
-    pub fn evaluate<T>(&self, conditions: Vec<&T>, ctx: &EvaluationContext) -> bool
-    where
-        T: Deref<Target = RoleCondition>,
-    {
-        let matching = conditions.iter().filter(|x| x.evaluate(ctx)).count();
-
-        match *self.combinator {
-            RoleCombinator::All => matching == conditions.len(),
+    pub fn evaluate(&self, conditions: &[RoleConditionWithId], ctx: &EvaluationContext) -> bool {
+        self.evaluate_with_score(conditions, ctx).0
+    }
+
+    /// Same as `evaluate`, but also returns the score of the user for this
+    /// role, i.e. the summed weight of all conditions that matched. For roles
+    /// that are not weighted, every condition has a weight of one by default.
+    pub fn evaluate_with_score(&self, conditions: &[RoleConditionWithId], ctx: &EvaluationContext) -> (bool, i32) {
+        let matching = conditions.iter().filter(|x| x.evaluate(ctx)).collect::<Vec<_>>();
+        let score = matching.iter().map(|x| x.weight).sum();
+
+        (self.combinator.applies(matching.len(), conditions.len(), score), score)
+    }
+}
+
+impl RoleCombinator {
+    /// Whether a set of `total` conditions of which `matching` matched, with the
+    /// given summed weight of the matching conditions, satisfies this combinator.
+    pub fn applies(&self, matching: usize, total: usize, score: i32) -> bool {
+        match *self {
+            RoleCombinator::All => matching == total,
             RoleCombinator::Any => matching > 0,
             RoleCombinator::AtLeast { amount } => matching >= amount as usize,
+            RoleCombinator::Weighted { threshold } => score >= threshold,
+        }
+    }
+}
+
+impl Ladder {
+    /// Given the steps of this ladder and the value of the user for the ladder
+    /// metric, find the step whose role the user should have. This is the step
+    /// with the highest threshold that is still reached by the value.
+    pub fn bracket<'a>(&self, steps: &'a [LadderStep], value: Option<i32>) -> Option<&'a LadderStep> {
+        let value = value?;
+
+        steps.iter().filter(|x| x.threshold <= value).max_by_key(|x| x.threshold)
+    }
+
+    /// Whether the given value is within 10% of the threshold of the next step,
+    /// so that a small change could already move the user up a bracket.
+    pub fn is_near_next_step(&self, steps: &[LadderStep], value: Option<i32>) -> bool {
+        let Some(value) = value else {
+            return false;
+        };
+
+        let next = steps.iter().map(|x| x.threshold).filter(|&x| x > value).min();
+        next.is_some_and(|next| (next as i64 - value as i64) * 10 <= next as i64)
+    }
+}
+
+impl LadderMetric {
+    /// Whether this metric needs mastery history, which is not part of the
+    /// evaluation context and needs to be queried separately.
+    pub fn needs_history(&self) -> bool {
+        matches!(self, LadderMetric::MasteryGain { .. })
+    }
+
+    /// Returns the champion that this metric refers to, if any.
+    pub fn champion(&self) -> Option<i32> {
+        match *self {
+            LadderMetric::MasteryLevel { champion, .. } | LadderMetric::MasteryScore { champion, .. } => Some(champion),
+            LadderMetric::MasteryGain { champion, .. } => champion,
+            _ => None,
+        }
+    }
+
+    /// Compute the value of this metric for the given user, or `None` if the
+    /// user has no value at all (e.g. because they are unranked). Always returns
+    /// `None` for metrics that need history.
+    pub fn value(&self, ctx: &EvaluationContext) -> Option<i32> {
+        match self {
+            LadderMetric::MasteryLevel { champion, region } => {
+                Some(ctx.champion_stats(region.as_ref()).get(champion).map_or(0, |x| x.0))
+            },
+            LadderMetric::TotalMasteryLevel { region } => {
+                Some(ctx.champion_stats(region.as_ref()).values().map(|x| x.0).sum())
+            },
+            LadderMetric::MasteryScore { champion, region } => {
+                Some(ctx.champion_stats(region.as_ref()).get(champion).map_or(0, |x| x.1))
+            },
+            LadderMetric::TotalMasteryScore { region } => {
+                Some(ctx.champion_stats(region.as_ref()).values().map(|x| x.1).sum())
+            },
+            LadderMetric::RankedTier { queue, region } => {
+                if ctx.accounts.is_empty() {
+                    return None;
+                }
+
+                let (ranks, _) = ctx.visible_ranks(region.as_ref());
+
+                ranks
+                    .iter()
+                    .filter(|x| match queue {
+                        RankedTierQueue::Any | RankedTierQueue::HighestIncludingTFT => true,
+                        RankedTierQueue::HighestExcludingTFT => x.0 != "RANKED_TFT",
+                        RankedTierQueue::NamedQueue(name) => x.0 == name,
+                    })
+                    .map(|x| tier_to_numeric(x.1))
+                    .filter(|&x| x > 0)
+                    .max()
+            },
+            LadderMetric::MasteryGain { .. } => None,
         }
     }
 }
@@ -83,7 +337,7 @@ impl RankedTierCompare {
             None => return false,
         };
 
-        let want_idx = match tier_to_numeric(&match self {
+        let want_idx = match tier_to_numeric(match self {
             RankedTierCompare::Higher(val) => val,
             RankedTierCompare::Lower(val) => val,
             RankedTierCompare::Equal(val) => val,
@@ -111,6 +365,7 @@ impl RoleCondition {
     pub fn needs_accounts(&self) -> bool {
         match self {
             RoleCondition::Server(_) => true,
+            RoleCondition::Group(x) => x.conditions.iter().any(RoleCondition::needs_accounts),
             _ => false,
         }
     }
@@ -120,6 +375,7 @@ impl RoleCondition {
     pub fn needs_ranked_tiers(&self) -> bool {
         match self {
             RoleCondition::RankedTier(_) => true,
+            RoleCondition::Group(x) => x.conditions.iter().any(RoleCondition::needs_ranked_tiers),
             _ => false,
         }
     }
@@ -132,10 +388,21 @@ impl RoleCondition {
             RoleCondition::TotalMasteryLevel(_) => true,
             RoleCondition::MasteryScore(_) => true,
             RoleCondition::TotalMasteryScore(_) => true,
+            RoleCondition::Group(x) => x.conditions.iter().any(RoleCondition::needs_mastery),
             _ => false,
         }
     }
 
+    /// Returns the champions that this role condition refers to, if any.
+    pub fn champions(&self) -> Vec<i32> {
+        match self {
+            RoleCondition::MasteryLevel(x) => vec![x.champion],
+            RoleCondition::MasteryScore(x) => vec![x.champion],
+            RoleCondition::Group(x) => x.conditions.iter().flat_map(RoleCondition::champions).collect(),
+            _ => vec![],
+        }
+    }
+
     /// Given the specified evaluation context, evaluate whether
     /// the current condition applies to the user.
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
@@ -146,13 +413,14 @@ impl RoleCondition {
             RoleCondition::TotalMasteryScore(x) => x.evaluate(ctx),
             RoleCondition::RankedTier(x) => x.evaluate(ctx),
             RoleCondition::Server(x) => x.evaluate(ctx),
+            RoleCondition::Group(x) => x.evaluate(ctx),
         }
     }
 }
 
 impl MasteryLevelCondition {
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
-        let level = ctx.stats.iter().find(|&x| x.champion_id == self.champion).map_or(0, |x| x.level);
+        let level = ctx.champion_stats(self.region.as_ref()).get(&self.champion).map_or(0, |x| x.0);
 
         self.range.evaluate(level)
     }
@@ -160,7 +428,7 @@ impl MasteryLevelCondition {
 
 impl TotalMasteryLevelCondition {
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
-        let total_level = ctx.stats.iter().map(|x| x.level).sum();
+        let total_level = ctx.champion_stats(self.region.as_ref()).values().map(|x| x.0).sum();
 
         self.range.evaluate(total_level)
     }
@@ -168,7 +436,7 @@ impl TotalMasteryLevelCondition {
 
 impl MasteryScoreCondition {
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
-        let score = ctx.stats.iter().find(|&x| x.champion_id == self.champion).map_or(0, |x| x.score);
+        let score = ctx.champion_stats(self.region.as_ref()).get(&self.champion).map_or(0, |x| x.1);
 
         self.range.evaluate(score)
     }
@@ -176,7 +444,7 @@ impl MasteryScoreCondition {
 
 impl TotalMasteryScoreCondition {
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
-        let total_score = ctx.stats.iter().map(|x| x.score).sum();
+        let total_score = ctx.champion_stats(self.region.as_ref()).values().map(|x| x.1).sum();
 
         self.range.evaluate(total_score)
     }
@@ -191,45 +459,43 @@ impl RankedTierCondition {
             return false;
         }
 
+        let (ranks, any_hidden) = ctx.visible_ranks(self.region.as_ref());
+
         match &self.queue {
             RankedTierQueue::HighestExcludingTFT | RankedTierQueue::HighestIncludingTFT => {
                 let include_tft = matches!(self.queue, RankedTierQueue::HighestIncludingTFT);
 
-                // Find the user's highest queue, filtering out TFT if needed.
-                let highest = ctx
-                    .ranks
-                    .iter()
-                    .filter(|&x| include_tft || x.queue != "RANKED_TFT")
-                    .max_by_key(|&x| tier_to_numeric(&x.tier));
-
-                // If the user has no rank, or if they are marked as unranked,
-                // this condition only matches if this is an explicit Equals(UNRANKED)
-                // check. This ensures that we don't include UNRANKED in less-than or
-                // higher-than comparisons (i.e. we don't want to treat it as a tier
-                // below iron).
-                match (highest, ctx.user.treat_as_unranked) {
-                    (None, _) | (_, true) => self.compare.is_equals_unranked(),
-                    (Some(rank), _) => self.compare.evaluate(&rank.tier),
+                // Find the user's highest visible queue, filtering out TFT if needed.
+                let highest =
+                    ranks.iter().filter(|&x| include_tft || x.0 != "RANKED_TFT").max_by_key(|&x| tier_to_numeric(x.1));
+
+                // If the user has no (visible) rank, this condition only matches if this
+                // is an explicit Equals(UNRANKED) check. This ensures that we don't include
+                // UNRANKED in less-than or higher-than comparisons (i.e. we don't want to
+                // treat it as a tier below iron).
+                match highest {
+                    None => self.compare.is_equals_unranked(),
+                    Some(rank) => self.compare.evaluate(rank.1),
                 }
             },
             RankedTierQueue::Any => {
-                // If the user should be treated as unranked, only apply this
-                // if we're equals(0).
-                if ctx.user.treat_as_unranked {
+                // If the user has hidden all of their ranks, they should be treated
+                // as unranked, so only apply this if we're equals(0).
+                if ranks.is_empty() && any_hidden {
                     return self.compare.is_equals_unranked();
                 }
 
-                // Check if any rank applies.
-                ctx.ranks.iter().any(|x| self.compare.evaluate(&x.tier))
+                // Check if any visible rank applies.
+                ranks.iter().any(|x| self.compare.evaluate(x.1))
             },
             RankedTierQueue::NamedQueue(queue) => {
-                let rank = ctx.ranks.iter().find(|&x| x.queue == *queue);
+                let rank = ranks.iter().find(|&x| x.0 == queue);
 
-                // Same thing here. Not found or specifically marked as unranked
-                // should only apply if this is an equals condition.
-                match (rank, ctx.user.treat_as_unranked) {
-                    (None, _) | (_, true) => self.compare.is_equals_unranked(),
-                    (Some(rank), _) => self.compare.evaluate(&rank.tier),
+                // Same thing here. Not found or hidden should only apply if this is
+                // an equals condition.
+                match rank {
+                    None => self.compare.is_equals_unranked(),
+                    Some(rank) => self.compare.evaluate(rank.1),
                 }
             },
         }
@@ -238,6 +504,202 @@ impl RankedTierCondition {
 
 impl ServerCondition {
     pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
-        ctx.accounts.iter().any(|x| x.include_region && x.region == self.region)
+        ctx.accounts.iter().any(|x| x.include_region && self.region.contains(x.region))
+    }
+}
+
+impl GroupCondition {
+    pub fn evaluate(&self, ctx: &EvaluationContext) -> bool {
+        let matching = self.conditions.iter().filter(|x| x.evaluate(ctx)).count();
+
+        self.combinator.applies(matching, self.conditions.len(), matching as i32)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+    use sqlx::types::Json;
+
+    use crate::{
+        db_model::{Ladder, LadderStep, Role, User, UserChampionStat},
+        evaluate::EvaluationContext,
+        role_model::{RoleCombinator, RoleConditionWithId},
+    };
+
+    /// A user with the given mastery levels on the given champions.
+    fn context(levels: &[(i32, i32)]) -> EvaluationContext {
+        EvaluationContext {
+            user: User {
+                id: 1,
+                snowflake: "1".to_string(),
+                username: "test".to_string(),
+                last_score_update_timestamp: 0,
+                last_rank_update_timestamp: 0,
+                last_account_update_timestamp: 0,
+                ignore: false,
+                has_accounts: true,
+                last_active_timestamp: None,
+                update_boost: 0,
+            },
+            accounts: vec![],
+            stats: levels
+                .iter()
+                .map(|&(champion_id, level)| UserChampionStat { id: 0, user_id: 1, champion_id, level, score: 0 })
+                .collect(),
+            ranks: vec![],
+            account_stats: vec![],
+            account_ranks: vec![],
+            hidden_ranks: vec![],
+        }
+    }
+
+    fn role(combinator: serde_json::Value) -> Role {
+        Role {
+            id: 1,
+            name: "test".to_string(),
+            snowflake: "1".to_string(),
+            announce: false,
+            combinator: Json(serde_json::from_value(combinator).unwrap()),
+        }
+    }
+
+    /// A condition that matches if the user has at least level 5 on the given champion.
+    fn level(champion: i32) -> serde_json::Value {
+        json!({ "type": "mastery_level", "options": { "compare_type": "at_least", "value": 5, "champion": champion } })
+    }
+
+    fn conditions(conditions: serde_json::Value) -> Vec<RoleConditionWithId> {
+        serde_json::from_value(conditions).unwrap()
+    }
+
+    /// A condition with the given weight.
+    fn weighted(mut condition: serde_json::Value, weight: i32) -> serde_json::Value {
+        condition["weight"] = json!(weight);
+        condition
+    }
+
+    #[test]
+    fn weights_default_to_one() {
+        let conditions = conditions(json!([level(1), weighted(level(2), 3)]));
+
+        assert_eq!(conditions[0].weight, 1);
+        assert_eq!(conditions[1].weight, 3);
+    }
+
+    #[test]
+    fn weighted_roles_apply_from_threshold() {
+        let role = role(json!({ "type": "weighted", "threshold": 5 }));
+        let conditions = conditions(json!([weighted(level(1), 2), weighted(level(2), 3), level(3)]));
+
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5), (3, 5)])), (false, 3));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5), (2, 5)])), (true, 5));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 7), (2, 7), (3, 7)])), (true, 6));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 4), (2, 4)])), (false, 0));
+    }
+
+    #[test]
+    fn weighted_roles_with_negative_and_zero_weights() {
+        let role = role(json!({ "type": "weighted", "threshold": 2 }));
+        let conditions = conditions(json!([weighted(level(1), 3), weighted(level(2), -2), weighted(level(3), 0)]));
+
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5)])), (true, 3));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5), (2, 5)])), (false, 1));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(3, 5)])), (false, 0));
+
+        // Nothing matching still reaches a threshold that is not positive.
+        let role = self::role(json!({ "type": "weighted", "threshold": 0 }));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[])), (true, 0));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(2, 5)])), (false, -2));
+    }
+
+    #[test]
+    fn groups_use_their_own_combinator() {
+        let group = |combinator: serde_json::Value, conditions: Vec<serde_json::Value>| json!({ "type": "group", "options": { "combinator": combinator, "conditions": conditions } });
+
+        // 1 && (2 || (3 && 4))
+        let nested = group(json!({ "type": "all" }), vec![level(3), level(4)]);
+        let conditions = conditions(json!([level(1), group(json!({ "type": "any" }), vec![level(2), nested])]));
+        let role = role(json!({ "type": "all" }));
+
+        assert!(role.evaluate(&conditions, &context(&[(1, 5), (2, 5)])));
+        assert!(role.evaluate(&conditions, &context(&[(1, 5), (3, 5), (4, 5)])));
+        assert!(!role.evaluate(&conditions, &context(&[(1, 5), (3, 5)])));
+        assert!(!role.evaluate(&conditions, &context(&[(2, 5), (3, 5), (4, 5)])));
+
+        // Every matching condition in a group counts as one point.
+        let group = group(json!({ "type": "weighted", "threshold": 2 }), vec![level(1), level(2), level(3)]);
+        let conditions = self::conditions(json!([weighted(group, 10)]));
+
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5)])), (false, 0));
+        assert_eq!(role.evaluate_with_score(&conditions, &context(&[(1, 5), (3, 5)])), (true, 10));
+    }
+
+    #[test]
+    fn combinators() {
+        assert!(RoleCombinator::All.applies(2, 2, 0));
+        assert!(!RoleCombinator::All.applies(1, 2, 0));
+        assert!(RoleCombinator::Any.applies(1, 2, 0));
+        assert!(!RoleCombinator::Any.applies(0, 2, 0));
+        assert!(RoleCombinator::AtLeast { amount: 2 }.applies(2, 3, 0));
+        assert!(!RoleCombinator::AtLeast { amount: 2 }.applies(1, 3, 0));
+        assert!(RoleCombinator::Weighted { threshold: 3 }.applies(1, 3, 3));
+        assert!(!RoleCombinator::Weighted { threshold: 3 }.applies(3, 3, 2));
+    }
+
+    fn ladder() -> Ladder {
+        Ladder {
+            id: 1,
+            server_id: 1,
+            name: "test".to_string(),
+            metric: Json(serde_json::from_value(json!({ "type": "total_mastery_score" })).unwrap()),
+        }
+    }
+
+    /// Steps with the given thresholds, with the threshold as their snowflake.
+    fn steps(thresholds: &[i32]) -> Vec<LadderStep> {
+        thresholds
+            .iter()
+            .map(|&threshold| LadderStep { id: threshold, ladder_id: 1, threshold, snowflake: threshold.to_string() })
+            .collect()
+    }
+
+    #[test]
+    fn ladder_brackets() {
+        let ladder = ladder();
+        let bracket = |steps: &[LadderStep], value| ladder.bracket(steps, value).map(|x| x.threshold);
+
+        let sorted = steps(&[100, 500, 1000]);
+        assert_eq!(bracket(&sorted, Some(99)), None);
+        assert_eq!(bracket(&sorted, Some(100)), Some(100));
+        assert_eq!(bracket(&sorted, Some(499)), Some(100));
+        assert_eq!(bracket(&sorted, Some(500)), Some(500));
+        assert_eq!(bracket(&sorted, Some(1000)), Some(1000));
+        assert_eq!(bracket(&sorted, Some(i32::MAX)), Some(1000));
+        assert_eq!(bracket(&sorted, None), None);
+
+        let unsorted = steps(&[1000, 100, 500]);
+        assert_eq!(bracket(&unsorted, Some(99)), None);
+        assert_eq!(bracket(&unsorted, Some(700)), Some(500));
+        assert_eq!(bracket(&unsorted, Some(5000)), Some(1000));
+
+        assert_eq!(bracket(&[], Some(100)), None);
+        assert_eq!(bracket(&steps(&[0]), Some(0)), Some(0));
+        assert_eq!(bracket(&steps(&[0]), Some(-1)), None);
+    }
+
+    #[test]
+    fn ladder_near_next_step() {
+        let ladder = ladder();
+        let steps = steps(&[1000, 100]);
+
+        assert!(ladder.is_near_next_step(&steps, Some(90)));
+        assert!(!ladder.is_near_next_step(&steps, Some(89)));
+        assert!(!ladder.is_near_next_step(&steps, Some(100)));
+        assert!(ladder.is_near_next_step(&steps, Some(900)));
+        assert!(!ladder.is_near_next_step(&steps, Some(899)));
+        assert!(!ladder.is_near_next_step(&steps, Some(1000)));
+        assert!(!ladder.is_near_next_step(&steps, None));
+        assert!(!ladder.is_near_next_step(&[], Some(100)));
     }
 }
@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use tracing::{debug, warn};
+
+use crate::{
+    database::Database,
+    db_model::{PrimaryAccountFallback, User},
+    evaluate::EvaluationContext,
+    nickname::Template,
+    role_model::LadderMetric,
+    util::DynError,
+};
+
+/// How long the computed needs of a server are reused before its configuration is
+/// loaded again.
+const SERVER_NEEDS_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// How often data that none of the servers of a user depend on is still fetched.
+/// It is still shown on their profile and on the leaderboards, so we never stop
+/// fetching it entirely.
+const UNNEEDED_REFRESH_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How often data is fetched for users that are not on any server we know of.
+const SERVERLESS_REFRESH_INTERVAL: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How long skipped users are left alone at most, even if their data is not stale by
+/// then. Their servers may have started to depend on the data in the meantime.
+const MAX_SKIP_DEFERRAL: Duration = Duration::from_secs(60 * 60);
+
+/// The kinds of data that the worker loops fetch from Riot.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FetchKind {
+    Accounts,
+    RankedTiers,
+    Mastery,
+}
+
+impl FetchKind {
+    /// The name of this kind of data in `user_fetch_deferrals`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            FetchKind::Accounts => "accounts",
+            FetchKind::RankedTiers => "ranked_tiers",
+            FetchKind::Mastery => "mastery",
+        }
+    }
+
+    /// The column of `users` that records when this kind of data was last fetched.
+    pub fn timestamp_column(self) -> &'static str {
+        match self {
+            FetchKind::Accounts => "last_account_update_timestamp",
+            FetchKind::RankedTiers => "last_rank_update_timestamp",
+            FetchKind::Mastery => "last_score_update_timestamp",
+        }
+    }
+
+    /// When this kind of data was last fetched for the given user, in milliseconds
+    /// since the epoch.
+    pub fn last_fetched(self, user: &User) -> i64 {
+        match self {
+            FetchKind::Accounts => user.last_account_update_timestamp,
+            FetchKind::RankedTiers => user.last_rank_update_timestamp,
+            FetchKind::Mastery => user.last_score_update_timestamp,
+        }
+    }
+}
+
+/// Which kinds of data the roles, ladders and nickname pattern of a server
+/// depend on.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct FetchNeeds {
+    pub accounts: bool,
+    pub ranked_tiers: bool,
+    pub mastery: bool,
+}
+
+impl FetchNeeds {
+    /// Whether the given kind of data is needed.
+    pub fn needs(&self, kind: FetchKind) -> bool {
+        match kind {
+            FetchKind::Accounts => self.accounts,
+            FetchKind::RankedTiers => self.ranked_tiers,
+            FetchKind::Mastery => self.mastery,
+        }
+    }
+
+    /// Combine the needs of two servers.
+    fn union(self, other: FetchNeeds) -> FetchNeeds {
+        FetchNeeds {
+            accounts: self.accounts || other.accounts,
+            ranked_tiers: self.ranked_tiers || other.ranked_tiers,
+            mastery: self.mastery || other.mastery,
+        }
+    }
+}
+
+/// Decides which users the worker loops should fetch data for, based on what the
+/// servers they are on actually use. Users whose servers don't depend on a kind of
+/// data are not skipped entirely, but only fetched once it gets stale.
+pub struct FetchPlanner {
+    database: Arc<Database>,
+    /// The needs of every server we computed them for, with when they were computed.
+    servers: Mutex<HashMap<i32, (Instant, FetchNeeds)>>,
+}
+
+impl FetchPlanner {
+    pub fn new(database: Arc<Database>) -> FetchPlanner {
+        FetchPlanner { database, servers: Mutex::new(HashMap::new()) }
+    }
+
+    /// Only keep the contexts of users for whom the given kind of data should be
+    /// fetched right now, together with whether one of their servers needs it (as
+    /// opposed to the data merely having gotten stale). The users that are skipped
+    /// are deferred until their data gets stale, so that they are not loaded again
+    /// on every pass.
+    pub async fn filter(
+        &self,
+        kind: FetchKind,
+        contexts: Vec<EvaluationContext>,
+    ) -> Result<Vec<(EvaluationContext, bool)>, DynError> {
+        let user_ids = contexts.iter().map(|x| x.user.id).collect::<Vec<_>>();
+        let memberships = self.database.get_servers_of_users(&user_ids).await?;
+
+        let mut user_needs = HashMap::<i32, FetchNeeds>::new();
+        for (user_id, server_id) in memberships {
+            let needs = self.server_needs(server_id).await?;
+            let entry = user_needs.entry(user_id).or_default();
+            *entry = entry.union(needs);
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_millis() as i64);
+        let before = contexts.len();
+
+        let mut skipped = (vec![], vec![]);
+        let contexts = contexts
+            .into_iter()
+            .filter_map(|ctx| {
+                let interval = match user_needs.get(&ctx.user.id) {
+                    Some(needs) if needs.needs(kind) => return Some((ctx, true)),
+                    Some(_) => UNNEEDED_REFRESH_INTERVAL,
+                    None => SERVERLESS_REFRESH_INTERVAL,
+                };
+
+                let stale_at = kind.last_fetched(&ctx.user) + interval.as_millis() as i64;
+                if now >= stale_at {
+                    return Some((ctx, false));
+                }
+
+                skipped.0.push(ctx.user.id);
+                skipped.1.push(stale_at.min(now + MAX_SKIP_DEFERRAL.as_millis() as i64));
+                None
+            })
+            .collect::<Vec<_>>();
+
+        debug!("Skipping {:?} for {} out of {} users", kind, skipped.0.len(), before);
+
+        if !skipped.0.is_empty() {
+            if let Err(e) = self.database.defer_fetches(kind.as_str(), &skipped.0, &skipped.1).await {
+                warn!("Failed to defer {:?} for {} skipped users: {:?}", kind, skipped.0.len(), e);
+            }
+        }
+
+        Ok(contexts)
+    }
+
+    /// Compute what the configuration of the server with the given ID depends on,
+    /// or reuse the result from the last time if it's recent enough.
+    async fn server_needs(&self, server_id: i32) -> Result<FetchNeeds, DynError> {
+        if let Some((computed_at, needs)) = self.servers.lock().unwrap().get(&server_id) {
+            if computed_at.elapsed() < SERVER_NEEDS_TTL {
+                return Ok(*needs);
+            }
+        }
+
+        let (server, roles, ladders) = futures::try_join!(
+            self.database.get_server(server_id),
+            self.database.get_roles_and_conditions_for_server(server_id),
+            self.database.get_ladders_for_server(server_id)
+        )?;
+
+        let conditions = roles.iter().flat_map(|x| &x.1).collect::<Vec<_>>();
+        let mut needs = FetchNeeds {
+            accounts: conditions.iter().any(|x| x.needs_accounts()),
+            ranked_tiers: conditions.iter().any(|x| x.needs_ranked_tiers()),
+            mastery: conditions.iter().any(|x| x.needs_mastery()),
+        };
+
+        for (ladder, _) in &ladders {
+            match *ladder.metric {
+                LadderMetric::RankedTier { .. } => needs.ranked_tiers = true,
+                _ => needs.mastery = true,
+            }
+        }
+
+        // Nicknames are based on the primary account, which may itself be chosen
+        // based on the ranks or mastery of the accounts of the user.
+        let template = Some(&server.nickname_pattern).filter(|x| !x.is_empty()).and_then(|x| Template::parse(x).ok());
+        if let Some(template) = template {
+            let fallback = server.primary_account_fallback;
+
+            needs.accounts = true;
+            needs.ranked_tiers |= template.needs_ranked_tiers() || fallback == PrimaryAccountFallback::HighestRank;
+            needs.mastery |= template.needs_mastery()
+                || matches!(
+                    fallback,
+                    PrimaryAccountFallback::MostMastery
+                        | PrimaryAccountFallback::RecentlyPlayed
+                        | PrimaryAccountFallback::MainRegion
+                );
+        }
+
+        self.servers.lock().unwrap().insert(server_id, (Instant::now(), needs));
+
+        Ok(needs)
+    }
+}
@@ -0,0 +1,73 @@
+use redis::{aio::MultiplexedConnection, AsyncTypedCommands};
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::util::DynError;
+
+/// Read-only access to the guilds that dissonance caches in Redis. Only the
+/// parts of the guild that shockwave needs are parsed.
+pub struct GuildCache(Option<MultiplexedConnection>);
+
+#[derive(Deserialize, Debug)]
+pub struct CachedGuild {
+    pub id: String,
+    pub owner_id: String,
+    pub roles: Vec<CachedRole>,
+    #[serde(default)]
+    pub member_count: Option<u64>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CachedRole {
+    pub id: String,
+    pub position: i64,
+    /// The permission bitset, as a string.
+    pub permissions: String,
+    /// Managed roles (such as those of integrations) cannot be assigned manually.
+    pub managed: bool,
+}
+
+impl GuildCache {
+    /// Connect to the Redis server specified in the REDIS_URL environment
+    /// variable. If it is not set, the cache will act as if it is empty.
+    pub async fn connect() -> Result<GuildCache, DynError> {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            warn!("No REDIS_URL set, permission checks are disabled.");
+            return Ok(GuildCache(None));
+        };
+
+        let client = redis::Client::open(format!("redis://{}/", url))?;
+        Ok(GuildCache(Some(client.get_multiplexed_async_connection().await?)))
+    }
+
+    /// Look up the guild with the given snowflake, returning `None` if it is not
+    /// cached (or if it could not be read).
+    pub async fn get_guild(&self, snowflake: &str) -> Option<CachedGuild> {
+        let mut conn = self.0.clone()?;
+
+        let content = match conn.get(format!("dissonance:guild:{}", snowflake)).await {
+            Ok(content) => content?,
+            Err(e) => {
+                warn!("Failed to read guild {} from the cache: {:?}", snowflake, e);
+                return None;
+            },
+        };
+
+        serde_json::from_str(&content)
+            .inspect_err(|e| warn!("Failed to parse cached guild {}: {:?}", snowflake, e))
+            .ok()
+    }
+
+    /// The version of the Orianna configuration of the guild with the given
+    /// snowflake. Dissonance bumps this whenever it changes the configuration in
+    /// response to Discord events, such as a deleted role.
+    pub async fn config_version(&self, snowflake: &str) -> Option<i64> {
+        let mut conn = self.0.clone()?;
+
+        conn.get(format!("dissonance:guild:{}:config_version", snowflake))
+            .await
+            .inspect_err(|e| warn!("Failed to read config version of guild {}: {:?}", snowflake, e))
+            .ok()?
+            .and_then(|x| x.parse().ok())
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::sync::{oneshot, Notify};
+use tracing::warn;
+
+use crate::{
+    database::Database,
+    db_model::{JobStatus, UpdateJob},
+    riot_api::Priority,
+    updater::{Fetch, Phase, PhaseState},
+    util::DynError,
+};
+
+/// How often someone waiting for a job checks whether it was finished by another
+/// instance, since we are only notified of jobs that finish in this process.
+const WAIT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A failed job is retried after this long, doubling for every attempt.
+const BASE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Jobs that failed this many times are given up on and marked as dead.
+const MAX_ATTEMPTS: i32 = 5;
+
+/// How urgent a job is. Jobs with a higher priority are always claimed first.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum JobPriority {
+    /// Regular refreshes from the worker loops.
+    Background = 0,
+    /// Caused by something that happened, such as a member joining a server.
+    Event = 50,
+    /// Someone is waiting for the result.
+    UserAction = 100,
+}
+
+impl JobPriority {
+    /// The priority of the Riot API requests that a job with the given priority makes.
+    pub fn riot_priority(priority: i32) -> Priority {
+        if priority >= JobPriority::UserAction as i32 {
+            Priority::UserAction
+        } else {
+            Priority::Updater
+        }
+    }
+}
+
+/// A single piece of work for the update queue.
+#[derive(Debug)]
+pub struct Job {
+    pub user_id: i32,
+    /// Only update the user on this server, instead of on all servers they are on.
+    pub server_id: Option<i32>,
+    /// The data to fetch from Riot before updating.
+    pub fetch: Fetch,
+    pub priority: JobPriority,
+}
+
+impl Job {
+    /// A job that updates the user with the given ID on all servers they are on.
+    pub fn new(user_id: i32, fetch: Fetch, priority: JobPriority) -> Job {
+        Job { user_id, server_id: None, fetch, priority }
+    }
+}
+
+/// The state of every phase of a job.
+pub type JobPhases = BTreeMap<Phase, PhaseState>;
+
+/// The phases of a job that fetches the given data, before any of them ran.
+pub fn initial_phases(fetch: Fetch) -> JobPhases {
+    fetch.phases().iter().chain(&[Phase::Roles]).map(|&x| (x, PhaseState::Pending)).collect()
+}
+
+/// The result of a single attempt at running a job. Contains the applied changes for
+/// jobs that update a single server, and the error message if the attempt failed.
+pub type JobOutcome = Result<Option<serde_json::Value>, String>;
+
+/// A durable queue of user updates, stored in the `update_jobs` table so that no work
+/// is lost when we restart. API requests, member joins and the worker loops all add
+/// their work here, and the worker runs it in order of priority.
+///
+/// Adding a job that is already pending joins the pending one, so the same user is not
+/// updated twice in a row. Jobs that fail are retried with an exponential backoff, until
+/// they have failed `MAX_ATTEMPTS` times and are kept as dead for inspection instead.
+///
+/// Jobs that were not added by the worker loops report the state of their phases while
+/// they run, and are kept for a while after they finish, so that their status can be
+/// looked up by ID.
+///
+/// In cluster mode, an instance only claims the jobs of users in its own partitions. Since
+/// the worker loops add jobs for those same users, the jobs they wait for run here and
+/// their waiters are woken right away instead of on the next poll.
+pub struct JobQueue {
+    database: Arc<Database>,
+    /// Woken whenever a job is added, so that the worker does not wait for its next poll.
+    enqueued: Notify,
+    /// Everyone in this process that is waiting for the outcome of a job, by job ID.
+    waiters: Mutex<HashMap<i64, Vec<oneshot::Sender<JobOutcome>>>>,
+}
+
+impl JobQueue {
+    pub fn new(database: Arc<Database>) -> JobQueue {
+        JobQueue { database, enqueued: Notify::new(), waiters: Mutex::new(HashMap::new()) }
+    }
+
+    /// Add the given job to the queue and return its ID, or the ID of the pending job it joined.
+    pub async fn enqueue(&self, job: &Job) -> Result<i64, DynError> {
+        let id = self
+            .database
+            .enqueue_job(job.user_id, job.server_id, job.fetch, job.priority as i32, &initial_phases(job.fetch))
+            .await?;
+        self.enqueued.notify_one();
+
+        Ok(id)
+    }
+
+    /// Add the given job to the queue and wait until it was attempted.
+    pub async fn run(&self, job: &Job) -> JobOutcome {
+        let id = self.enqueue(job).await.map_err(|e| e.to_string())?;
+        self.wait(id).await
+    }
+
+    /// Wait until the job with the given ID was attempted and return the outcome. If the
+    /// job was run by another instance, we only learn about it from its stored status. A
+    /// failed attempt ends the wait, even though the job is retried later.
+    pub async fn wait(&self, id: i64) -> JobOutcome {
+        let _waiting = Waiting { queue: self, id };
+        let (sender, mut receiver) = oneshot::channel();
+        self.waiters.lock().unwrap().entry(id).or_default().push(sender);
+
+        // The attempts that were already made when we started waiting. These are reset
+        // when a more urgent request joins the job, so any change counts as an attempt.
+        let mut previous_attempts = None;
+
+        loop {
+            // The job may have finished before we started waiting, so check right away.
+            match self.database.get_job_status(id).await {
+                Ok(None) => break Ok(None),
+                Ok(Some(status)) if status.state == "done" => break Ok(status.plan.map(|x| x.0)),
+                Ok(Some(status)) if status.state == "dead" => {
+                    break Err(status.last_error.unwrap_or_else(|| "Job failed".to_string()))
+                },
+                Ok(Some(status))
+                    if status.state == "pending"
+                        && status.last_error.is_some()
+                        && previous_attempts.is_some_and(|x| status.attempts != x) =>
+                {
+                    break Err(status.last_error.unwrap_or_else(|| "Job failed".to_string()))
+                },
+                Ok(Some(status)) => {
+                    let running = status.state == "running";
+                    previous_attempts.get_or_insert(status.attempts - running as i32);
+                },
+                Err(e) => warn!("Failed to look up update job {}: {:?}", id, e),
+            }
+
+            tokio::select! {
+                outcome = &mut receiver => break outcome.unwrap_or(Ok(None)),
+                _ = tokio::time::sleep(WAIT_POLL_INTERVAL) => {},
+            }
+        }
+    }
+
+    /// The status of the job with the given ID, if it is still known.
+    pub async fn status(&self, id: i64) -> Result<Option<JobStatus>, DynError> {
+        self.database.get_job_status(id).await
+    }
+
+    /// Wait until a job was added to the queue, or return right away if one was added
+    /// since the last call.
+    pub(crate) async fn enqueued(&self) {
+        self.enqueued.notified().await
+    }
+
+    /// Claim up to `amount` jobs that should run now, marking them as running for `lease`.
+    /// Only jobs of users in the given partitions are claimed, see `Cluster::partitions`.
+    pub(crate) async fn claim(
+        &self,
+        amount: usize,
+        lease: Duration,
+        partitions: (i32, &[i32]),
+    ) -> Result<Vec<UpdateJob>, DynError> {
+        self.database.claim_jobs(amount as i64, lease.as_secs_f64(), partitions).await
+    }
+
+    /// Store the phases of the running job with the given ID while it runs.
+    pub(crate) async fn save_phases(&self, id: i64, phases: &JobPhases) {
+        if let Err(e) = self.database.set_job_phases(id, phases).await {
+            warn!("Failed to store the progress of update job {}: {:?}", id, e);
+        }
+    }
+
+    /// Record the outcome of an attempt at the given job, together with the phases it
+    /// went through. Successful jobs are done, failed ones are retried later or given up on.
+    pub(crate) async fn finish(&self, job: &UpdateJob, outcome: JobOutcome, mut phases: JobPhases) {
+        for waiter in self.waiters.lock().unwrap().remove(&job.id).unwrap_or_default() {
+            let _ = waiter.send(outcome.clone());
+        }
+
+        // Phases that never reported anything either shared the outcome of identical
+        // work that ran at the same time, or did not run because of an earlier failure.
+        for state in phases.values_mut().filter(|x| **x == PhaseState::Pending) {
+            *state = if outcome.is_ok() { PhaseState::Done } else { PhaseState::Skipped };
+        }
+
+        let result = match &outcome {
+            // Nobody asks about jobs from the worker loops, and there are a lot of them.
+            Ok(_) if job.priority <= JobPriority::Background as i32 => self.database.delete_job(job.id).await,
+            Ok(plan) => self.database.finish_job(job.id, &phases, plan.as_ref()).await,
+            Err(e) if job.attempts >= MAX_ATTEMPTS => {
+                warn!("Giving up on update job {} for user {}: {}", job.id, job.user_id, e);
+                self.database.bury_job(job.id, e, &phases).await
+            },
+            Err(e) => {
+                let delay = BASE_RETRY_DELAY.saturating_mul(1 << (job.attempts - 1).clamp(0, 16));
+                self.database.retry_job(job.id, delay.min(MAX_RETRY_DELAY).as_secs_f64(), e, &phases).await
+            },
+        };
+
+        if let Err(e) = result {
+            warn!("Failed to record the outcome of update job {}: {:?}", job.id, e);
+        }
+    }
+
+    /// Remove jobs that are done or dead for longer than the given ages. Returns how many were removed.
+    pub(crate) async fn prune(&self, done_age: Duration, dead_age: Duration) -> Result<u64, DynError> {
+        self.database.prune_jobs(done_age.as_secs_f64(), dead_age.as_secs_f64()).await
+    }
+}
+
+/// Someone waiting for a job, whose notification is cleaned up once they stop waiting.
+struct Waiting<'a> {
+    queue: &'a JobQueue,
+    id: i64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut waiters = self.queue.waiters.lock().unwrap();
+        if let Some(senders) = waiters.get_mut(&self.id) {
+            senders.retain(|x| !x.is_closed());
+            if senders.is_empty() {
+                waiters.remove(&self.id);
+            }
+        }
+    }
+}
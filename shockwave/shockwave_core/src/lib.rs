@@ -1,12 +1,21 @@
 pub mod database;
+pub mod dsl;
+pub mod guild_cache;
+pub mod jobs;
+pub mod nickname;
 pub mod riot_api;
 pub mod updater;
 pub mod worker;
 
 pub use twilight_http as discord;
 
+mod adaptive;
+mod cluster;
 mod db_model;
 mod evaluate;
+mod fetch_plan;
 mod orianna;
+mod region;
 mod role_model;
+mod scheduler;
 mod util;
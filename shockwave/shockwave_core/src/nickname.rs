@@ -0,0 +1,491 @@
+//! Templates for the nicknames that Orianna assigns to members of a server.
+//! Placeholders are written in braces and may be followed by filters, and
+//! parts of the nickname can be made conditional. For example:
+//!
+//! ```text
+//! {if rank}[{rank|emoji}] {end}{gamename} ({mastery|abbreviate})
+//! ```
+//!
+//! Use `{{` and `}}` for literal braces. Nicknames are limited to 32 graphemes,
+//! and the values of placeholders are shortened before the rest of the pattern.
+
+use std::ops::Range;
+
+use riven::consts::Champion;
+use serde::Serialize;
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::{db_model::Server, evaluate::EvaluationContext};
+
+/// The maximum length of a nickname on Discord.
+const MAX_LENGTH: usize = 32;
+
+/// An error in a nickname pattern. The span is a byte range into the pattern.
+#[derive(Debug, Serialize)]
+pub struct TemplateError {
+    pub message: String,
+    pub span: Range<usize>,
+}
+
+impl TemplateError {
+    fn new(message: impl Into<String>, span: Range<usize>) -> TemplateError {
+        TemplateError { message: message.into(), span }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Variable {
+    Region,
+    GameName,
+    Tagline,
+    DiscordUsername,
+    Rank,
+    TopChampion,
+    TopChampionLevel,
+    TopChampionScore,
+    Mastery,
+    Level,
+}
+
+impl Variable {
+    fn parse(name: &str) -> Option<Variable> {
+        Some(match name {
+            "region" => Variable::Region,
+            // Summoner names no longer exist, so this is kept as an alias.
+            "gamename" | "username" => Variable::GameName,
+            "tagline" => Variable::Tagline,
+            "discord_username" => Variable::DiscordUsername,
+            "rank" => Variable::Rank,
+            "top_champion" => Variable::TopChampion,
+            "top_champion_level" => Variable::TopChampionLevel,
+            "top_champion_score" => Variable::TopChampionScore,
+            "mastery" => Variable::Mastery,
+            "level" => Variable::Level,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Filter {
+    Upper,
+    Lower,
+    Title,
+    Abbreviate,
+    Emoji,
+}
+
+impl Filter {
+    fn parse(name: &str) -> Option<Filter> {
+        Some(match name {
+            "upper" => Filter::Upper,
+            "lower" => Filter::Lower,
+            "title" => Filter::Title,
+            "abbreviate" => Filter::Abbreviate,
+            "emoji" => Filter::Emoji,
+            _ => return None,
+        })
+    }
+
+    fn apply(self, value: Value) -> Value {
+        match (self, value) {
+            (Filter::Upper, value) => Value::Text(value.to_string().to_uppercase()),
+            (Filter::Lower, value) => Value::Text(value.to_string().to_lowercase()),
+            (Filter::Title, value) => Value::Text(title_case(&value.to_string())),
+            (Filter::Abbreviate, Value::Number(x)) => Value::Text(abbreviate(x)),
+            (Filter::Emoji, Value::Text(x)) => Value::Text(rank_emoji(&x).map_or(x, str::to_string)),
+            (_, value) => value,
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Node {
+    Text(String),
+    Variable(Variable, Vec<Filter>),
+    If { variable: Variable, negate: bool, then: Vec<Node>, otherwise: Vec<Node> },
+}
+
+/// A parsed nickname pattern.
+#[derive(Debug)]
+pub struct Template(Vec<Node>);
+
+/// The values that placeholders in a template refer to.
+#[derive(Debug)]
+pub struct NicknameValues {
+    pub region: String,
+    pub game_name: Option<String>,
+    pub tagline: Option<String>,
+    pub discord_username: String,
+    /// The highest ranked tier outside of TFT, such as `GOLD`.
+    pub rank: Option<String>,
+    /// The champion with the highest mastery score, with its level and score.
+    pub top_champion: Option<(String, i32, i32)>,
+    pub mastery: i64,
+    pub level: i64,
+}
+
+#[derive(Debug)]
+enum Value {
+    Text(String),
+    Number(i64),
+}
+
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Text(x) => f.write_str(x),
+            Value::Number(x) => write!(f, "{}", x),
+        }
+    }
+}
+
+impl NicknameValues {
+    /// Collect the values for the given user on the given server, or `None` if
+    /// they have no primary account to base their nickname on.
+    pub fn from_context(ctx: &EvaluationContext, server: &Server) -> Option<NicknameValues> {
+        let primary = ctx.primary_account(server)?;
+        let top_champion = ctx.stats.iter().max_by_key(|x| x.score).and_then(|x| {
+            let name = Champion(x.champion_id as i16).name()?;
+            Some((name.to_string(), x.level, x.score))
+        });
+
+        Some(NicknameValues {
+            region: primary.region.as_str().to_string(),
+            game_name: primary.riot_id_game_name.clone(),
+            tagline: primary.riot_id_tagline.clone(),
+            discord_username: ctx.user.username.clone(),
+            rank: ctx.highest_tier().map(str::to_string),
+            top_champion,
+            mastery: ctx.stats.iter().map(|x| x.score as i64).sum(),
+            level: ctx.stats.iter().map(|x| x.level as i64).sum(),
+        })
+    }
+
+    /// Example values, for previewing a pattern without a user.
+    pub fn example() -> NicknameValues {
+        NicknameValues {
+            region: "KR".to_string(),
+            game_name: Some("Hide on bush".to_string()),
+            tagline: Some("KR1".to_string()),
+            discord_username: "T1 Faker".to_string(),
+            rank: Some("CHALLENGER".to_string()),
+            top_champion: Some(("Orianna".to_string(), 7, 1_234_567)),
+            mastery: 4_567_890,
+            level: 1_337,
+        }
+    }
+
+    fn get(&self, variable: Variable) -> Option<Value> {
+        let value = match variable {
+            Variable::Region => Value::Text(self.region.clone()),
+            Variable::GameName => Value::Text(self.game_name.clone()?),
+            Variable::Tagline => Value::Text(self.tagline.clone()?),
+            Variable::DiscordUsername => Value::Text(self.discord_username.clone()),
+            Variable::Rank => Value::Text(self.rank.clone()?),
+            Variable::TopChampion => Value::Text(self.top_champion.as_ref()?.0.clone()),
+            Variable::TopChampionLevel => Value::Number(self.top_champion.as_ref()?.1 as i64),
+            Variable::TopChampionScore => Value::Number(self.top_champion.as_ref()?.2 as i64),
+            Variable::Mastery => Value::Number(self.mastery),
+            Variable::Level => Value::Number(self.level),
+        };
+
+        // Treat empty values as missing, so that conditionals skip them.
+        match &value {
+            Value::Text(x) if x.is_empty() => None,
+            Value::Number(0) => None,
+            _ => Some(value),
+        }
+    }
+}
+
+/// A `{if}` block that is still being parsed.
+struct OpenIf {
+    variable: Variable,
+    negate: bool,
+    then: Vec<Node>,
+    otherwise: Option<Vec<Node>>,
+    span: Range<usize>,
+}
+
+impl Template {
+    /// Parse the given nickname pattern.
+    pub fn parse(pattern: &str) -> Result<Template, TemplateError> {
+        let mut root = vec![];
+        let mut open = Vec::<OpenIf>::new();
+        let mut text = String::new();
+        let mut pos = 0;
+
+        // Returns the list of nodes that new nodes should be added to.
+        fn current<'a>(root: &'a mut Vec<Node>, open: &'a mut [OpenIf]) -> &'a mut Vec<Node> {
+            match open.last_mut() {
+                Some(OpenIf { otherwise: Some(x), .. }) => x,
+                Some(x) => &mut x.then,
+                None => root,
+            }
+        }
+
+        while pos < pattern.len() {
+            let rest = &pattern[pos..];
+
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                text.push_str(&rest[..1]);
+                pos += 2;
+                continue;
+            }
+
+            if !rest.starts_with('{') {
+                let c = rest.chars().next().unwrap();
+                text.push(c);
+                pos += c.len_utf8();
+                continue;
+            }
+
+            let end = rest.find('}').ok_or_else(|| TemplateError::new("unclosed `{`", pos..pattern.len()))?;
+            let span = pos..pos + end + 1;
+            let tag = rest[1..end].trim();
+            pos = span.end;
+
+            if !text.is_empty() {
+                current(&mut root, &mut open).push(Node::Text(std::mem::take(&mut text)));
+            }
+
+            if let Some(condition) = tag.strip_prefix("if ") {
+                let (negate, name) = match condition.trim().strip_prefix("not ") {
+                    Some(name) => (true, name.trim()),
+                    None => (false, condition.trim()),
+                };
+                let variable = Variable::parse(name)
+                    .ok_or_else(|| TemplateError::new(format!("unknown placeholder `{}`", name), span.clone()))?;
+
+                open.push(OpenIf { variable, negate, then: vec![], otherwise: None, span });
+            } else if tag == "else" {
+                match open.last_mut() {
+                    Some(x) if x.otherwise.is_none() => x.otherwise = Some(vec![]),
+                    _ => return Err(TemplateError::new("`{else}` without a matching `{if}`", span)),
+                }
+            } else if tag == "end" {
+                let block = open.pop().ok_or_else(|| TemplateError::new("`{end}` without a matching `{if}`", span))?;
+
+                current(&mut root, &mut open).push(Node::If {
+                    variable: block.variable,
+                    negate: block.negate,
+                    then: block.then,
+                    otherwise: block.otherwise.unwrap_or_default(),
+                });
+            } else {
+                let mut parts = tag.split('|').map(str::trim);
+                let name = parts.next().unwrap_or_default();
+                let variable = Variable::parse(name)
+                    .ok_or_else(|| TemplateError::new(format!("unknown placeholder `{}`", name), span.clone()))?;
+                let filters = parts
+                    .map(|x| {
+                        Filter::parse(x)
+                            .ok_or_else(|| TemplateError::new(format!("unknown filter `{}`", x), span.clone()))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+
+                current(&mut root, &mut open).push(Node::Variable(variable, filters));
+            }
+        }
+
+        if let Some(block) = open.pop() {
+            return Err(TemplateError::new("`{if}` is missing an `{end}`", block.span));
+        }
+
+        if !text.is_empty() {
+            root.push(Node::Text(text));
+        }
+
+        Ok(Template(root))
+    }
+
+    /// Render this template with the given values, shortening the result to the
+    /// maximum length of a nickname.
+    pub fn render(&self, values: &NicknameValues) -> String {
+        let mut segments = vec![];
+        render_nodes(&self.0, values, &mut segments);
+
+        truncate(segments)
+    }
+
+    /// Returns whether rendering this template requires knowing the ranked tiers
+    /// of the user.
+    pub fn needs_ranked_tiers(&self) -> bool {
+        self.uses(|x| matches!(x, Variable::Rank))
+    }
+
+    /// Returns whether rendering this template requires knowing the mastery
+    /// statistics of the user.
+    pub fn needs_mastery(&self) -> bool {
+        self.uses(|x| {
+            matches!(
+                x,
+                Variable::TopChampion
+                    | Variable::TopChampionLevel
+                    | Variable::TopChampionScore
+                    | Variable::Mastery
+                    | Variable::Level
+            )
+        })
+    }
+
+    /// Whether any placeholder or condition in this template refers to a variable
+    /// matching the given predicate.
+    fn uses(&self, predicate: impl Fn(Variable) -> bool + Copy) -> bool {
+        fn any(nodes: &[Node], predicate: impl Fn(Variable) -> bool + Copy) -> bool {
+            nodes.iter().any(|node| match node {
+                Node::Text(_) => false,
+                Node::Variable(variable, _) => predicate(*variable),
+                Node::If { variable, then, otherwise, .. } => {
+                    predicate(*variable) || any(then, predicate) || any(otherwise, predicate)
+                },
+            })
+        }
+
+        any(&self.0, predicate)
+    }
+}
+
+/// A rendered piece of a nickname, and whether it came from a placeholder.
+struct Segment {
+    graphemes: Vec<String>,
+    variable: bool,
+}
+
+impl Segment {
+    fn new(text: &str, variable: bool) -> Segment {
+        Segment { graphemes: text.graphemes(true).map(str::to_string).collect(), variable }
+    }
+}
+
+fn render_nodes(nodes: &[Node], values: &NicknameValues, out: &mut Vec<Segment>) {
+    for node in nodes {
+        match node {
+            Node::Text(x) => out.push(Segment::new(x, false)),
+            Node::Variable(variable, filters) => {
+                if let Some(value) = values.get(*variable) {
+                    let value = filters.iter().fold(value, |value, filter| filter.apply(value));
+                    out.push(Segment::new(&value.to_string(), true));
+                }
+            },
+            Node::If { variable, negate, then, otherwise } => {
+                let branch = if values.get(*variable).is_some() != *negate { then } else { otherwise };
+                render_nodes(branch, values, out);
+            },
+        }
+    }
+}
+
+/// Join the given segments, shortening the longest placeholder values first until
+/// the result fits. Only if there is nothing left to shorten is the end cut off.
+fn truncate(mut segments: Vec<Segment>) -> String {
+    let mut length = segments.iter().map(|x| x.graphemes.len()).sum::<usize>();
+
+    while length > MAX_LENGTH {
+        let Some(longest) = segments.iter_mut().filter(|x| x.variable).max_by_key(|x| x.graphemes.len()) else {
+            break;
+        };
+
+        if longest.graphemes.pop().is_none() {
+            break;
+        }
+
+        length -= 1;
+    }
+
+    segments
+        .iter()
+        .flat_map(|x| &x.graphemes)
+        .take(MAX_LENGTH)
+        .map(String::as_str)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+fn title_case(text: &str) -> String {
+    text.split(' ')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect(),
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Shorten the given number to at most four characters, e.g. `12.3k` or `4.5m`.
+fn abbreviate(value: i64) -> String {
+    for (size, suffix) in [(1_000_000_000, "b"), (1_000_000, "m"), (1_000, "k")] {
+        if value.abs() >= size {
+            let scaled = value as f64 / size as f64;
+            return if scaled.abs() >= 100.0 {
+                format!("{}{}", scaled.trunc(), suffix)
+            } else {
+                format!("{}{}", (scaled * 10.0).trunc() / 10.0, suffix)
+            };
+        }
+    }
+
+    value.to_string()
+}
+
+fn rank_emoji(tier: &str) -> Option<&'static str> {
+    Some(match tier.to_uppercase().as_str() {
+        "IRON" => "🔩",
+        "BRONZE" => "🥉",
+        "SILVER" => "🥈",
+        "GOLD" => "🥇",
+        "PLATINUM" => "💠",
+        "EMERALD" => "💚",
+        "DIAMOND" => "💎",
+        "MASTER" => "🔮",
+        "GRANDMASTER" => "🏆",
+        "CHALLENGER" => "👑",
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NicknameValues, Template};
+
+    fn render(pattern: &str) -> String {
+        Template::parse(pattern).unwrap().render(&NicknameValues::example())
+    }
+
+    #[test]
+    fn renders_templates() {
+        assert_eq!(render("[{region}] {gamename}#{tagline}"), "[KR] Hide on bush#KR1");
+        assert_eq!(render("{if rank}{rank|emoji} {end}{top_champion|upper}"), "👑 ORIANNA");
+        assert_eq!(
+            render("{if not rank}unranked{else}{rank|title}{end} {{{mastery|abbreviate}}}"),
+            "Challenger {4.5m}"
+        );
+        assert!(Template::parse("{if rank}{rank}").is_err());
+        assert!(Template::parse("{rank|shout}").is_err());
+    }
+
+    #[test]
+    fn truncates_placeholders_first() {
+        let mut values = NicknameValues::example();
+        values.game_name = Some("a very long game name that does not fit".to_string());
+
+        let nickname = Template::parse("{gamename} [{region}]!").unwrap().render(&values);
+        assert_eq!(nickname, "a very long game name that [KR]!");
+    }
+
+    #[test]
+    fn finds_needed_data() {
+        let template = Template::parse("{gamename}{if not rank} ({level}){end}").unwrap();
+        assert!(template.needs_ranked_tiers());
+        assert!(template.needs_mastery());
+
+        let template = Template::parse("[{region}] {gamename}").unwrap();
+        assert!(!template.needs_ranked_tiers());
+        assert!(!template.needs_mastery());
+    }
+}
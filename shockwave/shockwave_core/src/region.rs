@@ -0,0 +1,295 @@
+//! Canonical representation of the regions (platforms) that a League
+//! account can live on. The database stores regions as the free-form
+//! strings that the frontend submitted at the time of linking, which
+//! includes legacy names for servers that have since been merged or
+//! renamed. Everything in shockwave should go through `Region` so that
+//! those aliases compare equal.
+
+use std::{fmt, str::FromStr};
+
+use riven::consts::PlatformRoute;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A League of Legends server, such as EUW. Named after the short names
+/// that Orianna has always used, rather than the Riot platform IDs.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Region {
+    BR,
+    EUNE,
+    EUW,
+    JP,
+    KR,
+    LAN,
+    LAS,
+    ME,
+    NA,
+    OCE,
+    RU,
+    SEA,
+    TR,
+    TW,
+    VN,
+}
+
+/// A group of regions that share a Riot regional cluster. Used by role
+/// conditions that want to match e.g. "any European server".
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MacroRegion {
+    Americas,
+    Europe,
+    Asia,
+    Sea,
+}
+
+/// Error returned when a string does not correspond to any known region
+/// or region alias.
+#[derive(Debug)]
+pub struct UnknownRegion(pub String);
+
+impl fmt::Display for UnknownRegion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unknown region {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnknownRegion {}
+
+impl Region {
+    /// The canonical short name of this region, as shown to users.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Region::BR => "BR",
+            Region::EUNE => "EUNE",
+            Region::EUW => "EUW",
+            Region::JP => "JP",
+            Region::KR => "KR",
+            Region::LAN => "LAN",
+            Region::LAS => "LAS",
+            Region::ME => "ME",
+            Region::NA => "NA",
+            Region::OCE => "OCE",
+            Region::RU => "RU",
+            Region::SEA => "SEA",
+            Region::TR => "TR",
+            Region::TW => "TW",
+            Region::VN => "VN",
+        }
+    }
+
+    /// The Riot API platform that hosts this region.
+    pub fn route(&self) -> PlatformRoute {
+        match self {
+            Region::BR => PlatformRoute::BR1,
+            Region::EUNE => PlatformRoute::EUN1,
+            Region::EUW => PlatformRoute::EUW1,
+            Region::JP => PlatformRoute::JP1,
+            Region::KR => PlatformRoute::KR,
+            Region::LAN => PlatformRoute::LA1,
+            Region::LAS => PlatformRoute::LA2,
+            Region::ME => PlatformRoute::ME1,
+            Region::NA => PlatformRoute::NA1,
+            Region::OCE => PlatformRoute::OC1,
+            Region::RU => PlatformRoute::RU,
+            Region::SEA => PlatformRoute::SG2,
+            Region::TR => PlatformRoute::TR1,
+            Region::TW => PlatformRoute::TW2,
+            Region::VN => PlatformRoute::VN2,
+        }
+    }
+
+    /// The macro region (Riot regional cluster) this region belongs to.
+    pub fn macro_region(&self) -> MacroRegion {
+        match self {
+            Region::BR | Region::LAN | Region::LAS | Region::NA => MacroRegion::Americas,
+            Region::EUNE | Region::EUW | Region::ME | Region::RU | Region::TR => MacroRegion::Europe,
+            Region::JP | Region::KR => MacroRegion::Asia,
+            Region::OCE | Region::SEA | Region::TW | Region::VN => MacroRegion::Sea,
+        }
+    }
+}
+
+impl FromStr for Region {
+    type Err = UnknownRegion;
+
+    /// Parses a region, accepting both the short names used by Orianna,
+    /// the platform IDs used by the Riot API and any legacy names that
+    /// may still be stored for older accounts.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_uppercase().as_str() {
+            "BR" | "BR1" => Region::BR,
+            "EUNE" | "EUN1" => Region::EUNE,
+            "EUW" | "EUW1" => Region::EUW,
+            "JP" | "JP1" => Region::JP,
+            "KR" => Region::KR,
+            "LAN" | "LA1" => Region::LAN,
+            "LAS" | "LA2" => Region::LAS,
+            "ME" | "ME1" | "MENA" => Region::ME,
+            "NA" | "NA1" => Region::NA,
+            "OCE" | "OC1" => Region::OCE,
+            "RU" => Region::RU,
+            "SEA" | "SG" | "SG2" => Region::SEA,
+            "PH" | "PH2" => Region::SEA, // merged into SG which got renamed to SEA
+            "TH" | "TH2" => Region::SEA, // merged into SG which got renamed to SEA
+            "TR" | "TR1" => Region::TR,
+            "TW" | "TW2" => Region::TW,
+            "VN" | "VN2" => Region::VN,
+            _ => return Err(UnknownRegion(s.to_string())),
+        })
+    }
+}
+
+impl TryFrom<String> for Region {
+    type Error = UnknownRegion;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Serialize for Region {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Region {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+    }
+}
+
+impl MacroRegion {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            MacroRegion::Americas => "ANY_AMERICAS",
+            MacroRegion::Europe => "ANY_EUROPE",
+            MacroRegion::Asia => "ANY_ASIA",
+            MacroRegion::Sea => "ANY_SEA",
+        }
+    }
+}
+
+impl FromStr for MacroRegion {
+    type Err = UnknownRegion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.trim().to_uppercase().as_str() {
+            "ANY_AMERICAS" | "ANY_AMERICA" => MacroRegion::Americas,
+            "ANY_EUROPE" | "ANY_EU" => MacroRegion::Europe,
+            "ANY_ASIA" => MacroRegion::Asia,
+            "ANY_SEA" => MacroRegion::Sea,
+            _ => return Err(UnknownRegion(s.to_string())),
+        })
+    }
+}
+
+/// A single entry in a region filter: either one specific region or
+/// every region within a macro region.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionFilter {
+    Region(Region),
+    Macro(MacroRegion),
+}
+
+impl RegionFilter {
+    pub fn matches(&self, region: Region) -> bool {
+        match *self {
+            RegionFilter::Region(x) => x == region,
+            RegionFilter::Macro(x) => x == region.macro_region(),
+        }
+    }
+}
+
+impl FromStr for RegionFilter {
+    type Err = UnknownRegion;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse::<MacroRegion>().map(RegionFilter::Macro).or_else(|_| s.parse::<Region>().map(RegionFilter::Region))
+    }
+}
+
+impl fmt::Display for RegionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionFilter::Region(x) => f.write_str(x.as_str()),
+            RegionFilter::Macro(x) => f.write_str(x.as_str()),
+        }
+    }
+}
+
+/// A set of regions as configured in a role condition. Deserializes from
+/// either a single string or a list of strings, where every string is
+/// either a region (`"EUW"`) or a macro region (`"ANY_EUROPE"`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RegionSet(pub Vec<RegionFilter>);
+
+impl RegionSet {
+    /// Whether the given region is included in this set.
+    pub fn contains(&self, region: Region) -> bool {
+        self.0.iter().any(|x| x.matches(region))
+    }
+}
+
+impl<'de> Deserialize<'de> for RegionSet {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+
+        let entries = match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(x) => vec![x],
+            OneOrMany::Many(x) => x,
+        };
+
+        entries
+            .iter()
+            .map(|x| x.parse::<RegionFilter>().map_err(de::Error::custom))
+            .collect::<Result<_, _>>()
+            .map(RegionSet)
+    }
+}
+
+impl Serialize for RegionSet {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.0.iter().map(|x| x.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::region::{MacroRegion, Region, RegionFilter, RegionSet};
+
+    #[test]
+    fn parses_aliases() {
+        assert_eq!("euw".parse::<Region>().unwrap(), Region::EUW);
+        assert_eq!("EUW1".parse::<Region>().unwrap(), Region::EUW);
+        assert_eq!("PH".parse::<Region>().unwrap(), Region::SEA);
+        assert_eq!("SG2".parse::<Region>().unwrap(), Region::SEA);
+        assert_eq!("ME".parse::<Region>().unwrap(), Region::ME);
+        assert!("ATLANTIS".parse::<Region>().is_err());
+    }
+
+    #[test]
+    fn region_sets() {
+        let single: RegionSet = serde_json::from_str(r#""SG2""#).unwrap();
+        assert!(single.contains(Region::SEA));
+        assert!(!single.contains(Region::OCE));
+
+        let many: RegionSet = serde_json::from_str(r#"["EUW", "ANY_ASIA"]"#).unwrap();
+        assert_eq!(many.0, vec![RegionFilter::Region(Region::EUW), RegionFilter::Macro(MacroRegion::Asia)]);
+        assert!(many.contains(Region::KR));
+        assert!(!many.contains(Region::EUNE));
+
+        assert!(serde_json::from_str::<RegionSet>(r#"["EUW", "NOPE"]"#).is_err());
+    }
+}
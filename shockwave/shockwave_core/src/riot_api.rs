@@ -1,12 +1,15 @@
-use futures::{future, FutureExt};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures::{future, TryFutureExt};
 use rand::prelude::SliceRandom;
+use reqwest::StatusCode;
 use riven::{
     consts::{QueueType, RegionalRoute, Tier},
     models::{account_v1, champion_mastery_v4::ChampionMastery, summoner_v4::Summoner},
-    Result as RivenResult, RiotApi, RiotApiConfig,
+    Result as RivenResult, RiotApi, RiotApiConfig, RiotApiError,
 };
 
-use crate::{db_model::LeagueAccount, util::DynError};
+use crate::{db_model::LeagueAccount, fetch_plan::FetchKind, util::DynError};
 
 /// Helper wrapper for `RiotApi` that will dispatch
 /// calls to either the updater or the priority instance
@@ -17,6 +20,12 @@ pub struct RiotApiInterface {
 
     priority_lol_api_client: RiotApi,
     priority_tft_api_client: RiotApi,
+
+    /// The amount of requests of the updater clients that failed because we were
+    /// rate limited, even after retrying, per kind of data (see `rate_limit_index`).
+    /// Each method has its own rate limit, so one kind being limited says nothing
+    /// about the others.
+    rate_limited: [AtomicU64; 3],
 }
 
 /// A priority that represents which Riot API instance should
@@ -53,90 +62,82 @@ impl RiotApiInterface {
             priority_tft_api_client: RiotApi::new(
                 RiotApiConfig::with_key(tft_api_key).set_rate_usage_factor(USER_ACTION_RATE_LIMIT_PCT),
             ),
+            rate_limited: Default::default(),
         }
     }
 
-    /// Retrieve all the league entries for the given accounts. Note that this
-    /// may return multiple entries for the same queue if the user has more than
-    /// one account.
+    /// The total amount of updater requests for the given kind of data that were
+    /// rejected with a 429 so far.
+    pub fn rate_limited_requests(&self, kind: FetchKind) -> u64 {
+        self.rate_limited[rate_limit_index(kind)].load(Ordering::Relaxed)
+    }
+
+    /// Retrieve all the league entries for the given accounts. The result contains
+    /// one entry per account, in the same order as the given accounts.
     pub async fn get_lol_league_entries(
         &self,
         priority: Priority,
-        accounts: &Vec<LeagueAccount>,
-    ) -> Result<Vec<(QueueType, Tier)>> {
-        Ok(future::try_join_all(accounts.iter().filter_map(|account| {
-            account
-                .route()
-                .map(|region| self.lol_client(priority).league_v4().get_league_entries_by_puuid(region, &account.puuid))
+        accounts: &[LeagueAccount],
+    ) -> Result<Vec<Vec<(QueueType, Tier)>>> {
+        Ok(future::try_join_all(accounts.iter().map(|account| {
+            self.lol_client(priority)
+                .league_v4()
+                .get_league_entries_by_puuid(account.route(), &account.puuid)
+                .inspect_err(|e| self.record_error(priority, FetchKind::RankedTiers, e))
         }))
         .await?
         .into_iter()
-        .flatten()
-        .flat_map(|x| x.tier.map(|t| (x.queue_type, t)))
+        .map(|entries| entries.into_iter().flat_map(|x| x.tier.map(|t| (x.queue_type, t))).collect())
         .collect())
     }
 
-    /// Retrieve all the TFT entries for the given accounts. Note that this
-    /// may return multiple entries for the same queue if the user has more than
-    /// one account. Does not return hyperroll queues, since they have a different
-    /// concept of tiers.
+    /// Retrieve all the TFT entries for the given accounts. The result contains
+    /// one entry per account, in the same order as the given accounts. Does not
+    /// return hyperroll queues, since they have a different concept of tiers.
     pub async fn get_tft_league_entries(
         &self,
         priority: Priority,
-        accounts: &Vec<LeagueAccount>,
-    ) -> Result<Vec<(QueueType, Tier)>> {
-        Ok(future::try_join_all(accounts.iter().filter_map(|account| {
-            let Some(route) = account.route() else {
-                return None;
-            };
-
-            Some(
-                self.tft_client(priority)
-                    .tft_league_v1()
-                    .get_league_entries_by_puuid(route, &account.puuid)
-                    .map(|x| x.map(|x| x.into_iter().filter(|x| x.tier.is_some()).collect::<Vec<_>>())),
-            )
+        accounts: &[LeagueAccount],
+    ) -> Result<Vec<Vec<(QueueType, Tier)>>> {
+        Ok(future::try_join_all(accounts.iter().map(|account| {
+            self.tft_client(priority)
+                .tft_league_v1()
+                .get_league_entries_by_puuid(account.route(), &account.puuid)
+                .inspect_err(|e| self.record_error(priority, FetchKind::RankedTiers, e))
         }))
         .await?
         .into_iter()
-        .flatten()
-        .map(|x| (x.queue_type, x.tier.unwrap()))
+        .map(|entries| entries.into_iter().flat_map(|x| x.tier.map(|t| (x.queue_type, t))).collect())
         .collect())
     }
 
-    /// Returns the set of champion mastery scores for the given users.
-    /// Note that multiple entries for the same champion may exist, since
-    /// this does not process the data in any way.
+    /// Returns the set of champion mastery scores for the given users, with one
+    /// entry per account in the same order as the given accounts. Note that multiple
+    /// entries for the same champion may exist, since this does not process the data
+    /// in any way.
     pub async fn get_champion_mastery_scores(
         &self,
         priority: Priority,
-        accounts: &Vec<LeagueAccount>,
+        accounts: &[LeagueAccount],
     ) -> Result<Vec<Vec<ChampionMastery>>> {
-        Ok(future::try_join_all(accounts.iter().filter_map(|account| {
-            account.route().map(|region| {
-                self.lol_client(priority)
-                    .champion_mastery_v4()
-                    .get_all_champion_masteries_by_puuid(region, &account.puuid)
-            })
+        Ok(future::try_join_all(accounts.iter().map(|account| {
+            self.lol_client(priority)
+                .champion_mastery_v4()
+                .get_all_champion_masteries_by_puuid(account.route(), &account.puuid)
+                .inspect_err(|e| self.record_error(priority, FetchKind::Mastery, e))
         }))
         .await?)
     }
 
-    /// Attempts to retrieve the summoner for the given account. Note
-    /// that this returns a double result: the first result is solely to
-    /// indicate whether we could even load the summoner (region parsing),
-    /// while the second result indicates the result of actually calling
-    /// the API (we expose this for more complex logic).
-    pub async fn get_summoner(
-        &self,
-        priority: Priority,
-        account: &LeagueAccount,
-    ) -> Result<RivenResult<Option<Summoner>>> {
-        let Some(region) = account.route() else {
-            return Err("Could not parse region".into());
-        };
-
-        Ok(self.lol_client(priority).summoner_v4().get_by_puuid(region, &account.puuid).await)
+    /// Attempts to retrieve the summoner for the given account. This returns
+    /// the raw result of calling the API, since callers need to distinguish
+    /// between a missing summoner and other API errors.
+    pub async fn get_summoner(&self, priority: Priority, account: &LeagueAccount) -> RivenResult<Option<Summoner>> {
+        self.lol_client(priority)
+            .summoner_v4()
+            .get_by_puuid(account.route(), &account.puuid)
+            .inspect_err(|e| self.record_error(priority, FetchKind::Accounts, e))
+            .await
     }
 
     /// Attempt to retrieve the full Riot ID for the given account.
@@ -147,7 +148,19 @@ impl RiotApiInterface {
         let mut rng = rand::thread_rng();
         let cluster = [RegionalRoute::AMERICAS, RegionalRoute::ASIA, RegionalRoute::EUROPE].choose(&mut rng).unwrap();
 
-        self.lol_client(priority).account_v1().get_by_puuid(*cluster, &account.puuid).await
+        self.lol_client(priority)
+            .account_v1()
+            .get_by_puuid(*cluster, &account.puuid)
+            .inspect_err(|e| self.record_error(priority, FetchKind::Accounts, e))
+            .await
+    }
+
+    /// Keep track of the given error of a request for the given kind of data if it
+    /// means that the updater is rate limited.
+    fn record_error(&self, priority: Priority, kind: FetchKind, error: &RiotApiError) {
+        if matches!(priority, Priority::Updater) && error.status_code() == Some(StatusCode::TOO_MANY_REQUESTS) {
+            self.rate_limited[rate_limit_index(kind)].fetch_add(1, Ordering::Relaxed);
+        }
     }
 
     /// Helper function to return the appropriate LOL client for the given priority.
@@ -166,3 +179,12 @@ impl RiotApiInterface {
         }
     }
 }
+
+/// The index of the rate limited requests of the given kind of data in `rate_limited`.
+fn rate_limit_index(kind: FetchKind) -> usize {
+    match kind {
+        FetchKind::Accounts => 0,
+        FetchKind::RankedTiers => 1,
+        FetchKind::Mastery => 2,
+    }
+}
@@ -1,8 +1,10 @@
 use std::ops::Deref;
 
-use serde::Deserialize;
+use serde::{Deserialize, Serialize, Serializer};
 
-#[derive(Deserialize, Debug)]
+use crate::region::RegionSet;
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "compare_type")]
 #[serde(rename_all = "snake_case")]
 pub enum RangeCondition {
@@ -14,12 +16,22 @@ pub enum RangeCondition {
 
 #[derive(Deserialize, Debug)]
 pub struct RoleConditionWithId {
+    /// Unsaved conditions (such as those in a dry-run) have no ID yet.
+    #[serde(default)]
     pub id: i32,
+    #[serde(default)]
     pub role_id: i32,
+    /// The amount of points this condition contributes to a weighted role.
+    #[serde(default = "default_weight")]
+    pub weight: i32,
     #[serde(flatten)]
     pub condition: RoleCondition,
 }
 
+fn default_weight() -> i32 {
+    1
+}
+
 impl Deref for RoleConditionWithId {
     type Target = RoleCondition;
 
@@ -28,7 +40,7 @@ impl Deref for RoleConditionWithId {
     }
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "type", content = "options")]
 #[serde(rename_all = "snake_case")]
 pub enum RoleCondition {
@@ -38,39 +50,55 @@ pub enum RoleCondition {
     TotalMasteryScore(TotalMasteryScoreCondition),
     RankedTier(RankedTierCondition),
     Server(ServerCondition),
+    Group(GroupCondition),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct MasteryLevelCondition {
     #[serde(flatten)]
     pub range: RangeCondition,
+    /// If set, only accounts on these regions count towards the condition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<RegionSet>,
     pub champion: i32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct TotalMasteryLevelCondition {
     #[serde(flatten)]
     pub range: RangeCondition,
+    /// If set, only accounts on these regions count towards the condition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<RegionSet>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct MasteryScoreCondition {
     #[serde(flatten)]
     pub range: RangeCondition,
+    /// If set, only accounts on these regions count towards the condition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<RegionSet>,
     pub champion: i32,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct TotalMasteryScoreCondition {
     #[serde(flatten)]
     pub range: RangeCondition,
+    /// If set, only accounts on these regions count towards the condition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<RegionSet>,
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct RankedTierCondition {
     #[serde(flatten)]
     pub compare: RankedTierCompare,
     pub queue: RankedTierQueue,
+    /// If set, only accounts on these regions count towards the condition.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<RegionSet>,
 }
 
 #[derive(Deserialize, Debug)]
@@ -85,7 +113,18 @@ pub enum RankedTierQueue {
     NamedQueue(String),
 }
 
-#[derive(Deserialize, Debug)]
+impl Serialize for RankedTierQueue {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(match self {
+            RankedTierQueue::Any => "ANY",
+            RankedTierQueue::HighestExcludingTFT => "HIGHEST",
+            RankedTierQueue::HighestIncludingTFT => "HIGHEST_TFT",
+            RankedTierQueue::NamedQueue(queue) => queue,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "compare_type", content = "tier")]
 #[serde(rename_all = "snake_case")]
 pub enum RankedTierCompare {
@@ -94,16 +133,67 @@ pub enum RankedTierCompare {
     Equal(String),
 }
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug)]
 pub struct ServerCondition {
-    pub region: String,
+    /// Either a single region or a list of regions, each of which
+    /// may also be a macro region such as `ANY_EUROPE`.
+    pub region: RegionSet,
 }
 
-#[derive(Deserialize, Debug)]
+/// A nested set of conditions with its own combinator, so that conditions such
+/// as `a && (b || c)` can be expressed. Weights are not supported within groups,
+/// so every matching condition counts as one point for a weighted combinator.
+#[derive(Deserialize, Serialize, Debug)]
+pub struct GroupCondition {
+    pub combinator: RoleCombinator,
+    pub conditions: Vec<RoleCondition>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
 pub enum RoleCombinator {
     All,
     Any,
     AtLeast { amount: i32 },
+    Weighted { threshold: i32 },
+}
+
+/// The single value that a role ladder is based on. Every variant mirrors the
+/// role condition of the same name, minus the range.
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum LadderMetric {
+    MasteryLevel {
+        champion: i32,
+        #[serde(default)]
+        region: Option<RegionSet>,
+    },
+    TotalMasteryLevel {
+        #[serde(default)]
+        region: Option<RegionSet>,
+    },
+    MasteryScore {
+        champion: i32,
+        #[serde(default)]
+        region: Option<RegionSet>,
+    },
+    TotalMasteryScore {
+        #[serde(default)]
+        region: Option<RegionSet>,
+    },
+    /// The tier in the given queue, as an index where IRON is 1 and CHALLENGER is 10.
+    RankedTier {
+        queue: RankedTierQueue,
+        #[serde(default)]
+        region: Option<RegionSet>,
+    },
+    /// The amount of mastery points gained in the last `days` days, either on a
+    /// single champion or on all champions combined.
+    MasteryGain {
+        #[serde(default)]
+        champion: Option<i32>,
+        days: i32,
+    },
 }
@@ -0,0 +1,140 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::Stream;
+use tracing::warn;
+
+use crate::{cluster::Cluster, database::Database, db_model::User, fetch_plan::FetchKind, updater::MemberPlan};
+
+/// Users that used a command within this window count as recently active.
+const ACTIVE_WINDOW: Duration = Duration::from_secs(3 * 24 * 60 * 60);
+
+const ACTIVE_BOOST: Duration = Duration::from_secs(2 * 60 * 60);
+const NEAR_THRESHOLD_BOOST: Duration = Duration::from_secs(60 * 60);
+const BUSY_SERVER_BOOST: Duration = Duration::from_secs(30 * 60);
+
+/// Only this many busy servers count towards the boost of a user, so that being
+/// on many large servers does not starve everyone else.
+const MAX_BUSY_SERVERS: u32 = 3;
+
+/// How long to wait before looking again when no user is due at all.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a user is left alone after a fetch failed, which doubles with every failure
+/// in a row up to the maximum. This keeps users whose fetches always fail (e.g. because
+/// their summoner was deleted) from being retried on every pass.
+const FAILURE_BACKOFF: Duration = Duration::from_secs(10 * 60);
+const MAX_FAILURE_BACKOFF: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The current time in milliseconds since the epoch, like the update timestamps.
+pub fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |x| x.as_millis() as i64)
+}
+
+/// Signals that make it more urgent to update a user than the age of their data
+/// alone suggests. They are turned into a boost that is subtracted from the update
+/// timestamps of the user when deciding who is most overdue.
+#[derive(Default, Debug)]
+pub struct PrioritySignals {
+    pub recently_active: bool,
+    pub busy_servers: u32,
+    pub near_threshold: bool,
+}
+
+impl PrioritySignals {
+    /// Collect the signals for the given user from the plans of their last update.
+    pub fn new<'a>(user: &User, plans: impl Iterator<Item = &'a MemberPlan>) -> PrioritySignals {
+        let mut signals = PrioritySignals {
+            recently_active: user
+                .last_active_timestamp
+                .is_some_and(|x| now_millis() - x < ACTIVE_WINDOW.as_millis() as i64),
+            ..Default::default()
+        };
+
+        for plan in plans {
+            signals.busy_servers += plan.busy_server as u32;
+            signals.near_threshold |= plan.near_threshold;
+        }
+
+        signals
+    }
+
+    /// How much earlier than usual the user should be updated, in milliseconds.
+    pub fn boost(&self) -> i64 {
+        let mut boost = BUSY_SERVER_BOOST * self.busy_servers.min(MAX_BUSY_SERVERS);
+        if self.recently_active {
+            boost += ACTIVE_BOOST;
+        }
+        if self.near_threshold {
+            boost += NEAR_THRESHOLD_BOOST;
+        }
+
+        boost.as_millis() as i64
+    }
+}
+
+/// Create an endless stream of batches of IDs of users whose data of the given kind
+/// is due for an update, which is at least `interval` after their last update minus
+/// their boost. Users that were never fetched have a timestamp of zero, so they come
+/// first. The size of every batch is asked from `batch_size` right before it is queried.
+///
+/// Every pass walks the due users from most to least overdue using keyset pagination,
+/// so users that are added or removed in the meantime don't shift the pages. Users
+/// that became due after their position was passed are picked up by the next pass.
+/// Only users in the partitions that this instance currently owns are returned, and
+/// none whose fetches are deferred after failing (see `record_fetch_outcome`).
+pub fn due_users<'a>(
+    database: &'a Database,
+    cluster: &'a Cluster,
+    kind: FetchKind,
+    interval: Duration,
+    batch_size: impl Fn() -> u32 + 'a,
+) -> impl Stream<Item = Vec<i32>> + 'a {
+    futures::stream::unfold(None, move |mut cursor: Option<(i64, i32)>| {
+        let amount = batch_size() as i64;
+
+        async move {
+            loop {
+                let due_before = now_millis() - interval.as_millis() as i64;
+                let after = cursor.unwrap_or((i64::MIN, i32::MIN));
+
+                // We may own nothing while the cluster is rebalancing.
+                let (partition_count, partitions) = cluster.partitions();
+                if partitions.is_empty() {
+                    tokio::time::sleep(IDLE_POLL_INTERVAL).await;
+                    continue;
+                }
+
+                let (column, name) = (kind.timestamp_column(), kind.as_str());
+                let partitions = (partition_count, partitions.as_slice());
+                match database.find_due_users(column, name, due_before, after, amount, partitions).await {
+                    Ok(page) if !page.is_empty() => {
+                        cursor = page.last().copied();
+                        return Some((page.into_iter().map(|x| x.1).collect(), cursor));
+                    },
+                    // We reached the end of this pass, start over from the most overdue user.
+                    Ok(_) if cursor.is_some() => {
+                        cursor = None;
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                    },
+                    Ok(_) => tokio::time::sleep(IDLE_POLL_INTERVAL).await,
+                    Err(_) => tokio::time::sleep(Duration::from_secs(1)).await,
+                }
+            }
+        }
+    })
+}
+
+/// Remember whether fetching the given kind of data for the user with the given ID
+/// worked. After a failure, the user is not due again until a backoff passed.
+pub async fn record_fetch_outcome(database: &Database, user_id: i32, kind: FetchKind, successful: bool) {
+    let result = if successful {
+        database.clear_fetch_deferral(user_id, kind.as_str()).await
+    } else {
+        let (backoff, max) = (FAILURE_BACKOFF.as_millis() as i64, MAX_FAILURE_BACKOFF.as_millis() as i64);
+        database.record_fetch_failure(user_id, kind.as_str(), backoff, max).await
+    };
+
+    if let Err(e) = result {
+        warn!("Failed to record the {:?} fetch outcome of user {}: {:?}", kind, user_id, e);
+    }
+}
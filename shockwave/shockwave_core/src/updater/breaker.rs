@@ -0,0 +1,269 @@
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tracing::warn;
+use twilight_http::{
+    api_error::{ApiError, GeneralApiError},
+    error::ErrorType,
+};
+
+use super::Updater;
+
+/// The amount of consecutive failed Discord requests on a server after which we
+/// stop sending requests to it for a while.
+const FAILURE_THRESHOLD: u32 = 5;
+
+/// How long we stop sending requests after the first trip. This doubles for
+/// every consecutive trip, up to `MAX_BACKOFF`.
+const BASE_BACKOFF: Duration = Duration::from_secs(60);
+const MAX_BACKOFF: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// After the backoff expires, a single update is let through to probe whether the
+/// server works again. Other updates are held back for at most this long.
+const PROBE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Tracks consecutive Discord failures for a single server.
+#[derive(Default, Debug)]
+pub(super) struct Breaker {
+    consecutive_failures: u32,
+    /// The amount of times in a row that the breaker opened without a success in between.
+    trips: u32,
+    open_until: Option<Instant>,
+    /// Whether an update was let through to probe the server.
+    probing: bool,
+    last_error: Option<String>,
+}
+
+/// The state of the circuit breaker of a server, as reported through the API.
+#[derive(Serialize, Debug)]
+pub struct BreakerStatus {
+    pub consecutive_failures: u32,
+    pub trips: u32,
+    /// Whether we are currently not sending requests to the server.
+    pub open: bool,
+    /// Milliseconds until the next request is attempted, if open.
+    pub retry_in: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl Breaker {
+    fn is_open(&self) -> bool {
+        self.open_until.is_some_and(|x| x > Instant::now())
+    }
+
+    /// Whether an update may be sent to the server right now. If so, returns whether
+    /// the update is the probe, which holds back other updates until it settles.
+    fn admit(&mut self) -> Option<bool> {
+        if self.is_open() {
+            return None;
+        }
+
+        let probe = self.consecutive_failures >= FAILURE_THRESHOLD;
+        if probe {
+            self.probing = true;
+            self.open_until = Some(Instant::now() + PROBE_WINDOW);
+        }
+
+        Some(probe)
+    }
+
+    /// Let the next update probe the server if the probe did not settle whether it
+    /// works again, for example because it had nothing to change.
+    fn release_probe(&mut self) {
+        if self.probing {
+            self.probing = false;
+            self.open_until = None;
+        }
+    }
+
+    /// Record a failed request, and open the breaker if there were too many in a row
+    /// or the probe failed. Returns how long the breaker is open for if it opened.
+    fn record_failure(&mut self, error: String) -> Option<Duration> {
+        self.consecutive_failures += 1;
+        self.last_error = Some(error);
+
+        // While open, requests that were already in flight may still fail. Those
+        // should not extend the backoff any further, unless the probe failed.
+        if !self.probing && (self.consecutive_failures < FAILURE_THRESHOLD || self.is_open()) {
+            return None;
+        }
+
+        let backoff = BASE_BACKOFF.saturating_mul(1 << self.trips.min(16)).min(MAX_BACKOFF);
+        self.trips += 1;
+        self.probing = false;
+        self.open_until = Some(Instant::now() + backoff);
+
+        Some(backoff)
+    }
+
+    fn status(&self) -> BreakerStatus {
+        let now = Instant::now();
+
+        BreakerStatus {
+            consecutive_failures: self.consecutive_failures,
+            trips: self.trips,
+            open: self.is_open(),
+            retry_in: self.open_until.filter(|x| *x > now).map(|x| (x - now).as_millis() as u64),
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+/// Whether the given error means that the server is (temporarily) refusing our
+/// requests, rather than something being wrong with a single request.
+fn is_server_failure(error: &twilight_http::Error) -> bool {
+    match error.kind() {
+        ErrorType::Response { status, error, .. } => {
+            status.get() == 403
+                || status.get() >= 500
+                || matches!(
+                    error,
+                    ApiError::General(GeneralApiError {
+                        code: 50013, // MissingPermissions
+                        ..
+                    })
+                )
+        },
+        _ => false,
+    }
+}
+
+/// Lets an update through to a server, see `Updater::pass_server_breaker`.
+pub(super) struct BreakerPass<'a> {
+    updater: &'a Updater,
+    server_id: i32,
+    probe: bool,
+}
+
+impl Drop for BreakerPass<'_> {
+    fn drop(&mut self) {
+        if self.probe {
+            if let Some(breaker) = self.updater.breakers.lock().unwrap().get_mut(&self.server_id) {
+                breaker.release_probe();
+            }
+        }
+    }
+}
+
+impl Updater {
+    /// Returns `None` if we are currently not sending requests to the server with the
+    /// given ID because of repeated failures. Once the backoff expires, the next update
+    /// is let through to probe whether the server works again. The returned pass should
+    /// be kept until the update is done, so that a probe that did not send any request
+    /// lets the next update probe instead of keeping the breaker open.
+    pub(super) fn pass_server_breaker(&self, server_id: i32) -> Option<BreakerPass<'_>> {
+        let probe = match self.breakers.lock().unwrap().get_mut(&server_id) {
+            Some(breaker) => breaker.admit()?,
+            None => false,
+        };
+
+        Some(BreakerPass { updater: self, server_id, probe })
+    }
+
+    /// Returns the state of the circuit breaker of the server with the given ID,
+    /// if any request to it failed since its last success.
+    pub fn server_breaker_status(&self, server_id: i32) -> Option<BreakerStatus> {
+        self.breakers.lock().unwrap().get(&server_id).map(Breaker::status)
+    }
+
+    /// Track the result of a Discord request to the server with the given ID. A
+    /// success clears all failures, while too many failures in a row open the breaker.
+    pub(super) fn track_discord_result<T>(&self, server_id: i32, result: &Result<T, twilight_http::Error>) {
+        let mut breakers = self.breakers.lock().unwrap();
+
+        let error = match result {
+            Ok(_) => {
+                breakers.remove(&server_id);
+                return;
+            },
+            Err(e) if is_server_failure(e) => e,
+            Err(_) => return,
+        };
+
+        if let Some(backoff) = breakers.entry(server_id).or_default().record_failure(error.to_string()) {
+            warn!("Too many failures on server {}, pausing requests for {:?}", server_id, backoff);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use super::{Breaker, BASE_BACKOFF, FAILURE_THRESHOLD, MAX_BACKOFF, PROBE_WINDOW};
+
+    /// Pretend that the backoff or probe window of the breaker expired.
+    fn expire(breaker: &mut Breaker) {
+        breaker.open_until = Some(Instant::now() - Duration::from_secs(1));
+    }
+
+    /// How long the breaker stays open, rounded up to whole seconds.
+    fn open_for(breaker: &Breaker) -> Duration {
+        Duration::from_secs((breaker.open_until.unwrap() - Instant::now()).as_secs_f64().ceil() as u64)
+    }
+
+    #[test]
+    fn opens_after_threshold() {
+        let mut breaker = Breaker::default();
+
+        for _ in 1..FAILURE_THRESHOLD {
+            assert_eq!(breaker.record_failure("error".to_string()), None);
+            assert_eq!(breaker.admit(), Some(false));
+        }
+
+        assert_eq!(breaker.record_failure("error".to_string()), Some(BASE_BACKOFF));
+        assert_eq!(breaker.admit(), None);
+        assert!(breaker.status().open);
+
+        // Requests that were in flight don't extend the backoff.
+        assert_eq!(breaker.record_failure("error".to_string()), None);
+        assert_eq!(breaker.trips, 1);
+    }
+
+    #[test]
+    fn backoff_doubles_until_max() {
+        let mut breaker = Breaker { consecutive_failures: FAILURE_THRESHOLD, ..Default::default() };
+        let mut expected = BASE_BACKOFF;
+
+        while expected < MAX_BACKOFF {
+            expire(&mut breaker);
+            assert_eq!(breaker.admit(), Some(true));
+            assert_eq!(breaker.record_failure("error".to_string()), Some(expected));
+            expected *= 2;
+        }
+
+        expire(&mut breaker);
+        assert_eq!(breaker.admit(), Some(true));
+        assert_eq!(breaker.record_failure("error".to_string()), Some(MAX_BACKOFF));
+    }
+
+    #[test]
+    fn probe_holds_back_other_updates() {
+        let mut breaker = Breaker { consecutive_failures: FAILURE_THRESHOLD, trips: 1, ..Default::default() };
+        expire(&mut breaker);
+
+        assert_eq!(breaker.admit(), Some(true));
+        assert_eq!(open_for(&breaker), PROBE_WINDOW);
+        assert_eq!(breaker.admit(), None);
+
+        // The probe window expired without the probe settling anything.
+        expire(&mut breaker);
+        assert_eq!(breaker.admit(), Some(true));
+    }
+
+    #[test]
+    fn probe_without_requests_is_released() {
+        let mut breaker = Breaker { consecutive_failures: FAILURE_THRESHOLD, trips: 1, ..Default::default() };
+        expire(&mut breaker);
+
+        assert_eq!(breaker.admit(), Some(true));
+        breaker.release_probe();
+        assert!(!breaker.status().open);
+        assert_eq!(breaker.admit(), Some(true));
+
+        // A failed probe opens the breaker again, so there is nothing to release.
+        assert_eq!(breaker.record_failure("error".to_string()), Some(BASE_BACKOFF * 2));
+        breaker.release_probe();
+        assert!(breaker.status().open);
+    }
+}
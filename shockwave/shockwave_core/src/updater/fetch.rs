@@ -6,17 +6,90 @@ use reqwest::StatusCode;
 use riven::consts::{QueueType, Tier};
 use tracing::{debug, instrument};
 
-use super::{Updater, UpdaterResult};
+use super::{Phase, PhaseReporter, PhaseState, Updater, UpdaterResult};
 use crate::{database::BatchQueryBuilder, evaluate::EvaluationContext, orianna, riot_api::Priority, util::HashMapExt};
 
+/// The data to fetch from Riot before a user is updated. Also identifies identical
+/// work when it is coalesced.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Fetch {
+    Nothing,
+    Accounts,
+    RankedTiers,
+    Mastery,
+    Everything,
+}
+
+impl Fetch {
+    /// The name of this kind of job in the job queue.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Fetch::Nothing => "nothing",
+            Fetch::Accounts => "accounts",
+            Fetch::RankedTiers => "ranked_tiers",
+            Fetch::Mastery => "mastery",
+            Fetch::Everything => "everything",
+        }
+    }
+
+    /// The phases of fetching this data, in the order in which they run.
+    pub fn phases(&self) -> &'static [Phase] {
+        match self {
+            Fetch::Nothing => &[],
+            Fetch::Accounts => &[Phase::Accounts],
+            Fetch::RankedTiers => &[Phase::Ranks],
+            Fetch::Mastery => &[Phase::Mastery],
+            Fetch::Everything => &[Phase::Accounts, Phase::Mastery, Phase::Ranks],
+        }
+    }
+}
+
+impl From<String> for Fetch {
+    fn from(value: String) -> Self {
+        match value.as_str() {
+            "nothing" => Fetch::Nothing,
+            "accounts" => Fetch::Accounts,
+            "ranked_tiers" => Fetch::RankedTiers,
+            "mastery" => Fetch::Mastery,
+            _ => Fetch::Everything,
+        }
+    }
+}
+
 impl Updater {
+    /// Fetch the given data for the given evaluation context, without updating
+    /// the user afterwards. Stops at the first phase that fails.
+    pub(super) async fn fetch(
+        &self,
+        priority: Priority,
+        fetch: Fetch,
+        ctx: &EvaluationContext,
+        report: PhaseReporter<'_>,
+    ) -> UpdaterResult {
+        for &phase in fetch.phases() {
+            report(phase, PhaseState::Running);
+
+            let result = match phase {
+                Phase::Accounts => self.fetch_user_accounts(priority, ctx).await,
+                Phase::Mastery => self.fetch_mastery_scores(priority, ctx).await,
+                Phase::Ranks => self.fetch_user_ranks(priority, ctx).await,
+                Phase::Roles => Ok(()),
+            };
+
+            report(phase, PhaseState::finished(&result));
+            result?;
+        }
+
+        Ok(())
+    }
+
     /// Updates/upserts the mastery values for the given user in the database.
     /// This will fetch the current mastery for the user and the current mastery
     /// from the Riot API, then compute totals and update accordingly. This operation
     /// solely updates the in-memory representation of the user and does not recompute
     /// roles for the user (use `update_user` for that).
     #[instrument(skip(self, ctx))]
-    pub async fn fetch_mastery_scores(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
+    async fn fetch_mastery_scores(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
         let user_id = ctx.user.id;
         debug!("Fetching mastery scores for user {}", user_id);
 
@@ -29,9 +102,19 @@ impl Updater {
 
         let mut connection = self.database.get_connection().await?;
 
-        // merge stats
-        for account_stats in account_stats {
+        // merge stats, keeping track of the per-account values for region-restricted conditions
+        for (account, account_stats) in ctx.accounts.iter().zip(account_stats) {
+            // used to pick a primary account for users that did not choose one
+            let last_played = account_stats.iter().map(|x| x.last_play_time).max();
+            if last_played != account.last_played {
+                self.database.update_account_last_played(account.id, last_played).await?;
+            }
+
+            let mut per_account = Vec::with_capacity(account_stats.len());
+
             for stat in account_stats {
+                per_account.push((stat.champion_id.0 as i32, stat.champion_level, stat.champion_points));
+
                 new_stats
                     .entry(stat.champion_id.0 as i32)
                     .and_modify(|(level, points)| {
@@ -40,6 +123,19 @@ impl Updater {
                     })
                     .or_insert((stat.champion_level, stat.champion_points));
             }
+
+            let old_per_account = ctx
+                .account_stats
+                .iter()
+                .filter(|x| x.account_id == account.id)
+                .map(|x| (x.champion_id, x.level, x.score))
+                .sorted()
+                .collect::<Vec<_>>();
+
+            per_account.sort();
+            if old_per_account != per_account {
+                self.database.replace_account_stats(&mut connection, user_id, account.id, &per_account).await?;
+            }
         }
 
         let mut leaderboard_builder = BatchQueryBuilder::new();
@@ -148,7 +244,7 @@ impl Updater {
     /// only update their current entries in the database and will not change
     /// any roles (to do so, follow a call to this with a call to update).
     #[instrument(skip(self, ctx))]
-    pub async fn fetch_user_ranks(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
+    async fn fetch_user_ranks(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
         let user_id = ctx.user.id;
         debug!("Fetching ranked tiers for user {}", user_id);
 
@@ -160,11 +256,52 @@ impl Updater {
         let lol_ranks = lol_ranks?;
         let tft_ranks = tft_ranks?;
 
+        // Keep track of the ranks of each individual account, for region-restricted conditions.
+        let per_account_ranks = ctx
+            .accounts
+            .iter()
+            .zip(lol_ranks.iter().zip(tft_ranks.iter()))
+            .map(|(account, (lol, tft))| {
+                // Same as below, an account can have more than one entry in a queue, so keep the best one.
+                let ranks = lol
+                    .iter()
+                    .chain(tft.iter())
+                    .into_group_map_by(|x| <&'static str>::from(x.0.clone()))
+                    .into_iter()
+                    .map(|(queue, entries)| {
+                        (queue, <&'static str>::from(entries.into_iter().map(|x| x.1).max().unwrap()))
+                    })
+                    .sorted()
+                    .collect::<Vec<_>>();
+
+                (account.id, ranks)
+            })
+            .filter(|(account_id, ranks)| {
+                let old = ctx
+                    .account_ranks
+                    .iter()
+                    .filter(|x| x.account_id == *account_id)
+                    .map(|x| (x.queue.as_str(), x.tier.as_str()))
+                    .sorted()
+                    .collect::<Vec<_>>();
+
+                old != *ranks
+            })
+            .collect::<Vec<_>>();
+
+        future::try_join_all(
+            per_account_ranks
+                .iter()
+                .map(|(account_id, ranks)| self.database.replace_account_ranks(user_id, *account_id, ranks)),
+        )
+        .await?;
+
         // Combine the LoL and TFT ranks and find the highest rank in each queue.
         // Turn that into a hashmap that maps the queue to the tier within that queue.
         let all_new_ranks: HashMap<_, _> = lol_ranks
             .into_iter()
-            .chain(tft_ranks.into_iter())
+            .chain(tft_ranks)
+            .flatten()
             .sorted_by_key(|x| <&'static str>::from(x.clone().0))
             .group_by(|x| x.clone().0)
             .into_iter() // group_by needs an iterator
@@ -212,13 +349,13 @@ impl Updater {
     /// specified user. This will re-query the API to ensure that the user
     /// still owns their account and that their username has not changed.
     #[instrument(skip(self, ctx))]
-    pub async fn fetch_user_accounts(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
+    async fn fetch_user_accounts(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
         let user_id = ctx.user.id;
         debug!("Fetching user accounts for user {}", user_id);
 
         // For each account, check whether the account still exists.
         for account in &ctx.accounts {
-            let account_data = self.riot_interface.get_summoner(priority, account).await?;
+            let account_data = self.riot_interface.get_summoner(priority, account).await;
 
             let name = format!(
                 "{}#{}",
@@ -232,7 +369,7 @@ impl Updater {
                     debug!("Account {} no longer exists", name);
 
                     self.database.remove_account(user_id, account.id).await?;
-                    orianna::message_transfer(user_id, &account.region, &name).await;
+                    orianna::message_transfer(user_id, account.region.as_str(), &name).await;
                 },
                 Err(_) => continue, // riot api issue
                 Ok(_) => {
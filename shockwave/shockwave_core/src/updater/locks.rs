@@ -0,0 +1,164 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use tokio::sync::watch;
+use tracing::debug;
+
+use super::{Fetch, UpdaterResult};
+
+/// The outcome of queued work, shared with the requests that were coalesced into it.
+/// Errors are shared by their message, since they cannot be cloned.
+type Outcome = Option<Result<(), String>>;
+
+/// Queued work by user and kind, with the ID of the request that will run it.
+type Queue = HashMap<(i32, Fetch), (u64, watch::Receiver<Outcome>)>;
+
+/// Makes sure that only one piece of work reads and writes the data of a user at a
+/// time, so that e.g. two mastery fetches don't both insert the same deltas and two
+/// updates don't fight over the roles of a member.
+///
+/// Work that is still waiting for its turn is coalesced: if the same work is requested
+/// again for that user, the request waits for the queued work and shares its outcome
+/// instead of running it twice. Work that already started may have read data that is
+/// outdated by the time it finishes, so requests that come in after that queue anew.
+///
+/// The locks only cover this process. In cluster mode, the worker loops and the update
+/// queue of each instance only work on the users in its own partitions, and jobs of a
+/// user are not claimed while another instance is still running one. Work can still
+/// overlap with another instance when a partition moves while it runs, and for whole
+/// server updates, which run on the instance that received the request.
+#[derive(Default)]
+pub(super) struct UserLocks {
+    users: Mutex<HashMap<i32, Arc<tokio::sync::Mutex<()>>>>,
+    /// Work that did not start yet.
+    queued: Mutex<Queue>,
+    next_id: AtomicU64,
+}
+
+impl UserLocks {
+    /// Run the given work once no other work for the given user is running.
+    pub async fn serialized<T>(&self, user_id: i32, work: impl Future<Output = T>) -> T {
+        let lock = self.users.lock().unwrap().entry(user_id).or_default().clone();
+        let entry = UserLock { locks: self, user_id, lock };
+
+        let _guard = entry.lock.lock().await;
+        work.await
+    }
+
+    /// Run the given work like `serialized`, unless the same work is already queued
+    /// for the given user, in which case we wait for it and share its outcome instead.
+    pub async fn coalesced(
+        &self,
+        user_id: i32,
+        fetch: Fetch,
+        work: impl Future<Output = UpdaterResult>,
+    ) -> UpdaterResult {
+        let key = (user_id, fetch);
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = watch::channel(None);
+
+        let existing = {
+            let mut queued = self.queued.lock().unwrap();
+            match queued.get(&key) {
+                Some((_, existing)) => Some(existing.clone()),
+                None => {
+                    queued.insert(key, (id, receiver));
+                    None
+                },
+            }
+        };
+
+        if let Some(mut existing) = existing {
+            debug!("Coalescing {:?} of user {} with queued work", fetch, user_id);
+
+            return match existing.wait_for(Option::is_some).await.map(|x| x.clone()) {
+                Ok(Some(Ok(()))) => Ok(()),
+                Ok(Some(Err(e))) => Err(e.into()),
+                _ => Err("Queued work was cancelled".into()),
+            };
+        }
+
+        // Dropping this marks the work as started, or as cancelled if it never got to run.
+        let queued = Queued { locks: self, key, id };
+        let result = self
+            .serialized(user_id, async {
+                drop(queued);
+                work.await
+            })
+            .await;
+
+        let _ = sender.send(Some(result.as_ref().map(|_| ()).map_err(|e| e.to_string())));
+        result
+    }
+}
+
+/// A reference to the lock of a user, which is cleaned up by the last one that lets go of it.
+struct UserLock<'a> {
+    locks: &'a UserLocks,
+    user_id: i32,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl Drop for UserLock<'_> {
+    fn drop(&mut self) {
+        let mut users = self.locks.users.lock().unwrap();
+
+        // Nobody else can take a new reference while we hold the map, so only the map and we remain.
+        if Arc::strong_count(&self.lock) == 2 {
+            users.remove(&self.user_id);
+        }
+    }
+}
+
+/// Queued work, which is removed from the queue once it starts or is cancelled.
+struct Queued<'a> {
+    locks: &'a UserLocks,
+    key: (i32, Fetch),
+    id: u64,
+}
+
+impl Drop for Queued<'_> {
+    fn drop(&mut self) {
+        let mut queued = self.locks.queued.lock().unwrap();
+        if queued.get(&self.key).is_some_and(|x| x.0 == self.id) {
+            queued.remove(&self.key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicU32;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_queued_work() {
+        let locks = UserLocks::default();
+        let runs = AtomicU32::new(0);
+        let work = || async {
+            runs.fetch_add(1, Ordering::SeqCst);
+            tokio::task::yield_now().await;
+            Ok(())
+        };
+
+        // The first one runs right away, the second one queues and the third one joins the second.
+        let results = futures::join!(
+            locks.coalesced(1, Fetch::Mastery, work()),
+            locks.coalesced(1, Fetch::Mastery, work()),
+            locks.coalesced(1, Fetch::Mastery, work()),
+            locks.coalesced(2, Fetch::Mastery, work()),
+        );
+
+        assert!(results.0.is_ok() && results.1.is_ok() && results.2.is_ok() && results.3.is_ok());
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        assert!(locks.users.lock().unwrap().is_empty());
+        assert!(locks.queued.lock().unwrap().is_empty());
+    }
+}
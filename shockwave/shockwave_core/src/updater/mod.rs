@@ -1,33 +1,69 @@
-use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Instant,
+};
 
-use futures::TryFutureExt;
+use tokio::sync::OnceCell;
 use twilight_http::Client;
 
-use crate::{database::Database, evaluate::EvaluationContext, riot_api::{Priority, RiotApiInterface}, util::DynError};
+use crate::{
+    database::Database, fetch_plan::FetchKind, guild_cache::GuildCache, riot_api::RiotApiInterface, util::DynError,
+};
 
 type UpdaterResult<T = ()> = Result<T, DynError>;
 
+mod breaker;
 mod fetch;
+mod locks;
+mod permissions;
+mod plan;
+mod progress;
 mod update;
 
+pub use breaker::BreakerStatus;
+pub use fetch::Fetch;
+pub use plan::{MemberPlan, NicknameChange, RoleChange, RoleOverride};
+pub use progress::{Phase, PhaseReporter, PhaseState, ServerUpdateProgress};
+
 pub struct Updater {
     database: Arc<Database>,
     discord_client: Client,
     riot_interface: RiotApiInterface,
+    guild_cache: GuildCache,
+    /// The user ID of the bot, looked up when first needed.
+    bot_id: OnceCell<u64>,
+    /// Recently loaded roles and permissions, by server ID. See `cached_server_roles`.
+    /// These are only forgotten on this instance when a server update starts, so other
+    /// instances in cluster mode may use the old configuration for up to `SERVER_ROLES_TTL`,
+    /// unless dissonance bumped the configuration version of the server.
+    server_roles: Mutex<HashMap<i32, (Instant, Arc<plan::ServerRoles>)>>,
+    /// Consecutive Discord failures, by server ID. Every instance tracks only the
+    /// requests it made itself, and pauses only its own requests to a server.
+    breakers: Mutex<HashMap<i32, breaker::Breaker>>,
+    /// Serializes work on the same user.
+    locks: locks::UserLocks,
 }
 
 impl Updater {
-    /// Creates a new updater that uses the given database.
-    pub fn new(db: Arc<Database>, client: Client, riot: RiotApiInterface) -> Updater {
-        Updater { database: db, discord_client: client, riot_interface: riot }
+    /// Creates a new updater that uses the given database. The guild cache is used
+    /// to skip changes that the bot does not have the permissions for.
+    pub fn new(db: Arc<Database>, client: Client, riot: RiotApiInterface, cache: GuildCache) -> Updater {
+        Updater {
+            database: db,
+            discord_client: client,
+            riot_interface: riot,
+            guild_cache: cache,
+            bot_id: OnceCell::new(),
+            server_roles: Mutex::new(HashMap::new()),
+            breakers: Mutex::new(HashMap::new()),
+            locks: locks::UserLocks::default(),
+        }
     }
 
-    /// Attempt to fetch all information for the given evaluation context.
-    /// This will discard any errors, and not update the user after the fetch.
-    pub async fn fetch_all(&self, priority: Priority, ctx: &EvaluationContext) -> UpdaterResult {
-        self.fetch_user_accounts(priority, ctx)
-            .and_then(|_| self.fetch_mastery_scores(priority, ctx))
-            .and_then(|_| self.fetch_user_ranks(priority, ctx))
-            .await
+    /// The total amount of background Riot API requests for the given kind of data that
+    /// were rejected because we exceeded the rate limits.
+    pub fn rate_limited_requests(&self, kind: FetchKind) -> u64 {
+        self.riot_interface.rate_limited_requests(kind)
     }
 }
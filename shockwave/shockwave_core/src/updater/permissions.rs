@@ -0,0 +1,119 @@
+use std::collections::HashMap;
+
+use twilight_model::guild::Permissions;
+
+use super::{plan::ServerRoles, Updater, UpdaterResult};
+use crate::{
+    db_model::{PermissionIssues, Server},
+    guild_cache::CachedGuild,
+    util::DynError,
+};
+
+/// What the bot is allowed to change on a single server, based on the cached
+/// guild and the roles of the bot on it.
+pub struct ServerPermissions {
+    owner_id: String,
+    manage_roles: bool,
+    manage_nicknames: bool,
+    /// The position of the highest role of the bot. Only roles (and members whose
+    /// highest role is) below this position can be changed.
+    highest_position: i64,
+    /// The position of every role on the server, and whether it is managed.
+    roles: HashMap<String, (i64, bool)>,
+}
+
+impl ServerPermissions {
+    pub fn new(guild: &CachedGuild, bot_roles: &[String]) -> ServerPermissions {
+        // The @everyone role has the same ID as the guild.
+        let own_roles = guild.roles.iter().filter(|x| x.id == guild.id || bot_roles.contains(&x.id));
+        let permissions = own_roles
+            .clone()
+            .map(|x| Permissions::from_bits_truncate(x.permissions.parse().unwrap_or_default()))
+            .fold(Permissions::empty(), |a, b| a | b);
+        let admin = permissions.contains(Permissions::ADMINISTRATOR);
+
+        ServerPermissions {
+            owner_id: guild.owner_id.clone(),
+            manage_roles: admin || permissions.contains(Permissions::MANAGE_ROLES),
+            manage_nicknames: admin || permissions.contains(Permissions::MANAGE_NICKNAMES),
+            highest_position: own_roles.map(|x| x.position).max().unwrap_or_default(),
+            roles: guild.roles.iter().map(|x| (x.id.clone(), (x.position, x.managed))).collect(),
+        }
+    }
+
+    /// Whether the bot can add or remove the role with the given snowflake. Roles
+    /// we don't know about are allowed, so that Discord can tell us they no longer exist.
+    pub fn can_change_role(&self, snowflake: &str) -> bool {
+        self.manage_roles
+            && self.roles.get(snowflake).is_none_or(|&(position, managed)| !managed && position < self.highest_position)
+    }
+
+    /// Whether the role with the given snowflake is managed by an integration.
+    pub fn is_managed(&self, snowflake: &str) -> bool {
+        self.roles.get(snowflake).is_some_and(|x| x.1)
+    }
+
+    /// Whether the bot can change the nickname of the given member.
+    pub fn can_change_nickname(&self, member: &str, member_roles: &[String]) -> bool {
+        let member_position = member_roles.iter().filter_map(|x| self.roles.get(x)).map(|x| x.0).max();
+
+        self.manage_nicknames
+            && member != self.owner_id
+            && member_position.is_none_or(|position| position < self.highest_position)
+    }
+
+    /// Find all problems that prevent us from giving out the given roles and the
+    /// nicknames of their server.
+    pub fn issues(&self, roles: &ServerRoles) -> PermissionIssues {
+        let snowflakes = roles
+            .roles
+            .iter()
+            .map(|x| &x.0.snowflake)
+            .chain(roles.ladders.iter().flat_map(|x| &x.1).map(|x| &x.snowflake))
+            .filter(|x| !x.is_empty());
+
+        let mut unassignable_roles =
+            snowflakes.filter(|x| self.manage_roles && !self.can_change_role(x)).cloned().collect::<Vec<_>>();
+        unassignable_roles.sort();
+        unassignable_roles.dedup();
+
+        PermissionIssues {
+            missing_manage_roles: !self.manage_roles,
+            missing_manage_nicknames: !roles.server.nickname_pattern.is_empty() && !self.manage_nicknames,
+            unassignable_roles,
+        }
+    }
+}
+
+impl Updater {
+    /// Determine what we are allowed to change on the given server, based on its
+    /// cached guild.
+    pub(super) async fn get_server_permissions(
+        &self,
+        server: &Server,
+        guild: &CachedGuild,
+    ) -> UpdaterResult<ServerPermissions> {
+        let bot_id = self
+            .bot_id
+            .get_or_try_init(|| async {
+                let user = self.discord_client.current_user().await?.model().await?;
+                Ok::<_, DynError>(user.id.get())
+            })
+            .await?;
+
+        let bot_roles = self.database.get_discord_member_roles(*bot_id, server.snowflake.parse()?).await?;
+
+        Ok(ServerPermissions::new(guild, &bot_roles))
+    }
+
+    /// Store the problems with the configuration of the given server, so that
+    /// they can be reported to its admins.
+    pub(super) async fn record_permission_issues(&self, roles: &ServerRoles) -> UpdaterResult {
+        let issues = roles.permissions.as_ref().map(|x| x.issues(roles)).unwrap_or_default();
+        let issues = (issues != PermissionIssues::default()).then_some(issues);
+
+        self.database.set_server_permission_issues(roles.server.id, issues).await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument, warn};
+
+use super::{permissions::ServerPermissions, Updater, UpdaterResult};
+use crate::{
+    db_model::{Ladder, LadderStep, Role, Server, ServerAndUserPresence},
+    evaluate::EvaluationContext,
+    nickname::{NicknameValues, Template},
+    role_model::{LadderMetric, RoleCombinator, RoleConditionWithId},
+};
+
+/// Servers with at least this many members are considered busy.
+const BUSY_SERVER_MEMBERS: u64 = 5_000;
+
+/// How long the roles of a server are reused when updating single members.
+const SERVER_ROLES_TTL: Duration = Duration::from_secs(60);
+
+/// How many members of a server are loaded at once when planning the whole server.
+const PLAN_BATCH_SIZE: usize = 500;
+
+/// The roles and ladders configured on a server. Loaded once so that the same
+/// configuration can be used to plan the changes for many members.
+pub struct ServerRoles {
+    pub server: Server,
+    pub roles: Vec<(Role, Vec<RoleConditionWithId>)>,
+    pub ladders: Vec<(Ladder, Vec<LadderStep>)>,
+    /// `None` if the guild is not cached, in which case every change is attempted.
+    pub permissions: Option<ServerPermissions>,
+    /// The amount of members of the guild, if it is cached.
+    pub member_count: Option<u64>,
+    /// The version of the configuration at the time it was loaded, see `GuildCache::config_version`.
+    pub config_version: Option<i64>,
+}
+
+impl ServerRoles {
+    /// All champions that the roles and ladders on the server refer to.
+    fn champions(&self) -> Vec<i32> {
+        let conditions = self.roles.iter().flat_map(|x| &x.1).flat_map(|x| x.champions());
+        let ladders = self.ladders.iter().filter_map(|x| x.0.metric.champion());
+
+        conditions.chain(ladders).collect()
+    }
+}
+
+/// Replaces the combinator and conditions of a single role while planning, so
+/// that the impact of a change can be seen before it is saved.
+#[derive(Deserialize, Debug)]
+pub struct RoleOverride {
+    pub role: i32,
+    pub combinator: RoleCombinator,
+    pub conditions: Vec<RoleConditionWithId>,
+}
+
+/// A Discord role that should be added to or removed from a member.
+#[derive(Serialize, Clone, Debug)]
+pub struct RoleChange {
+    pub snowflake: String,
+    /// The Orianna role that gives out this Discord role, if any. This is `None`
+    /// for roles that are only given out by ladders.
+    pub role_id: Option<i32>,
+    pub announce: bool,
+    pub reason: ChangeReason,
+}
+
+/// The evaluation results that caused a role to be added or removed.
+#[derive(Serialize, Clone, Debug)]
+pub struct ChangeReason {
+    /// The result of each condition of the role, as `(condition id, matched)`.
+    pub conditions: Vec<(i32, bool)>,
+    /// The ladder that gives out the role, if any.
+    pub ladder: Option<LadderValue>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LadderValue {
+    pub ladder_id: i32,
+    /// The value of the user for the metric of the ladder.
+    pub value: Option<i32>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct NicknameChange {
+    pub from: Option<String>,
+    pub to: Option<String>,
+}
+
+/// All changes that need to be made to a single member of a single server.
+#[derive(Serialize, Clone, Debug)]
+pub struct MemberPlan {
+    pub user_id: i32,
+    pub server_id: i32,
+    pub add_roles: Vec<RoleChange>,
+    pub remove_roles: Vec<RoleChange>,
+    pub nickname: Option<NicknameChange>,
+    /// The values that the changes in this plan were based on.
+    pub context: serde_json::Value,
+    /// Whether the server has enough members for changes to be noticed quickly.
+    #[serde(skip)]
+    pub busy_server: bool,
+    /// Whether the user is close to reaching the next step of a ladder.
+    #[serde(skip)]
+    pub near_threshold: bool,
+}
+
+impl MemberPlan {
+    /// Whether executing this plan would not change anything.
+    pub fn is_empty(&self) -> bool {
+        self.add_roles.is_empty() && self.remove_roles.is_empty() && self.nickname.is_none()
+    }
+}
+
+impl Updater {
+    /// Load the roles and ladders configured on the server with the given ID.
+    pub(super) async fn load_server_roles(&self, server_id: i32) -> UpdaterResult<ServerRoles> {
+        let (server, roles, ladders) = futures::try_join!(
+            self.database.get_server(server_id),
+            self.database.get_roles_and_conditions_for_server(server_id),
+            self.database.get_ladders_for_server(server_id)
+        )?;
+        // If the guild is not cached, we optimistically try every change.
+        let guild = self.guild_cache.get_guild(&server.snowflake).await;
+        let permissions = match &guild {
+            Some(guild) => Some(self.get_server_permissions(&server, guild).await?),
+            None => None,
+        };
+        let member_count = guild.and_then(|x| x.member_count);
+        let config_version = self.guild_cache.config_version(&server.snowflake).await;
+
+        Ok(ServerRoles { server, roles, ladders, permissions, member_count, config_version })
+    }
+
+    /// Same as `load_server_roles`, but reuses the roles of the server if they were loaded
+    /// less than `SERVER_ROLES_TTL` ago and dissonance did not change the configuration
+    /// since. The permission issues of the server are recorded whenever they are loaded.
+    pub(super) async fn cached_server_roles(&self, server_id: i32) -> UpdaterResult<Arc<ServerRoles>> {
+        let cached = self
+            .server_roles
+            .lock()
+            .unwrap()
+            .get(&server_id)
+            .filter(|x| x.0.elapsed() < SERVER_ROLES_TTL)
+            .map(|x| x.1.clone());
+
+        if let Some(roles) = cached {
+            if self.guild_cache.config_version(&roles.server.snowflake).await == roles.config_version {
+                return Ok(roles);
+            }
+        }
+
+        let roles = Arc::new(self.load_server_roles(server_id).await?);
+        self.record_permission_issues(&roles).await?;
+
+        let mut cache = self.server_roles.lock().unwrap();
+        cache.retain(|_, x| x.0.elapsed() < SERVER_ROLES_TTL);
+        cache.insert(server_id, (Instant::now(), roles.clone()));
+
+        Ok(roles)
+    }
+
+    /// Forget the cached roles of the given server, so that the next update loads them
+    /// again. Used when the configuration of the server was changed through the API.
+    pub(super) fn forget_server_roles(&self, server_id: i32) {
+        self.server_roles.lock().unwrap().remove(&server_id);
+    }
+
+    /// Plan the changes for the user with the given ID on all servers we share
+    /// with them, without making any of them.
+    #[instrument(skip(self))]
+    pub async fn plan_user(&self, user_id: i32) -> UpdaterResult<Vec<MemberPlan>> {
+        let ctx = self.database.get_evaluation_context(user_id).await?;
+        if ctx.user.ignore {
+            return Ok(vec![]);
+        }
+
+        let mut plans = vec![];
+        for membership in self.database.get_servers_with_user(ctx.user.snowflake.clone()).await? {
+            let roles = self.load_server_roles(membership.server.id).await?;
+            plans.push(self.plan_user_on_server(&ctx, &membership, &roles).await?);
+        }
+
+        Ok(plans)
+    }
+
+    /// Plan the changes for every member of the server with the given ID, without
+    /// making any of them. If an override is given, it is used instead of the saved
+    /// configuration of that role. Members that would not change are left out.
+    #[instrument(skip(self, role_override))]
+    pub async fn plan_server(
+        &self,
+        server_id: i32,
+        role_override: Option<RoleOverride>,
+    ) -> UpdaterResult<Vec<MemberPlan>> {
+        let mut roles = self.load_server_roles(server_id).await?;
+
+        if let Some(role_override) = role_override {
+            let mut role = match roles.roles.iter().position(|x| x.0.id == role_override.role) {
+                Some(idx) => roles.roles.swap_remove(idx).0,
+                None => self
+                    .database
+                    .get_roles_in_server(server_id)
+                    .await?
+                    .into_iter()
+                    .find(|x| x.id == role_override.role)
+                    .ok_or("Role does not exist on this server")?,
+            };
+
+            role.combinator.0 = role_override.combinator;
+            roles.roles.push((role, role_override.conditions));
+        }
+
+        let presences = self.database.get_server_presences(server_id).await?;
+        let user_ids = presences.keys().copied().collect::<Vec<_>>();
+
+        let mut plans = vec![];
+        for batch in user_ids.chunks(PLAN_BATCH_SIZE) {
+            for ctx in self.database.get_batch_evaluation_context(batch.to_vec()).await? {
+                if ctx.user.ignore {
+                    continue;
+                }
+
+                let plan = self.plan_user_on_server(&ctx, &presences[&ctx.user.id], &roles).await?;
+                if !plan.is_empty() {
+                    plans.push(plan);
+                }
+            }
+        }
+
+        plans.sort_by_key(|x| x.user_id);
+        Ok(plans)
+    }
+
+    /// Compute the roles that should be added and removed and the nickname that
+    /// should be assigned for the given user on the given server. This does not
+    /// have any side effects, see `update_user_on_server` for actually applying them.
+    pub(super) async fn plan_user_on_server(
+        &self,
+        ctx: &EvaluationContext,
+        membership: &ServerAndUserPresence,
+        roles: &ServerRoles,
+    ) -> UpdaterResult<MemberPlan> {
+        let mut plan = MemberPlan {
+            user_id: ctx.user.id,
+            server_id: membership.server.id,
+            add_roles: vec![],
+            remove_roles: vec![],
+            nickname: None,
+            context: serde_json::Value::Null,
+            busy_server: roles.member_count.is_some_and(|x| x >= BUSY_SERVER_MEMBERS),
+            near_threshold: false,
+        };
+
+        if !membership.exempt_roles {
+            let (should_have, should_be_removed, ladder_values) = self.compute_roles(ctx, roles).await?;
+            plan.near_threshold =
+                roles.ladders.iter().any(|(ladder, steps)| ladder.is_near_next_step(steps, ladder_values[&ladder.id]));
+
+            let role_change = |snowflake: &String| {
+                let role = roles.roles.iter().find(|x| &x.0.snowflake == snowflake);
+                let ladder = roles.ladders.iter().find(|x| x.1.iter().any(|step| &step.snowflake == snowflake));
+
+                RoleChange {
+                    snowflake: snowflake.clone(),
+                    role_id: role.map(|x| x.0.id),
+                    announce: role.is_some_and(|x| x.0.announce),
+                    reason: ChangeReason {
+                        conditions: role.map_or(vec![], |x| x.1.iter().map(|c| (c.id, c.evaluate(ctx))).collect()),
+                        ladder: ladder.map(|x| LadderValue { ladder_id: x.0.id, value: ladder_values[&x.0.id] }),
+                    },
+                }
+            };
+
+            // Skip changes that Discord would refuse anyway, and roles that a moderator changed manually.
+            let can_change = |snowflake: &&String| {
+                if membership.overridden_roles.0.contains(snowflake) {
+                    debug!("Skipping role {} since it was manually changed by a moderator", snowflake);
+                    return false;
+                }
+
+                let allowed = roles.permissions.as_ref().is_none_or(|x| x.can_change_role(snowflake));
+                if !allowed {
+                    debug!("Skipping role {} since we are not allowed to change it", snowflake);
+                }
+
+                allowed
+            };
+
+            let mut add =
+                should_have.iter().filter(|x| !membership.roles.0.contains(x)).filter(can_change).collect::<Vec<_>>();
+            let mut remove = should_be_removed
+                .difference(&should_have)
+                .filter(|x| membership.roles.0.contains(x))
+                .filter(can_change)
+                .collect::<Vec<_>>();
+
+            add.sort();
+            remove.sort();
+
+            plan.add_roles = add.into_iter().map(role_change).collect();
+            plan.remove_roles = remove.into_iter().map(role_change).collect();
+        }
+
+        if !membership.server.nickname_pattern.is_empty() && !membership.exempt_nickname {
+            // Without a primary account (or a fallback for it), we reset their nickname since it may be stale
+            // (and the server may be configured to prevent users from manually assigning nicknames).
+            let values = NicknameValues::from_context(ctx, &membership.server);
+            let target_nick = match Template::parse(&membership.server.nickname_pattern) {
+                Ok(template) => Some(values.map(|values| template.render(&values))),
+                Err(e) => {
+                    warn!("Invalid nickname pattern on server {}: {}", membership.server.id, e.message);
+                    None
+                },
+            };
+
+            let can_change = roles
+                .permissions
+                .as_ref()
+                .is_none_or(|x| x.can_change_nickname(&ctx.user.snowflake, &membership.roles.0));
+
+            if let Some(target_nick) = target_nick.filter(|x| x != &membership.nickname && can_change) {
+                plan.nickname = Some(NicknameChange { from: membership.nickname.clone(), to: target_nick });
+            }
+        }
+
+        if !plan.is_empty() {
+            plan.context = ctx.snapshot(&roles.champions());
+        }
+
+        Ok(plan)
+    }
+
+    /// Evaluate all roles and ladders for the given user, returning the Discord
+    /// roles that they should have, the ones they should not have and the value
+    /// of the user for each ladder.
+    async fn compute_roles(
+        &self,
+        ctx: &EvaluationContext,
+        roles: &ServerRoles,
+    ) -> UpdaterResult<(HashSet<String>, HashSet<String>, HashMap<i32, Option<i32>>)> {
+        let mut should_have = HashSet::<String>::new();
+        let mut should_be_removed = HashSet::<String>::new();
+        let mut ladder_values = HashMap::<i32, Option<i32>>::new();
+
+        for (role, conditions) in &roles.roles {
+            // Skip roles that don't seem to look like a snowflake.
+            if role.snowflake.is_empty() || !role.snowflake.chars().all(char::is_numeric) {
+                continue;
+            }
+
+            let applies = role.evaluate(conditions, ctx);
+
+            if applies {
+                should_have.insert(role.snowflake.clone());
+            } else {
+                should_be_removed.insert(role.snowflake.clone());
+            }
+        }
+
+        for (ladder, steps) in &roles.ladders {
+            let value = match &*ladder.metric {
+                LadderMetric::MasteryGain { champion, days } => {
+                    Some(self.database.get_mastery_gain(ctx.user.id, *champion, *days).await?)
+                },
+                metric => metric.value(ctx),
+            };
+
+            let bracket = ladder.bracket(steps, value);
+            ladder_values.insert(ladder.id, value);
+
+            // Exactly the role of the bracket the user is in, none of the others.
+            for step in steps {
+                if step.snowflake.is_empty() || !step.snowflake.chars().all(char::is_numeric) {
+                    continue;
+                }
+
+                if bracket.is_some_and(|x| x.id == step.id) {
+                    should_have.insert(step.snowflake.clone());
+                } else {
+                    should_be_removed.insert(step.snowflake.clone());
+                }
+            }
+        }
+
+        Ok((should_have, should_be_removed, ladder_values))
+    }
+}
@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use tracing::warn;
+
+use super::{Updater, UpdaterResult};
+
+/// How long the progress of a finished server update is kept around.
+const SERVER_UPDATE_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// How long a server update may go without updating a single member before we assume
+/// that its instance died, and let another update of the server start instead.
+const SERVER_UPDATE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// A step of refreshing a single user, reported while it runs.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Accounts,
+    Mastery,
+    Ranks,
+    /// Recomputing and applying roles and nicknames.
+    Roles,
+}
+
+/// How far a single phase of refreshing a user got.
+#[derive(Serialize, Deserialize, Clone, PartialEq, Debug)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub enum PhaseState {
+    Pending,
+    Running,
+    Done,
+    Failed {
+        error: String,
+    },
+    /// Not run because an earlier phase failed.
+    Skipped,
+}
+
+impl PhaseState {
+    /// The state of a phase that finished with the given result.
+    pub fn finished<T>(result: &UpdaterResult<T>) -> PhaseState {
+        match result {
+            Ok(_) => PhaseState::Done,
+            Err(e) => PhaseState::Failed { error: e.to_string() },
+        }
+    }
+}
+
+/// Receives every change in the state of the phases of a refresh.
+pub type PhaseReporter<'a> = &'a (dyn Fn(Phase, PhaseState) + Sync);
+
+/// The progress of re-evaluating all members of a single server. This is stored
+/// in the database, so that it can be reported by every instance.
+#[derive(sqlx::FromRow, Serialize, Clone, Debug)]
+pub struct ServerUpdateProgress {
+    /// The amount of members that will be updated, or zero while they are still being looked up.
+    pub total: i32,
+    pub done: i32,
+    /// The amount of members for which the update failed. These are also counted in `done`.
+    pub errors: i32,
+    pub finished: bool,
+    /// Whether the server should be updated once more after this update, because
+    /// another update was requested while it ran.
+    pub rerun: bool,
+    /// Milliseconds since the epoch.
+    pub started_at: i64,
+    pub finished_at: Option<i64>,
+}
+
+impl Updater {
+    /// Returns the progress of the current or last update of the server with the
+    /// given ID, if it was updated within `SERVER_UPDATE_RETENTION`.
+    pub async fn server_update_progress(&self, server_id: i32) -> UpdaterResult<Option<ServerUpdateProgress>> {
+        self.database.get_server_update_progress(server_id).await
+    }
+
+    /// Returns whether the server with the given ID is currently being updated.
+    pub async fn is_updating_server(&self, server_id: i32) -> UpdaterResult<bool> {
+        Ok(self.server_update_progress(server_id).await?.is_some_and(|x| !x.finished))
+    }
+
+    /// Start tracking the progress of an update of the given server. Returns false if
+    /// the server is already being updated, possibly by another instance, in which case
+    /// that update runs once more.
+    pub(super) async fn begin_server_update(&self, server_id: i32) -> UpdaterResult<bool> {
+        self.database.prune_server_updates(SERVER_UPDATE_RETENTION.as_secs_f64()).await?;
+
+        loop {
+            if self.database.begin_server_update(server_id, SERVER_UPDATE_TIMEOUT.as_secs_f64()).await? {
+                return Ok(true);
+            }
+
+            // Try again if the running update finished in the meantime.
+            if self.database.request_server_update_rerun(server_id).await? {
+                return Ok(false);
+            }
+        }
+    }
+
+    /// Mark the current update of the given server as finished. Returns true if it should
+    /// run once more instead, in which case its progress starts over.
+    pub(super) async fn end_server_update(&self, server_id: i32) -> UpdaterResult<bool> {
+        self.database.end_server_update(server_id).await
+    }
+
+    /// Set the amount of members that the current update of the given server updates.
+    pub(super) async fn track_server_update_total(&self, server_id: i32, total: usize) {
+        if let Err(e) = self.database.set_server_update_total(server_id, total as i32).await {
+            warn!("Failed to store the progress of the update of server {}: {:?}", server_id, e);
+        }
+    }
+
+    /// Count a member as updated in the current update of the given server.
+    pub(super) async fn track_server_update_member(&self, server_id: i32, failed: bool) {
+        if let Err(e) = self.database.add_server_update_progress(server_id, failed).await {
+            warn!("Failed to store the progress of the update of server {}: {:?}", server_id, e);
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::{collections::HashSet, future::IntoFuture, num::NonZeroU64};
+use std::{future::IntoFuture, num::NonZeroU64, sync::Arc, time::Duration};
 
-use futures::FutureExt;
+use futures::{FutureExt, StreamExt};
+use tokio::sync::RwLock;
 use tracing::{debug, info, instrument, warn, Instrument};
 use twilight_http::{
     api_error::{ApiError, GeneralApiError},
@@ -8,12 +9,28 @@ use twilight_http::{
     request::AuditLogReason,
 };
 use twilight_model::id::{
-    marker::{GuildMarker, UserMarker},
+    marker::{GuildMarker, RoleMarker, UserMarker},
     Id,
 };
 
-use super::{Updater, UpdaterResult};
-use crate::{db_model::ServerAndUserPresence, evaluate::EvaluationContext, orianna};
+use super::{
+    permissions::ServerPermissions,
+    plan::{MemberPlan, NicknameChange, RoleChange, ServerRoles},
+    Fetch, Phase, PhaseReporter, PhaseState, Updater, UpdaterResult,
+};
+use crate::{
+    db_model::{NewAuditLogEntry, ServerAndUserPresence},
+    evaluate::EvaluationContext,
+    orianna,
+    riot_api::Priority,
+    scheduler::PrioritySignals,
+};
+
+/// How long dissonance attributes a role change of a member to us after we announced it.
+const PENDING_CHANGE_TTL: Duration = Duration::from_secs(60);
+
+/// The amount of users that are updated at the same time when updating an entire server.
+const SERVER_UPDATE_CONCURRENCY: usize = 10;
 
 impl Updater {
     /// **Update**s the user with the given user id. This will recalulate and
@@ -21,8 +38,50 @@ impl Updater {
     /// with them, but will not fetch any new data. This should be invoked after
     /// the user has been updated with new data (and possibly when things like
     /// the configured roles in a server have been changed).
-    #[instrument(skip(self))]
+    ///
+    /// Waits for other work on the same user, and shares the outcome of an update of
+    /// the user that is already queued.
     pub async fn update_user(&self, user_id: i32) -> UpdaterResult {
+        self.locks.coalesced(user_id, Fetch::Nothing, self.do_update_user(user_id)).await
+    }
+
+    /// Fetches the given data for the user with the given ID and then **update**s
+    /// them, stopping if the fetch fails. The context of the user is loaded only
+    /// once no other work on the user is running, so it is never outdated.
+    ///
+    /// Shares the outcome of the same work for the user if it is already queued, in
+    /// which case nothing is reported to `report`.
+    pub async fn refresh_user(
+        &self,
+        user_id: i32,
+        priority: Priority,
+        fetch: Fetch,
+        report: PhaseReporter<'_>,
+    ) -> UpdaterResult {
+        self.locks.coalesced(user_id, fetch, self.do_refresh_user(user_id, priority, fetch, report)).await
+    }
+
+    async fn do_refresh_user(
+        &self,
+        user_id: i32,
+        priority: Priority,
+        fetch: Fetch,
+        report: PhaseReporter<'_>,
+    ) -> UpdaterResult {
+        if fetch != Fetch::Nothing {
+            let ctx = self.database.get_evaluation_context(user_id).await?;
+            self.fetch(priority, fetch, &ctx, report).await?;
+        }
+
+        report(Phase::Roles, PhaseState::Running);
+        let result = self.do_update_user(user_id).await;
+        report(Phase::Roles, PhaseState::finished(&result));
+
+        result
+    }
+
+    #[instrument(skip(self))]
+    async fn do_update_user(&self, user_id: i32) -> UpdaterResult {
         debug!("Updating user with ID {}", user_id);
 
         let ctx = self.database.get_evaluation_context(user_id).await?;
@@ -36,86 +95,292 @@ impl Updater {
         let servers = self.database.get_servers_with_user(ctx.user.snowflake.clone()).await?;
 
         // Simply update on each server in parallel.
-        futures::future::join_all(servers.iter().map(|x| self.update_user_on_server(&ctx, &x))).await;
+        let plans =
+            futures::future::join_all(servers.iter().map(|x| self.load_and_update_user_on_server(&ctx, x))).await;
+
+        // Now that we know what the user is close to, decide how soon they are due again.
+        let boost = PrioritySignals::new(&ctx.user, plans.iter().flatten().flatten()).boost();
+        if boost != ctx.user.update_boost {
+            self.database.set_update_boost(user_id, boost).await?;
+        }
 
         Ok(())
     }
 
+    /// **Update**s the user with the given ID on the server with the given ID
+    /// only, e.g. right after they joined it, without touching any of the other
+    /// servers they are on. The given data is fetched first, but the user is
+    /// evaluated with the data we already have if that fails.
+    ///
+    /// Returns the changes that were applied, or `None` if nothing was evaluated
+    /// because the user is not on the server, is ignored or is exempt from all changes,
+    /// or because the server is paused.
+    pub async fn update_member(
+        &self,
+        user_id: i32,
+        server_id: i32,
+        priority: Priority,
+        fetch: Fetch,
+        report: PhaseReporter<'_>,
+    ) -> UpdaterResult<Option<MemberPlan>> {
+        let update = self.do_update_member(user_id, server_id, priority, fetch, report);
+        self.locks.serialized(user_id, update).await
+    }
+
+    async fn do_update_member(
+        &self,
+        user_id: i32,
+        server_id: i32,
+        priority: Priority,
+        fetch: Fetch,
+        report: PhaseReporter<'_>,
+    ) -> UpdaterResult<Option<MemberPlan>> {
+        if fetch != Fetch::Nothing {
+            let ctx = self.database.get_evaluation_context(user_id).await?;
+            if let Err(e) = self.fetch(priority, fetch, &ctx, report).await {
+                warn!("Failed to fetch user {} before updating them on server {}: {:?}", user_id, server_id, e);
+            }
+        }
+
+        report(Phase::Roles, PhaseState::Running);
+        let result = self.update_member_on_server(user_id, server_id).await;
+        report(Phase::Roles, PhaseState::finished(&result));
+
+        result
+    }
+
+    #[instrument(skip(self))]
+    async fn update_member_on_server(&self, user_id: i32, server_id: i32) -> UpdaterResult<Option<MemberPlan>> {
+        let ctx = self.database.get_evaluation_context(user_id).await?;
+        if ctx.user.ignore {
+            return Ok(None);
+        }
+
+        let servers = self.database.get_servers_with_user(ctx.user.snowflake.clone()).await?;
+        match servers.iter().find(|x| x.server.id == server_id) {
+            Some(membership) => self.load_and_update_user_on_server(&ctx, membership).await,
+            None => Ok(None),
+        }
+    }
+
+    /// **Update**s all members of the server with the given ID, but only on that
+    /// server. This should be invoked after the configuration of the server has
+    /// changed in a way that affects many users, such as a changed ladder.
+    ///
+    /// Progress is tracked, see `server_update_progress`. If the server is already
+    /// being updated, possibly by another instance, this returns right away and the
+    /// server is updated once more after that, so that configuration changes made in
+    /// the meantime are applied.
+    #[instrument(skip(self))]
+    pub async fn update_server(&self, server_id: i32) -> UpdaterResult {
+        if !self.begin_server_update(server_id).await? {
+            debug!("Server {} is already being updated, it will be updated again afterwards", server_id);
+            return Ok(());
+        }
+
+        loop {
+            let result = self.update_all_users_in_server(server_id).await;
+            if !self.end_server_update(server_id).await? {
+                return result;
+            }
+
+            if let Err(e) = result {
+                warn!("Failed to update server {}: {:?}", server_id, e);
+            }
+            info!("Updating server {} again, since it changed during the last update", server_id);
+        }
+    }
+
+    async fn update_all_users_in_server(&self, server_id: i32) -> UpdaterResult {
+        let user_ids = self.database.get_users_in_server(server_id).await?;
+        // Whole-server updates follow configuration changes, so don't reuse anything from before.
+        self.forget_server_roles(server_id);
+        let roles = self.cached_server_roles(server_id).await?;
+        info!("Updating {} users on server {}", user_ids.len(), server_id);
+
+        self.track_server_update_total(server_id, user_ids.len()).await;
+
+        let roles = &RwLock::new(roles);
+        futures::stream::iter(user_ids)
+            .for_each_concurrent(SERVER_UPDATE_CONCURRENCY, |user_id| async move {
+                let result = match self.refresh_server_roles(roles).await {
+                    Ok(roles) => {
+                        let update = self.update_user_in_server(user_id, server_id, &roles);
+                        self.locks.serialized(user_id, update).await
+                    },
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = &result {
+                    warn!("Failed to update user {} on server {}: {:?}", user_id, server_id, e);
+                }
+
+                self.track_server_update_member(server_id, result.is_err()).await;
+            })
+            .await;
+
+        Ok(())
+    }
+
+    /// Returns the given roles of a server, reloading them first if dissonance
+    /// changed the configuration of the server since they were loaded.
+    async fn refresh_server_roles(&self, roles: &RwLock<Arc<ServerRoles>>) -> UpdaterResult<Arc<ServerRoles>> {
+        let current = roles.read().await.clone();
+        let version = self.guild_cache.config_version(&current.server.snowflake).await;
+        if version == current.config_version {
+            return Ok(current);
+        }
+
+        let mut roles = roles.write().await;
+
+        // Another user may have already reloaded them while we waited for the lock.
+        if roles.config_version != version {
+            info!("Configuration of server {} changed, reloading its roles", current.server.id);
+            *roles = self.cached_server_roles(current.server.id).await?;
+        }
+
+        Ok(roles.clone())
+    }
+
+    /// Same as `update_user`, but only recomputes the roles of the user on the
+    /// server with the given ID, using the already loaded roles of that server.
+    async fn update_user_in_server(&self, user_id: i32, server_id: i32, roles: &ServerRoles) -> UpdaterResult {
+        let Some(_pass) = self.pass_server_breaker(server_id) else {
+            debug!("Skipping user {} since server {} is paused", user_id, server_id);
+            return Ok(());
+        };
+
+        let ctx = self.database.get_evaluation_context(user_id).await?;
+        if ctx.user.ignore {
+            return Ok(());
+        }
+
+        let servers = self.database.get_servers_with_user(ctx.user.snowflake.clone()).await?;
+        if let Some(membership) = servers.iter().find(|x| x.server.id == server_id) {
+            self.update_user_on_server(&ctx, membership, roles).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the roles of the given server and then **update**s the user on it.
+    async fn load_and_update_user_on_server(
+        &self,
+        ctx: &EvaluationContext,
+        membership: &ServerAndUserPresence,
+    ) -> UpdaterResult<Option<MemberPlan>> {
+        // Don't bother evaluating anything if we can't write to the server anyway.
+        let Some(_pass) = self.pass_server_breaker(membership.server.id) else {
+            debug!("Skipping server {} since it is paused after repeated failures", membership.server.id);
+            return Ok(None);
+        };
+
+        let roles = self.cached_server_roles(membership.server.id).await?;
+        self.update_user_on_server(ctx, membership, &roles).await
+    }
+
     /// Given the specific server membership and evaluation context for the
     /// given user, **update**s them on the given server by recomputing their
-    /// roles and possibly updating their nickname.
-    #[instrument(skip(self, ctx, membership))]
+    /// roles and possibly updating their nickname. Returns the applied plan.
+    #[instrument(skip(self, ctx, membership, roles))]
     async fn update_user_on_server(
         &self,
         ctx: &EvaluationContext,
         membership: &ServerAndUserPresence,
-    ) -> UpdaterResult {
+        roles: &ServerRoles,
+    ) -> UpdaterResult<Option<MemberPlan>> {
         debug!(
             "Updating user {} ({}) on server {} ({})",
             ctx.user.username, ctx.user.snowflake, membership.server.name, membership.server.snowflake
         );
 
-        let conditions = self.database.get_roles_and_conditions_for_server(membership.server.id).await?;
+        // If the user has opted out of (or was exempted from) both roles and
+        // nicknames on this server, there is nothing for us to do here.
+        if membership.exempt_roles && membership.exempt_nickname {
+            debug!("User is exempt from all changes on this server");
+            return Ok(None);
+        }
 
-        let mut should_have = HashSet::<String>::new();
-        let mut should_be_removed = HashSet::<String>::new();
+        let plan = self.plan_user_on_server(ctx, membership, roles).await?;
+        self.execute_plan(ctx, membership, &plan, roles).await?;
 
-        for (role, conditions) in &conditions {
-            // Skip roles that don't seem to look like a snowflake.
-            if role.snowflake.is_empty() || !role.snowflake.chars().all(char::is_numeric) {
-                continue;
-            }
+        Ok(Some(plan))
+    }
 
-            let applies = role.evaluate(conditions.iter().collect(), &ctx);
+    /// Applies the changes in the given plan to Discord. If more than one thing
+    /// changes, everything is applied with a single request where possible.
+    async fn execute_plan(
+        &self,
+        ctx: &EvaluationContext,
+        membership: &ServerAndUserPresence,
+        plan: &MemberPlan,
+        roles: &ServerRoles,
+    ) -> UpdaterResult {
+        let guild_id: Id<GuildMarker> = membership.server.snowflake.parse::<NonZeroU64>()?.into();
+        let user_id: Id<UserMarker> = ctx.user.snowflake.parse::<NonZeroU64>()?.into();
 
-            if applies {
-                should_have.insert(role.snowflake.clone());
-            } else {
-                should_be_removed.insert(role.snowflake.clone());
-            }
+        // Dissonance sees our changes come in like those of moderators, so tell it which are ours first.
+        let role_changes = plan
+            .add_roles
+            .iter()
+            .map(|x| (x.snowflake.as_str(), true))
+            .chain(plan.remove_roles.iter().map(|x| (x.snowflake.as_str(), false)))
+            .collect::<Vec<_>>();
+        if !role_changes.is_empty() {
+            self.database
+                .record_pending_role_changes(
+                    user_id.get(),
+                    guild_id.get(),
+                    &role_changes,
+                    PENDING_CHANGE_TTL.as_secs_f64(),
+                )
+                .await?;
         }
 
-        // Compute the roles we should remove.
-        should_be_removed = should_be_removed.difference(&should_have).cloned().collect();
-
-        let guild_id: Id<GuildMarker> = membership.server.snowflake.parse::<NonZeroU64>()?.into();
-        let user_id: Id<UserMarker> = ctx.user.snowflake.parse::<NonZeroU64>()?.into();
+        // Without the cached guild we can't tell which roles are managed, which Discord
+        // refuses in the role list of a member update.
+        let changes = plan.add_roles.len() + plan.remove_roles.len() + plan.nickname.is_some() as usize;
+        if let Some(permissions) = roles.permissions.as_ref().filter(|_| changes > 1) {
+            if self.execute_plan_batched(ctx, membership, plan, permissions, guild_id, user_id).await? {
+                return Ok(());
+            }
+        }
 
         // Remove what we shouldn't have.
-        futures::future::join_all(should_be_removed.iter().filter_map(|to_be_removed| {
-            if !membership.roles.0.contains(&to_be_removed) {
-                return None;
-            }
+        futures::future::join_all(plan.remove_roles.iter().filter_map(|to_be_removed| {
+            info!("Removing role {}", to_be_removed.snowflake);
 
-            info!("Removing role {}", to_be_removed);
+            let role_id = to_be_removed.snowflake.parse::<NonZeroU64>().ok()?.into();
 
             // ignore error, likely means something is wrong with permissions
             Some(
                 self.discord_client
-                    .remove_guild_member_role(guild_id, user_id, to_be_removed.parse::<NonZeroU64>().ok()?.into())
+                    .remove_guild_member_role(guild_id, user_id, role_id)
                     .reason("Orianna: User no longer qualifies for role")
                     .ok()?
                     .into_future()
-                    .instrument(tracing::info_span!("remove_guild_member_role")),
+                    .instrument(tracing::info_span!("remove_guild_member_role"))
+                    .then(move |result| async move {
+                        self.track_discord_result(plan.server_id, &result);
+
+                        if result.is_ok() {
+                            let _ = self
+                                .database
+                                .remove_discord_member_role(user_id.get(), guild_id.get(), role_id.get())
+                                .await;
+                        }
+
+                        let error = result.err().map(|e| e.to_string());
+                        self.record_role_change(plan, "remove_role", to_be_removed, error).await;
+                    }),
             )
         }))
         .await;
 
         // Add what we should have.
-        futures::future::join_all(should_have.iter().filter_map(|to_be_added| {
-            if membership.roles.0.contains(&to_be_added) {
-                return None;
-            }
-
-            info!("Adding role {}", to_be_added);
+        futures::future::join_all(plan.add_roles.iter().filter_map(|to_be_added| {
+            info!("Adding role {}", to_be_added.snowflake);
 
-            let role = &conditions
-                .iter()
-                .find(|x| x.0.snowflake == to_be_added.clone())
-                .expect("Role somehow does not show up in conditions.")
-                .0;
-
-            let role_id = to_be_added.parse::<NonZeroU64>().ok()?.into();
+            let role_id = to_be_added.snowflake.parse::<NonZeroU64>().ok()?.into();
 
             // ignore error, likely means something is wrong with permissions
             Some(
@@ -126,6 +391,9 @@ impl Updater {
                     .into_future()
                     .instrument(tracing::info_span!("add_guild_member_role"))
                     .then(move |result| async move {
+                        self.track_discord_result(plan.server_id, &result);
+                        let error = result.as_ref().err().map(|e| e.to_string());
+
                         match result {
                             Ok(_) => {
                                 let _ = self
@@ -133,81 +401,228 @@ impl Updater {
                                     .insert_discord_member_role(user_id.get(), guild_id.get(), role_id.get())
                                     .await;
 
-                                if role.announce {
-                                    debug!("Requesting promotion announcement for {}", role.name);
-                                    orianna::announce_promotion(ctx.user.id, role.id).await;
-                                }
+                                self.announce_role(ctx, to_be_added).await;
                             },
-                            Err(e)
-                                if matches!(e.kind(), ErrorType::Response {
-                                    error: ApiError::General(GeneralApiError {
-                                        code: 10011, // UnknownRole
-                                        ..
-                                    }),
-                                    ..
-                                }) =>
-                            {
-                                warn!("Role {} no longer exists", role.snowflake);
-                                let _ = self.database.clear_snowflake_for_role(role.id).await;
+                            Err(e) if is_unknown_role(&e) => {
+                                warn!("Role {} no longer exists", to_be_added.snowflake);
+                                if let Some(role) = to_be_added.role_id {
+                                    let _ = self.database.clear_snowflake_for_role(role).await;
+                                }
                             },
                             Err(e) => {
-                                warn!("Failed to give role {} to user {}: {:?}", role.snowflake, user_id, e);
+                                warn!("Failed to give role {} to user {}: {:?}", to_be_added.snowflake, user_id, e);
                             },
                         }
+
+                        self.record_role_change(plan, "add_role", to_be_added, error).await;
                     }),
             )
         }))
         .await;
 
-        // Check whether the user's nickname is appropriate.
-        if !membership.server.nickname_pattern.is_empty() {
-            if let Some(primary_account) = ctx.accounts.iter().find(|x| x.primary) {
-                let target_nick = Some(
-                    membership
-                        .server
-                        .nickname_pattern
-                        .replace("{region}", &primary_account.region)
-                        .replace("{username}", "N/A")
-                        .replace("{gamename}", &primary_account.riot_id_game_name.clone().unwrap_or_default())
-                        .replace("{tagline}", &primary_account.riot_id_tagline.clone().unwrap_or_default())
-                        .replace("{discord_username}", &ctx.user.username)
-                        .chars()
-                        .take(32)
-                        .collect::<String>(),
-                );
-
-                // if the user's nick isn't already this nick, try assigning
-                if membership.nickname != target_nick {
-                    info!("Updating user nickname to {:?}", target_nick);
-
-                    let _ = self
-                        .discord_client
-                        .update_guild_member(guild_id, user_id)
-                        .nick(target_nick.as_deref())?
-                        .reason("Orianna: Updating nickname to match server pattern.")?
-                        .into_future()
-                        .instrument(tracing::info_span!("update_guild_member"))
-                        .await;
-                }
+        // Update the nickname if it doesn't match the server pattern.
+        if let Some(nickname) = &plan.nickname {
+            let reason = if nickname.to.is_some() {
+                info!("Updating user nickname to {:?}", nickname.to);
+                "Orianna: Updating nickname to match server pattern."
             } else {
-                // We have a nickname pattern but not a primary account. This means we should reset
-                // their nickname since the nickname may be stale (and the server may be configured
-                // to prevent users from manually assigning nicknames).
-                if membership.nickname.is_some() {
-                    info!("Removing user nickname from {:?}", membership.nickname);
-
-                    let _ = self
-                        .discord_client
-                        .update_guild_member(guild_id, user_id)
-                        .nick(None)?
-                        .reason("Orianna: Removing nickname since user has no accounts linked.")?
-                        .into_future()
-                        .instrument(tracing::info_span!("update_guild_member"))
-                        .await;
-                }
+                info!("Removing user nickname from {:?}", nickname.from);
+                "Orianna: Removing nickname since user has no accounts linked."
+            };
+
+            let result = self
+                .discord_client
+                .update_guild_member(guild_id, user_id)
+                .nick(nickname.to.as_deref())?
+                .reason(reason)?
+                .into_future()
+                .instrument(tracing::info_span!("update_guild_member"))
+                .await;
+            self.track_discord_result(plan.server_id, &result);
+
+            if result.is_ok() {
+                let _ = self
+                    .database
+                    .set_discord_member_nickname(user_id.get(), guild_id.get(), nickname.to.as_deref())
+                    .await;
             }
+
+            self.record_nickname_change(plan, nickname, result.err().map(|e| e.to_string())).await;
         }
 
         Ok(())
     }
+
+    /// Applies all changes in the given plan with a single member update. Returns false
+    /// if nothing was changed, because the roles of the member changed since the plan was
+    /// made or because the request failed, in which case the changes should be applied
+    /// one by one instead.
+    async fn execute_plan_batched(
+        &self,
+        ctx: &EvaluationContext,
+        membership: &ServerAndUserPresence,
+        plan: &MemberPlan,
+        permissions: &ServerPermissions,
+        guild_id: Id<GuildMarker>,
+        user_id: Id<UserMarker>,
+    ) -> UpdaterResult<bool> {
+        let change_roles = !plan.add_roles.is_empty() || !plan.remove_roles.is_empty();
+
+        // The role list replaces all roles of the member, so any role that was given to them
+        // since we planned would be taken away again. Only add and remove our own roles then.
+        if change_roles {
+            let mut current = self.database.get_discord_member_roles(user_id.get(), guild_id.get()).await?;
+            let mut planned = membership.roles.0.clone();
+            current.sort();
+            planned.sort();
+
+            if current != planned {
+                debug!("Roles of user {} changed since planning, updating roles one by one", user_id);
+                return Ok(false);
+            }
+        }
+
+        // The final set of roles, based on the roles we know the user currently has.
+        let roles = membership
+            .roles
+            .0
+            .iter()
+            .filter(|x| !plan.remove_roles.iter().any(|removed| &removed.snowflake == *x))
+            .chain(plan.add_roles.iter().map(|x| &x.snowflake))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        // Managed roles stay on the member regardless, and Discord rejects the request if we send them.
+        let role_ids = roles
+            .iter()
+            .filter(|x| !permissions.is_managed(x))
+            .filter_map(|x| x.parse::<NonZeroU64>().ok())
+            .map(Id::<RoleMarker>::from)
+            .collect::<Vec<_>>();
+
+        info!(
+            "Adding {} and removing {} roles and updating nickname to {:?} in a single request",
+            plan.add_roles.len(),
+            plan.remove_roles.len(),
+            plan.nickname.as_ref().map(|x| &x.to)
+        );
+
+        let mut request = self.discord_client.update_guild_member(guild_id, user_id);
+        if change_roles {
+            request = request.roles(&role_ids);
+        }
+        if let Some(nickname) = &plan.nickname {
+            request = request.nick(nickname.to.as_deref())?;
+        }
+
+        let result = request
+            .reason("Orianna: Updating roles and nickname to match server configuration.")?
+            .into_future()
+            .instrument(tracing::info_span!("update_guild_member"))
+            .await;
+        self.track_discord_result(plan.server_id, &result);
+
+        // The changes are recorded by the fallback, which also finds out which of them failed.
+        if let Err(e) = result {
+            warn!("Failed to update user {} in a single request, updating one by one: {:?}", user_id, e);
+            return Ok(false);
+        }
+
+        if change_roles {
+            let _ = self.database.set_discord_member_roles(user_id.get(), guild_id.get(), &roles).await;
+        }
+
+        if let Some(nickname) = &plan.nickname {
+            let _ =
+                self.database.set_discord_member_nickname(user_id.get(), guild_id.get(), nickname.to.as_deref()).await;
+        }
+
+        for role in &plan.add_roles {
+            self.announce_role(ctx, role).await;
+        }
+
+        for change in &plan.remove_roles {
+            self.record_role_change(plan, "remove_role", change, None).await;
+        }
+        for change in &plan.add_roles {
+            self.record_role_change(plan, "add_role", change, None).await;
+        }
+        if let Some(nickname) = &plan.nickname {
+            self.record_nickname_change(plan, nickname, None).await;
+        }
+
+        Ok(true)
+    }
+
+    /// Request a promotion announcement for the given added role, if it has them enabled.
+    async fn announce_role(&self, ctx: &EvaluationContext, change: &RoleChange) {
+        if let Some(role) = change.role_id.filter(|_| change.announce) {
+            debug!("Requesting promotion announcement for role {}", role);
+            orianna::announce_promotion(ctx.user.id, role).await;
+        }
+    }
+
+    /// Store the outcome of changing the nickname in the audit log.
+    async fn record_nickname_change(&self, plan: &MemberPlan, nickname: &NicknameChange, error: Option<String>) {
+        self.record_change(NewAuditLogEntry {
+            server_id: plan.server_id,
+            user_id: plan.user_id,
+            action: "nickname",
+            role_id: None,
+            role_snowflake: None,
+            nickname_from: nickname.from.clone(),
+            nickname_to: nickname.to.clone(),
+            conditions: serde_json::Value::Null,
+            context: plan.context.clone(),
+            success: error.is_none(),
+            error,
+        })
+        .await;
+    }
+
+    /// Store the outcome of adding or removing the given role in the audit log.
+    async fn record_role_change(
+        &self,
+        plan: &MemberPlan,
+        action: &'static str,
+        change: &RoleChange,
+        error: Option<String>,
+    ) {
+        self.record_change(NewAuditLogEntry {
+            server_id: plan.server_id,
+            user_id: plan.user_id,
+            action,
+            role_id: change.role_id,
+            role_snowflake: Some(change.snowflake.clone()),
+            nickname_from: None,
+            nickname_to: None,
+            conditions: serde_json::to_value(&change.reason).unwrap_or_default(),
+            context: plan.context.clone(),
+            success: error.is_none(),
+            error,
+        })
+        .await;
+    }
+
+    /// Store the given change in the audit log. A failure to do so is logged, but
+    /// does not affect the update itself.
+    async fn record_change(&self, entry: NewAuditLogEntry) {
+        if let Err(e) = self.database.insert_audit_log_entry(entry).await {
+            warn!("Failed to write audit log entry: {:?}", e);
+        }
+    }
+}
+
+/// Whether the given error was caused by a role that no longer exists.
+fn is_unknown_role(error: &twilight_http::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorType::Response {
+            error: ApiError::General(GeneralApiError {
+                code: 10011, // UnknownRole
+                ..
+            }),
+            ..
+        }
+    )
 }
@@ -1,159 +1,351 @@
 use std::{
+    collections::HashSet,
+    pin::pin,
     sync::{
-        atomic::{AtomicI64, Ordering},
-        Arc,
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
     },
-    time::{Duration, Instant},
+    time::Duration,
 };
 
-use futures::{Future, Stream, StreamExt, TryFutureExt};
-use tracing::info;
+use futures::{stream::FuturesUnordered, Future, Stream, StreamExt, TryFutureExt};
+use tokio::sync::watch;
+use tracing::{info, warn};
 
-use crate::{database::Database, evaluate::EvaluationContext, riot_api::Priority, updater::Updater};
+use crate::{
+    adaptive::{LoopController, LoopSettings, LoopStatus},
+    cluster::Cluster,
+    database::Database,
+    db_model::UpdateJob,
+    evaluate::EvaluationContext,
+    fetch_plan::{FetchKind, FetchPlanner},
+    jobs::{self, Job, JobOutcome, JobPriority, JobQueue},
+    scheduler,
+    updater::{Fetch, Updater},
+};
 
-#[derive(Copy, Clone)]
-struct WorkerLoopConfiguration {
-    query_batch_size: u32,
-    concurrent_updates: u32,
-    name: &'static str,
-}
+/// How often the worker loops log their progress and adapt their concurrency.
+const ADJUST_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long a claimed job may run before other workers assume that we died and run it instead.
+const JOB_LEASE: Duration = Duration::from_secs(10 * 60);
+
+/// How often we look for jobs if none were added by this instance, which picks up
+/// retries and jobs that were added by other instances.
+const JOB_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The amount of jobs that may run at once besides those that the worker loops wait for.
+const EXTRA_JOB_CONCURRENCY: usize = 25;
+
+/// How long finished and dead jobs are kept around, and how often we look for ones to remove.
+const DONE_JOB_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+const DEAD_JOB_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+const JOB_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub struct Worker {
     updater: Arc<Updater>,
     database: Arc<Database>,
+    planner: FetchPlanner,
+    cluster: Cluster,
+    jobs: JobQueue,
+    mastery: LoopController,
+    ranked: LoopController,
+    accounts: LoopController,
 }
 
-static MASTERY_WORKER_CONFIG: WorkerLoopConfiguration =
-    WorkerLoopConfiguration { query_batch_size: 100, concurrent_updates: 200, name: "mastery" };
+static MASTERY_WORKER_SETTINGS: LoopSettings = LoopSettings {
+    name: "mastery",
+    kind: FetchKind::Mastery,
+    initial_concurrency: 200,
+    max_concurrency: 1000,
+    target_env: "MASTERY_TARGET_STALENESS_MINUTES",
+    default_target: Duration::from_secs(60 * 60),
+};
 
-static RANKED_WORKER_CONFIG: WorkerLoopConfiguration =
-    WorkerLoopConfiguration { query_batch_size: 100, concurrent_updates: 15, name: "ranks" };
+static RANKED_WORKER_SETTINGS: LoopSettings = LoopSettings {
+    name: "ranks",
+    kind: FetchKind::RankedTiers,
+    initial_concurrency: 15,
+    max_concurrency: 200,
+    target_env: "RANKED_TARGET_STALENESS_MINUTES",
+    default_target: Duration::from_secs(60 * 60),
+};
 
-static ACCOUNT_WORKER_CONFIG: WorkerLoopConfiguration =
-    WorkerLoopConfiguration { query_batch_size: 100, concurrent_updates: 15, name: "accounts" };
+static ACCOUNT_WORKER_SETTINGS: LoopSettings = LoopSettings {
+    name: "accounts",
+    kind: FetchKind::Accounts,
+    initial_concurrency: 15,
+    max_concurrency: 200,
+    target_env: "ACCOUNT_TARGET_STALENESS_MINUTES",
+    default_target: Duration::from_secs(12 * 60 * 60),
+};
 
 impl Worker {
     /// Create a new update worker that uses the given updater.
     pub fn new(database: Arc<Database>, updater: Arc<Updater>) -> Worker {
-        Worker { database, updater }
+        Worker {
+            planner: FetchPlanner::new(database.clone()),
+            cluster: Cluster::new(database.clone()),
+            jobs: JobQueue::new(database.clone()),
+            database,
+            updater,
+            mastery: LoopController::new(&MASTERY_WORKER_SETTINGS),
+            ranked: LoopController::new(&RANKED_WORKER_SETTINGS),
+            accounts: LoopController::new(&ACCOUNT_WORKER_SETTINGS),
+        }
+    }
+
+    /// The state of each of the worker loops, as of their last adjustment.
+    pub fn status(&self) -> Vec<LoopStatus> {
+        vec![self.mastery.status(), self.ranked.status(), self.accounts.status()]
+    }
+
+    /// The queue that all updates go through.
+    pub fn jobs(&self) -> &JobQueue {
+        &self.jobs
+    }
+
+    /// Keep this instance's share of the users up to date with the other instances
+    /// in cluster mode. Returns immediately if cluster mode is disabled.
+    pub async fn run_cluster_loop(&self) {
+        self.cluster.run().await;
     }
 
     /// Start a new worker updater loop that is responsible for updating
-    /// user mastery according to the configuration in `MASTERY_WORKER_CONFIG`.
+    /// user mastery according to the settings in `MASTERY_WORKER_SETTINGS`.
     pub async fn run_mastery_loop(&self) {
         self.run_concurrently_on_streams(
-            &|ctx| async move {
-                let _ = self.updater
-                    .fetch_mastery_scores(Priority::Updater, &ctx)
-                    .and_then(|_| self.updater.update_user(ctx.user.id))
-                    .await;
-            },
-            MASTERY_WORKER_CONFIG,
+            &|ctx| async move { self.jobs.run(&Job::new(ctx.user.id, Fetch::Mastery, JobPriority::Background)).await },
+            &self.mastery,
         )
         .await;
     }
 
     /// Start a new worker updater loop that is responsible for updating
-    /// user ranked tiers according to the configuration in `RANKED_WORKER_CONFIG`.
+    /// user ranked tiers according to the settings in `RANKED_WORKER_SETTINGS`.
     pub async fn run_ranked_loop(&self) {
         self.run_concurrently_on_streams(
-            &|ctx| async move {
-                let _ = self.updater
-                    .fetch_user_ranks(Priority::Updater, &ctx)
-                    .and_then(|_| self.updater.update_user(ctx.user.id))
-                    .await;
-            },
-            RANKED_WORKER_CONFIG,
+            &|ctx| async move { self.jobs.run(&Job::new(ctx.user.id, Fetch::RankedTiers, JobPriority::Background)).await },
+            &self.ranked,
         )
         .await;
     }
 
     /// Start a new worker updater loop that is responsible for updating
-    /// user accounts according to the configuration in `ACCOUNT_WORKER_CONFIG`.
+    /// user accounts according to the settings in `ACCOUNT_WORKER_SETTINGS`.
     pub async fn run_account_loop(&self) {
         self.run_concurrently_on_streams(
-            &|ctx| async move {
-                let _ = self.updater
-                    .fetch_user_accounts(Priority::Updater, &ctx)
-                    .and_then(|_| self.updater.update_user(ctx.user.id))
-                    .await;
-            },
-            ACCOUNT_WORKER_CONFIG,
+            &|ctx| async move { self.jobs.run(&Job::new(ctx.user.id, Fetch::Accounts, JobPriority::Background)).await },
+            &self.accounts,
         )
         .await;
     }
 
+    /// Start the loop that runs the jobs in the update queue, highest priority first.
+    /// Since the worker loops wait for their jobs to finish, as many jobs may run at
+    /// once as the loops allow together, plus some room for other jobs.
+    pub async fn run_job_loop(&self) {
+        let running = &AtomicUsize::new(0);
+        let capacity = || {
+            let loops = self.mastery.concurrency() + self.ranked.concurrency() + self.accounts.concurrency();
+            loops + EXTRA_JOB_CONCURRENCY
+        };
+
+        let mut jobs = pin!(self.get_job_stream(move || capacity().saturating_sub(running.load(Ordering::Relaxed))));
+        let mut in_flight = FuturesUnordered::new();
+        let mut prune = tokio::time::interval(JOB_PRUNE_INTERVAL);
+
+        loop {
+            tokio::select! {
+                Some(batch) = jobs.next(), if running.load(Ordering::Relaxed) < capacity() => {
+                    running.fetch_add(batch.len(), Ordering::Relaxed);
+                    in_flight.extend(batch.into_iter().map(|job| async move {
+                        self.run_job(job).await;
+                        running.fetch_sub(1, Ordering::Relaxed);
+                    }));
+                },
+                Some(_) = in_flight.next(), if !in_flight.is_empty() => {},
+                _ = prune.tick() => match self.jobs.prune(DONE_JOB_RETENTION, DEAD_JOB_RETENTION).await {
+                    Ok(0) => {},
+                    Ok(pruned) => info!("Removed {} old update jobs", pruned),
+                    Err(e) => warn!("Failed to remove old update jobs: {:?}", e),
+                },
+            }
+        }
+    }
+
+    /// Run a single job from the update queue and record its outcome. Jobs that someone
+    /// asked for store the state of their phases as they change.
+    async fn run_job(&self, job: UpdateJob) {
+        let (user_id, server_id, fetch) = (job.user_id, job.server_id, job.kind);
+        let priority = JobPriority::riot_priority(job.priority);
+        let (phases, mut changes) = watch::channel(jobs::initial_phases(fetch));
+
+        let work = async move {
+            let report = |phase, state| {
+                phases.send_modify(|x| {
+                    x.insert(phase, state);
+                })
+            };
+
+            let outcome = match server_id {
+                Some(server_id) => self
+                    .updater
+                    .update_member(user_id, server_id, priority, fetch, &report)
+                    .await
+                    .map(|plan| plan.and_then(|x| serde_json::to_value(x).ok())),
+                None => self.updater.refresh_user(user_id, priority, fetch, &report).await.map(|_| None),
+            };
+
+            let phases = phases.borrow().clone();
+            (outcome.map_err(|e| e.to_string()), phases)
+        };
+
+        // Ends once the work is done and drops its sender.
+        let progress = async {
+            if job.priority > JobPriority::Background as i32 {
+                while changes.changed().await.is_ok() {
+                    let current = changes.borrow_and_update().clone();
+                    self.jobs.save_phases(job.id, &current).await;
+                }
+            }
+        };
+
+        let ((outcome, phases), _) = futures::join!(work, progress);
+        self.jobs.finish(&job, outcome, phases).await;
+    }
+
+    /// Create a new stream of batches of jobs that are claimed from the update queue.
+    /// The amount of jobs to claim is asked from `capacity` right before claiming, and
+    /// only jobs of users in this instance's partitions are claimed.
+    fn get_job_stream<'a>(&'a self, capacity: impl Fn() -> usize + 'a) -> impl Stream<Item = Vec<UpdateJob>> + 'a {
+        futures::stream::unfold((), move |_| {
+            let amount = capacity();
+
+            async move {
+                loop {
+                    if amount > 0 {
+                        let (partition_count, partitions) = self.cluster.partitions();
+
+                        match self.jobs.claim(amount, JOB_LEASE, (partition_count, &partitions)).await {
+                            Ok(jobs) if !jobs.is_empty() => return Some((jobs, ())),
+                            Ok(_) => {},
+                            Err(e) => warn!("Failed to claim update jobs: {:?}", e),
+                        }
+                    }
+
+                    tokio::select! {
+                        _ = self.jobs.enqueued() => {},
+                        _ = tokio::time::sleep(JOB_POLL_INTERVAL) => {},
+                    }
+                }
+            }
+        })
+    }
+
     /// Given the specified function, runs the function concurrently on an infinite
-    /// stream of users, configured by the given configuration. Metrics will be printed
-    /// periodically.
+    /// stream of users, as many at a time as the given controller allows. Metrics will
+    /// be printed and the concurrency adjusted periodically. Users whose update fails
+    /// are deferred, and users that are still being updated are not picked up again.
     async fn run_concurrently_on_streams<'a, R>(
         &'a self,
         fun: &'a impl Fn(EvaluationContext) -> R,
-        config: WorkerLoopConfiguration,
+        controller: &'a LoopController,
     ) where
-        R: Future<Output = ()>,
+        R: Future<Output = JobOutcome>,
     {
-        let stream = self.get_user_context_stream(config.query_batch_size);
+        let name = controller.settings.name;
 
-        let amount = Arc::new(AtomicI64::new(0));
-        let amount_clone = amount.clone();
-
-        // Timing loop, keeps track of number of users processed.
-        tokio::spawn(async move {
-            let start = Instant::now();
+        // Timing loop, keeps track of number of users processed and adapts the concurrency.
+        let timing = async {
+            let mut last_completed = 0;
 
             loop {
-                tokio::time::sleep(Duration::from_secs(10)).await;
+                tokio::time::sleep(ADJUST_INTERVAL).await;
 
-                let amnt = amount_clone.load(Ordering::SeqCst);
-                let elapsed_time = start.elapsed().as_secs_f64();
+                let completed = controller.completed();
+                let rate = (completed - last_completed) as f64 / ADJUST_INTERVAL.as_secs_f64();
+                last_completed = completed;
 
-                info!("Updated {} for {} users ({:.2} users/s)", config.name, amnt, (amnt as f64 / elapsed_time));
+                info!("Updated {} for {} users ({:.2} users/s)", name, completed, rate);
+                controller.adjust(self.updater.rate_limited_requests(controller.settings.kind));
             }
-        });
+        };
 
         // Actual concurrent invocation. Since the stream is infinite,
         // this will never resolve.
-        stream
-            .for_each_concurrent(config.concurrent_updates as usize, move |ctx| {
-                let amnt_clone = amount.clone();
+        let updates = async {
+            let updating = &Mutex::new(HashSet::new());
+            let mut stream = pin!(self.get_user_context_stream(controller, updating));
+            let mut in_flight = FuturesUnordered::new();
+            let kind = controller.settings.kind;
+
+            loop {
+                tokio::select! {
+                    Some((ctx, needed)) = stream.next(), if in_flight.len() < controller.concurrency() => {
+                        // Only data that is needed counts towards the target. Users that were never
+                        // fetched don't say anything about how far behind we are either.
+                        let last_fetched = kind.last_fetched(&ctx.user);
+                        let staleness = (needed && last_fetched > 0).then(|| scheduler::now_millis() - last_fetched);
 
-                async move {
-                    fun(ctx).await;
+                        let user_id = ctx.user.id;
+                        updating.lock().unwrap().insert(user_id);
 
-                    amnt_clone.fetch_add(1, Ordering::SeqCst);
+                        in_flight.push(async move {
+                            let outcome = fun(ctx).await;
+                            scheduler::record_fetch_outcome(&self.database, user_id, kind, outcome.is_ok()).await;
+
+                            // Users whose fetches keep failing would otherwise only ever get staler.
+                            if let (Some(staleness), Ok(_)) = (staleness, &outcome) {
+                                controller.record_fetched(staleness);
+                            }
+                            controller.record_completed();
+                            updating.lock().unwrap().remove(&user_id);
+                        });
+                    },
+                    Some(_) = in_flight.next(), if !in_flight.is_empty() => {},
                 }
-            })
-            .await
+            }
+        };
+
+        futures::join!(timing, updates);
     }
 
-    /// Create a new stream of evaluation contexts that endlessly
-    /// returns evaluation contexts of users with at least one account.
-    fn get_user_context_stream(&self, batch_size: u32) -> impl Stream<Item = EvaluationContext> + '_ {
-        futures::stream::unfold(0u32, move |mut offset| async move {
-            // Keep attempting to find users.
-            loop {
-                if let Ok(contexts) = self
-                    .database
-                    .find_users(batch_size, offset)
-                    .and_then(|ids| self.database.get_batch_evaluation_context(ids))
-                    .await
-                {
-                    // If we received less than `batch_size` contexts, it means
-                    // that we reached the end and need to loop around to the start.
-                    if contexts.len() < batch_size as usize {
-                        offset = 0;
-                    } else {
-                        offset += batch_size;
+    /// Create a new stream of evaluation contexts that endlessly returns
+    /// the users that are most overdue for the kind of data of the loop,
+    /// leaving out those that the fetch planner decides can wait and those
+    /// in `updating`. Each context comes with whether one of the servers of
+    /// the user needs it.
+    fn get_user_context_stream<'a>(
+        &'a self,
+        controller: &'a LoopController,
+        updating: &'a Mutex<HashSet<i32>>,
+    ) -> impl Stream<Item = (EvaluationContext, bool)> + 'a {
+        let kind = controller.settings.kind;
+
+        // Users become due halfway through the target, leaving the other half to reach them.
+        let interval = controller.target() / 2;
+
+        scheduler::due_users(&self.database, &self.cluster, kind, interval, move || controller.batch_size())
+            .then(move |mut ids| async move {
+                // A new pass may start before the updates from the last one finished.
+                ids.retain(|x| !updating.lock().unwrap().contains(x));
+
+                // Keep attempting to load the users.
+                loop {
+                    if let Ok(contexts) = self
+                        .database
+                        .get_batch_evaluation_context(ids.clone())
+                        .and_then(|contexts| self.planner.filter(kind, contexts))
+                        .await
+                    {
+                        return futures::stream::iter(contexts);
                     }
 
-                    return Some((futures::stream::iter(contexts), offset));
+                    // Wait for a second and then retry.
+                    tokio::time::sleep(Duration::from_secs(1)).await;
                 }
-
-                // Wait for a second and then retry.
-                tokio::time::sleep(Duration::from_secs(1)).await;
-            }
-        })
-        .flatten()
+            })
+            .flatten()
     }
 }
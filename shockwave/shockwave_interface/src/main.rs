@@ -1,16 +1,27 @@
-use actix_web::error::ErrorNotFound;
+use std::time::Duration;
+
+use actix_web::error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound};
 use actix_web::{web, App, HttpResponse, HttpServer, Responder};
 use futures::TryFutureExt;
+use serde::Deserialize;
 use serde_json::json;
 use shockwave_core::database::Database as SWDatabase;
 use shockwave_core::discord::Client;
-use shockwave_core::riot_api::{Priority, RiotApiInterface};
-use shockwave_core::updater::Updater as SWUpdater;
+use shockwave_core::dsl;
+use shockwave_core::guild_cache::GuildCache;
+use shockwave_core::jobs::{Job, JobPriority};
+use shockwave_core::nickname::{NicknameValues, Template};
+use shockwave_core::riot_api::RiotApiInterface;
+use shockwave_core::updater::{Fetch, RoleOverride, Updater as SWUpdater};
 use shockwave_core::worker::Worker as SWWorker;
 use tracing::error;
 
 type DB = web::Data<SWDatabase>;
 type Updater = web::Data<SWUpdater>;
+type Worker = web::Data<SWWorker>;
+
+/// How often the status of a job is checked for changes while streaming its events.
+const JOB_EVENTS_POLL_INTERVAL: Duration = Duration::from_millis(500);
 
 #[actix_web::post("/api/v1/evaluate/{server_id}/{user_id}")]
 async fn evaluate_role(path: web::Path<(i32, i32)>, db: DB) -> actix_web::Result<impl Responder> {
@@ -21,9 +32,12 @@ async fn evaluate_role(path: web::Path<(i32, i32)>, db: DB) -> actix_web::Result
     let mut results = vec![];
 
     for (role, conditions) in conditions {
+        let (applies, score) = role.evaluate_with_score(&conditions, &ctx);
+
         results.push(json!({
             "role": role.id,
-            "applies": role.evaluate(conditions.iter().collect(), &ctx),
+            "applies": applies,
+            "score": score,
             "conditions": conditions.iter().map(|x| (x.id, x.evaluate(&ctx))).collect::<Vec<_>>()
         }));
     }
@@ -31,22 +45,424 @@ async fn evaluate_role(path: web::Path<(i32, i32)>, db: DB) -> actix_web::Result
     Ok(HttpResponse::Ok().json(results))
 }
 
+#[derive(Deserialize)]
+struct DslBody {
+    source: String,
+}
+
+#[actix_web::post("/api/v1/dsl/compile")]
+async fn compile_dsl(body: web::Json<DslBody>) -> actix_web::Result<impl Responder> {
+    match dsl::compile(&body.source) {
+        Ok(role) => Ok(HttpResponse::Ok().json(role)),
+        Err(e) => Ok(HttpResponse::BadRequest().json(json!({
+            "error": e.message,
+            "span": e.span,
+            "rendered": e.render(&body.source),
+        }))),
+    }
+}
+
+#[derive(Deserialize)]
+struct NicknamePreviewBody {
+    pattern: String,
+    /// The user and server to render the pattern for. Example values are used if absent.
+    user_id: Option<i32>,
+    server_id: Option<i32>,
+}
+
+#[actix_web::post("/api/v1/nickname/preview")]
+async fn preview_nickname(body: web::Json<NicknamePreviewBody>, db: DB) -> actix_web::Result<impl Responder> {
+    let template = match Template::parse(&body.pattern) {
+        Ok(template) => template,
+        Err(e) => {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": e.message,
+                "span": e.span,
+            })))
+        },
+    };
+
+    let values = match (body.user_id, body.server_id) {
+        (Some(user_id), Some(server_id)) => {
+            let server = db.get_server(server_id).await.map_err(ErrorNotFound)?;
+            let ctx = db.get_evaluation_context(user_id).await.map_err(ErrorNotFound)?;
+            NicknameValues::from_context(&ctx, &server)
+        },
+        (None, _) => Some(NicknameValues::example()),
+        (Some(_), None) => return Err(ErrorBadRequest("A server is required to preview for a user")),
+    };
+
+    Ok(HttpResponse::Ok().json(json!({
+        "nickname": values.map(|x| template.render(&x)),
+    })))
+}
+
+#[actix_web::get("/api/v1/server/{server_id}/dsl")]
+async fn print_dsl(path: web::Path<i32>, db: DB) -> actix_web::Result<impl Responder> {
+    let roles = db.get_roles_and_conditions_for_server(path.into_inner()).await.map_err(ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(
+        roles
+            .iter()
+            .map(|(role, conditions)| {
+                json!({
+                    "role": role.id,
+                    "source": dsl::print(&role.combinator, conditions),
+                })
+            })
+            .collect::<Vec<_>>(),
+    ))
+}
+
+/// Queues fetching the latest data of the user and updating them everywhere, and returns
+/// right away with the ID of the job. See `/api/v1/jobs/{job_id}` for its progress.
 #[actix_web::post("/api/v1/user/{user_id}/update")]
-async fn update_user(path: web::Path<i32>, db: DB, updater: Updater) -> actix_web::Result<impl Responder> {
+async fn update_user(path: web::Path<i32>, db: DB, worker: Worker) -> actix_web::Result<impl Responder> {
     let user_id = path.into_inner();
 
-    let ctx = db.get_evaluation_context(user_id).await.map_err(ErrorNotFound)?;
+    db.get_evaluation_context(user_id).await.map_err(ErrorNotFound)?;
+
+    match worker.jobs().enqueue(&Job::new(user_id, Fetch::Everything, JobPriority::UserAction)).await {
+        Ok(job_id) => Ok(HttpResponse::Accepted().json(json!({
+            "successful": true,
+            "job_id": job_id,
+        }))),
+        Err(e) => {
+            error!("Failed to queue update of user {}: {:?}", user_id, e);
+            Ok(HttpResponse::Ok().json(json!({
+                "successful": false,
+            })))
+        },
+    }
+}
+
+/// Returns the state of an update job and each of its phases, or 404 if the job
+/// is unknown. Jobs from the worker loops are forgotten as soon as they finish.
+#[actix_web::get("/api/v1/jobs/{job_id}")]
+async fn get_job(path: web::Path<i64>, worker: Worker) -> actix_web::Result<impl Responder> {
+    match worker.jobs().status(path.into_inner()).await.map_err(ErrorInternalServerError)? {
+        Some(status) => Ok(HttpResponse::Ok().json(status)),
+        None => Err(ErrorNotFound("Unknown job")),
+    }
+}
+
+/// Streams the status of an update job as server-sent events, sending the full status
+/// whenever it changes. The stream ends once the job is done or dead, or is forgotten.
+#[actix_web::get("/api/v1/jobs/{job_id}/events")]
+async fn get_job_events(path: web::Path<i64>, worker: Worker) -> actix_web::Result<impl Responder> {
+    let job_id = path.into_inner();
+    if worker.jobs().status(job_id).await.map_err(ErrorInternalServerError)?.is_none() {
+        return Err(ErrorNotFound("Unknown job"));
+    }
+
+    // The state is the last status that was sent, or `None` once the stream should end.
+    let events = futures::stream::unfold(Some(String::new()), move |last| {
+        let worker = worker.clone();
 
-    let result = updater.fetch_all(Priority::UserAction, &ctx).and_then(|_| updater.update_user(user_id)).await;
-    if let Err(e) = &result {
-        error!("Failed to update user: {:?}", e);
+        async move {
+            let last = last?;
+
+            loop {
+                let (data, finished) = match worker.jobs().status(job_id).await {
+                    Ok(Some(status)) => {
+                        let finished = status.state == "done" || status.state == "dead";
+                        (serde_json::to_string(&status).unwrap_or_default(), finished)
+                    },
+                    Ok(None) => ("null".to_string(), true),
+                    Err(e) => {
+                        error!("Failed to look up update job {}: {:?}", job_id, e);
+                        (last.clone(), false)
+                    },
+                };
+
+                if data != last || finished {
+                    let event = web::Bytes::from(format!("data: {}\n\n", data));
+                    return Some((Ok::<_, actix_web::Error>(event), (!finished).then_some(data)));
+                }
+
+                actix_web::rt::time::sleep(JOB_EVENTS_POLL_INTERVAL).await;
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events))
+}
+
+#[derive(Deserialize)]
+struct MemberUpdateQuery {
+    /// Whether to fetch new data from Riot before evaluating. Defaults to true.
+    fetch: Option<bool>,
+}
+
+/// Updates the user on a single server only and returns the changes that were applied,
+/// or null as the plan if there was nothing to evaluate.
+#[actix_web::post("/api/v1/server/{server_id}/user/{user_id}/update")]
+async fn update_member(
+    path: web::Path<(i32, i32)>,
+    query: web::Query<MemberUpdateQuery>,
+    db: DB,
+    worker: Worker,
+) -> actix_web::Result<impl Responder> {
+    let (server_id, user_id) = path.into_inner();
+
+    db.get_evaluation_context(user_id).await.map_err(ErrorNotFound)?;
+    let fetch = if query.fetch.unwrap_or(true) { Fetch::Everything } else { Fetch::Nothing };
+    let job = Job { server_id: Some(server_id), ..Job::new(user_id, fetch, JobPriority::UserAction) };
+
+    match worker.jobs().run(&job).await {
+        Ok(plan) => Ok(HttpResponse::Ok().json(json!({
+            "successful": true,
+            "plan": plan,
+        }))),
+        Err(e) => {
+            error!("Failed to update user {} on server {}: {:?}", user_id, server_id, e);
+            Ok(HttpResponse::Ok().json(json!({
+                "successful": false,
+                "plan": null,
+            })))
+        },
+    }
+}
+
+#[derive(Deserialize)]
+struct MemberJoinedBody {
+    guild_id: String,
+    user_id: String,
+}
+
+/// Called by dissonance whenever someone joins a server, so that their roles are
+/// assigned right away instead of whenever the update loops reach them.
+#[actix_web::post("/api/v1/member/joined")]
+async fn member_joined(body: web::Json<MemberJoinedBody>, db: DB, worker: Worker) -> actix_web::Result<impl Responder> {
+    let Some((user_id, server_id)) =
+        db.find_member_ids(&body.guild_id, &body.user_id).await.map_err(ErrorInternalServerError)?
+    else {
+        // Either the user has no Orianna profile, or the server is not set up.
+        return Ok(HttpResponse::Ok().json(json!({
+            "successful": false,
+        })));
+    };
+
+    let job = Job { server_id: Some(server_id), ..Job::new(user_id, Fetch::Nothing, JobPriority::Event) };
+    if let Err(e) = worker.jobs().enqueue(&job).await {
+        error!("Failed to queue update of user {} after joining server {}: {:?}", user_id, server_id, e);
     }
 
     Ok(HttpResponse::Ok().json(json!({
-        "successful": result.is_ok(),
+        "successful": true,
+    })))
+}
+
+#[actix_web::post("/api/v1/server/{server_id}/update")]
+async fn update_server(path: web::Path<i32>, updater: Updater) -> actix_web::Result<impl Responder> {
+    let server_id = path.into_inner();
+    let queued = updater.is_updating_server(server_id).await.map_err(ErrorInternalServerError)?;
+
+    // This may take a while on large servers, so don't make the caller wait for it. If
+    // the server is already being updated, it is updated again once that finishes.
+    actix_web::rt::spawn(async move {
+        if let Err(e) = updater.update_server(server_id).await {
+            error!("Failed to update server: {:?}", e);
+        }
+    });
+
+    Ok(HttpResponse::Accepted().json(json!({
+        "successful": true,
+        "queued": queued,
     })))
 }
 
+/// Reports the progress of the current or last update of the given server, whichever
+/// instance runs it, or 404 if it was not updated within the last hour.
+#[actix_web::get("/api/v1/server/{server_id}/update")]
+async fn get_server_update_progress(path: web::Path<i32>, updater: Updater) -> actix_web::Result<impl Responder> {
+    let progress = updater
+        .server_update_progress(path.into_inner())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .ok_or_else(|| ErrorNotFound("Server has not been updated"))?;
+
+    Ok(HttpResponse::Ok().json(progress))
+}
+
+/// Reports whether we stopped sending requests to the given server after repeated
+/// Discord failures, or null if the last request to it succeeded. In cluster mode,
+/// this only covers the requests of the instance that answers.
+#[actix_web::get("/api/v1/server/{server_id}/breaker")]
+async fn get_server_breaker(path: web::Path<i32>, updater: Updater) -> actix_web::Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(updater.server_breaker_status(path.into_inner())))
+}
+
+/// Reports what prevents us from giving out roles or nicknames on the given server,
+/// or null if nothing does.
+#[actix_web::get("/api/v1/server/{server_id}/permissions")]
+async fn get_server_permissions(path: web::Path<i32>, db: DB) -> actix_web::Result<impl Responder> {
+    let issues = db.get_server_permission_issues(path.into_inner()).await.map_err(ErrorNotFound)?;
+
+    Ok(HttpResponse::Ok().json(issues))
+}
+
+#[actix_web::post("/api/v1/user/{user_id}/dry-run")]
+async fn dry_run_user(path: web::Path<i32>, updater: Updater) -> actix_web::Result<impl Responder> {
+    let plans = updater.plan_user(path.into_inner()).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(plans))
+}
+
+/// Optionally takes the unsaved configuration of a single role, to preview what
+/// would change on the server if it were saved.
+#[actix_web::post("/api/v1/server/{server_id}/dry-run")]
+async fn dry_run_server(
+    path: web::Path<i32>,
+    body: Option<web::Json<RoleOverride>>,
+    updater: Updater,
+) -> actix_web::Result<impl Responder> {
+    let plans = updater
+        .plan_server(path.into_inner(), body.map(web::Json::into_inner))
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(plans))
+}
+
+/// Pagination for the audit log, newest entries first. `before` is the ID of the
+/// oldest entry of the previous page.
+#[derive(Deserialize)]
+struct AuditLogQuery {
+    before: Option<i32>,
+    limit: Option<i64>,
+}
+
+impl AuditLogQuery {
+    fn limit(&self) -> i64 {
+        self.limit.unwrap_or(50).clamp(1, 500)
+    }
+}
+
+#[actix_web::get("/api/v1/user/{user_id}/audit-log")]
+async fn get_user_audit_log(
+    path: web::Path<i32>,
+    query: web::Query<AuditLogQuery>,
+    db: DB,
+) -> actix_web::Result<impl Responder> {
+    let entries = db
+        .get_audit_log_for_user(path.into_inner(), query.before, query.limit())
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[actix_web::get("/api/v1/server/{server_id}/audit-log")]
+async fn get_server_audit_log(
+    path: web::Path<i32>,
+    query: web::Query<AuditLogQuery>,
+    db: DB,
+) -> actix_web::Result<impl Responder> {
+    let entries = db
+        .get_audit_log_for_server(path.into_inner(), query.before, query.limit())
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[derive(Deserialize)]
+struct ExemptionBody {
+    roles: bool,
+    nickname: bool,
+}
+
+#[actix_web::get("/api/v1/server/{server_id}/exemptions")]
+async fn get_server_exemptions(path: web::Path<i32>, db: DB) -> actix_web::Result<impl Responder> {
+    let exemptions = db.get_exemptions_for_server(path.into_inner()).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(exemptions))
+}
+
+#[actix_web::get("/api/v1/user/{user_id}/exemptions")]
+async fn get_user_exemptions(path: web::Path<i32>, db: DB) -> actix_web::Result<impl Responder> {
+    let exemptions = db.get_exemptions_for_user(path.into_inner()).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(exemptions))
+}
+
+#[actix_web::put("/api/v1/server/{server_id}/exemptions/{user_id}")]
+async fn put_exemption(
+    path: web::Path<(i32, i32)>,
+    body: web::Json<ExemptionBody>,
+    db: DB,
+) -> actix_web::Result<impl Responder> {
+    let (server_id, user_id) = path.into_inner();
+
+    db.upsert_exemption(server_id, user_id, body.roles, body.nickname).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "successful": true,
+    })))
+}
+
+#[actix_web::delete("/api/v1/server/{server_id}/exemptions/{user_id}")]
+async fn delete_exemption(path: web::Path<(i32, i32)>, db: DB) -> actix_web::Result<impl Responder> {
+    let (server_id, user_id) = path.into_inner();
+
+    db.remove_exemption(server_id, user_id).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(json!({
+        "successful": true,
+    })))
+}
+
+#[actix_web::get("/api/v1/server/{server_id}/role-overrides")]
+async fn get_server_role_overrides(path: web::Path<i32>, db: DB) -> actix_web::Result<impl Responder> {
+    let overrides = db.get_role_overrides_for_server(path.into_inner()).await.map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok().json(overrides))
+}
+
+#[derive(Deserialize)]
+struct RoleOverrideQuery {
+    /// Only clear the override for this role, instead of all of them.
+    role: Option<String>,
+}
+
+#[actix_web::delete("/api/v1/server/{server_id}/role-overrides/{user_id}")]
+async fn delete_role_overrides(
+    path: web::Path<(i32, i32)>,
+    query: web::Query<RoleOverrideQuery>,
+    db: DB,
+    worker: Worker,
+) -> actix_web::Result<impl Responder> {
+    let (server_id, user_id) = path.into_inner();
+
+    let removed = db
+        .remove_role_overrides(server_id, user_id, query.into_inner().role)
+        .await
+        .map_err(ErrorInternalServerError)?;
+
+    // Apply the roles that the overrides held back.
+    if removed > 0 {
+        if let Err(e) = worker.jobs().enqueue(&Job::new(user_id, Fetch::Nothing, JobPriority::Event)).await {
+            error!("Failed to queue update of user {} after clearing overrides: {:?}", user_id, e);
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "successful": true,
+        "removed": removed,
+    })))
+}
+
+/// Reports the concurrency, throughput and staleness of each of the update loops,
+/// and whether they keep up with their target staleness.
+#[actix_web::get("/api/v1/worker/status")]
+async fn get_worker_status(worker: Worker) -> actix_web::Result<impl Responder> {
+    Ok(HttpResponse::Ok().json(worker.status()))
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     tracing_subscriber::fmt::init();
@@ -67,21 +483,57 @@ async fn main() -> std::io::Result<()> {
         db_data.clone().into_inner(),
         Client::new(std::env::var("DISCORD_TOKEN").expect("No Discord token set")),
         riot,
+        GuildCache::connect().await.expect("Could not connect to Redis."),
     ));
 
     // Create worker for update loops.
-    let worker = SWWorker::new(db_data.clone().into_inner(), updater.clone().into_inner());
+    let worker = web::Data::new(SWWorker::new(db_data.clone().into_inner(), updater.clone().into_inner()));
+    let worker_data = worker.clone();
 
     // Create a web server that runs on the tokio threadpool.
     let webserver = HttpServer::new(move || {
-        App::new().app_data(db_data.clone()).app_data(updater.clone()).service(evaluate_role).service(update_user)
+        App::new()
+            .app_data(db_data.clone())
+            .app_data(updater.clone())
+            .app_data(worker_data.clone())
+            .service(evaluate_role)
+            .service(compile_dsl)
+            .service(print_dsl)
+            .service(preview_nickname)
+            .service(update_user)
+            .service(update_server)
+            .service(update_member)
+            .service(member_joined)
+            .service(get_server_update_progress)
+            .service(get_server_permissions)
+            .service(get_server_breaker)
+            .service(dry_run_user)
+            .service(dry_run_server)
+            .service(get_user_audit_log)
+            .service(get_server_audit_log)
+            .service(get_server_exemptions)
+            .service(get_user_exemptions)
+            .service(put_exemption)
+            .service(delete_exemption)
+            .service(get_server_role_overrides)
+            .service(delete_role_overrides)
+            .service(get_worker_status)
+            .service(get_job)
+            .service(get_job_events)
     })
     .bind(format!("0.0.0.0:{}", std::env::var("PORT").unwrap_or("8080".to_string())))?
     .run()
     .unwrap_or_else(|e| panic!("Could not start web server: {:?}", e));
 
     // Run infinitely.
-    futures::join!(webserver, worker.run_account_loop(), worker.run_mastery_loop(), worker.run_ranked_loop());
+    futures::join!(
+        webserver,
+        worker.run_cluster_loop(),
+        worker.run_job_loop(),
+        worker.run_account_loop(),
+        worker.run_mastery_loop(),
+        worker.run_ranked_loop()
+    );
 
     Ok(())
 }
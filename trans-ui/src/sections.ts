@@ -2108,6 +2108,7 @@ const SECTIONS: TranslationSectionDefinition[] = [{
             "command_roles_all_match",
             "command_roles_any_match",
             "command_roles_at_least_n",
+            "command_roles_weighted",
             "command_roles_no_conditions",
             "command_roles_continued"
         ],
@@ -2671,6 +2672,7 @@ const SECTIONS: TranslationSectionDefinition[] = [{
                 ],
                 "value": [
                     { "name": "command_roles_at_least_n", "args": { "amount": 2 } },
+                    { "name": "command_roles_weighted", "args": { "threshold": 5 } },
                     "\n❌ ",
                     {
                         "name": "command_roles_mastery_level",